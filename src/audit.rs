@@ -0,0 +1,96 @@
+//! JSON Lines audit log of signature requests
+
+use crate::bridge::{ClientInfo, SignContext};
+use crate::proto::SignedData;
+use anyhow::{Result, anyhow};
+use log::warn;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignResult {
+    /// upstream 返回了签名
+    Success,
+    /// upstream 返回了 SSH_AGENT_FAILURE 或连接中断
+    Failure,
+    /// bridge 按策略拒绝，未转发给 upstream
+    Denied,
+}
+
+/// One line of the audit log
+#[derive(Serialize)]
+struct SignRecord<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    client: &'a ClientInfo,
+    key_fingerprint: &'a str,
+    key_type: &'a str,
+    key_comment: Option<&'a str>,
+    flags: u32,
+    #[serde(flatten)]
+    data: &'a SignedData,
+    result: SignResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u128>,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<std::fs::File>,
+}
+
+impl AuditLog {
+    /// 以追加方式打开审计日志
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("Failed to open audit log '{}': {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 记录一次签名请求的结果；写入失败只记录警告，不影响转发
+    pub fn record_sign(
+        &self,
+        client: &ClientInfo,
+        sign: &SignContext,
+        result: SignResult,
+        latency: Option<Duration>,
+    ) {
+        let record = SignRecord {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            client,
+            key_fingerprint: &sign.key.fingerprint,
+            key_type: &sign.key.key_type,
+            key_comment: sign.key.comment.as_deref(),
+            flags: sign.flags,
+            data: &sign.data,
+            result,
+            latency_ms: latency.map(|latency| latency.as_millis()),
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        // 整行一次写入，保证并发客户端的记录不会交错
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            warn!("Failed to write audit log '{}': {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,981 @@
+use crate::audit::{AuditLog, SignResult};
+use crate::cache::IdentityCache;
+use crate::codec::{SshAgentCodec, SshAgentMessage};
+use crate::confirm::{Confirmer, printable};
+use crate::events::{Event, Events, RequestKey, SignEvent};
+use crate::metrics::Metrics;
+use crate::policy::{KeyInfo, Operation, Policy};
+use crate::proto::{self, AgentMessage, Identity, SignedData};
+use crate::reload::Live;
+use crate::upstream::{UpstreamOptions, Upstreams, WaitMode};
+use anyhow::{Result, anyhow};
+use futures::SinkExt;
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+fn log_message(direction: &str, msg: &SshAgentMessage) {
+    if !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    match msg.parse() {
+        Ok(message) => debug!(
+            "Forwarding {} {} message of length: {}",
+            direction,
+            message.name(),
+            msg.length
+        ),
+        Err(e) => debug!(
+            "Forwarding {} undecoded message of length {}: {}",
+            direction, msg.length, e
+        ),
+    }
+}
+
+/// 尚未处理的客户端请求最多缓存的数量，包括等待 upstream 连接期间
+const MAX_QUEUED_REQUESTS: usize = 16;
+
+/// How to treat a client frame rejected by the codec
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum InvalidFramePolicy {
+    /// Answer SSH_AGENT_FAILURE and keep the connection open
+    Failure,
+    /// Drop the client connection
+    Disconnect,
+}
+
+/// 每类请求等待 upstream 响应的最长时间，`None` 表示不限
+#[derive(Clone, Debug)]
+pub struct RequestTimeouts {
+    pub list: Option<Duration>,
+    pub sign: Option<Duration>,
+    pub other: Option<Duration>,
+}
+
+impl RequestTimeouts {
+    fn for_message(&self, msg_type: u8) -> Option<Duration> {
+        match msg_type {
+            proto::SSH_AGENTC_REQUEST_IDENTITIES => self.list,
+            proto::SSH_AGENTC_SIGN_REQUEST => self.sign,
+            _ => self.other,
+        }
+    }
+}
+
+/// Per-connection forwarding settings
+#[derive(Clone)]
+pub struct BridgeOptions {
+    pub max_message_size: u32,
+    pub invalid_frame: InvalidFramePolicy,
+    pub policy: Option<Arc<Policy>>,
+    pub allowed_operations: Vec<Operation>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub confirm: Option<Arc<Confirmer>>,
+    pub timeouts: RequestTimeouts,
+    pub cache: Option<Arc<IdentityCache>>,
+    pub metrics: Option<Arc<Metrics>>,
+    pub events: Option<Arc<Events>>,
+}
+
+/// 客户端身份，用于日志与审计
+#[derive(Debug, Default, Serialize)]
+pub struct ClientInfo {
+    #[serde(rename = "client")]
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+    /// 已收到的请求数，供控制接口查看
+    #[serde(skip)]
+    pub requests: AtomicU64,
+}
+
+impl ClientInfo {
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl std::fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.label)?;
+        match (&self.process, self.pid) {
+            (Some(process), Some(pid)) => write!(f, " ({} pid {})", process, pid),
+            (None, Some(pid)) => write!(f, " (pid {})", pid),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 签名请求的上下文，用于与响应配对
+pub struct SignContext {
+    pub key: KeyInfo,
+    pub flags: u32,
+    pub data: SignedData,
+}
+
+/// 已转发给 upstream、等待响应的请求
+struct PendingRequest {
+    started: Instant,
+    sign: Option<Box<SignContext>>,
+}
+
+/// 请求检查结果
+enum Verdict {
+    Forward(PendingRequest),
+    /// 由 bridge 以 SSH_AGENT_FAILURE 拒绝；签名请求附带其上下文
+    Deny(Option<Box<SignContext>>),
+}
+
+impl Verdict {
+    fn sign(&self) -> Option<&SignContext> {
+        match self {
+            Verdict::Forward(pending) => pending.sign.as_deref(),
+            Verdict::Deny(sign) => sign.as_deref(),
+        }
+    }
+}
+
+/// 单个连接上的消息检查状态
+struct Inspector<'a> {
+    client: &'a ClientInfo,
+    // 从 IDENTITIES_ANSWER 中学到的密钥注释，SIGN_REQUEST 不携带注释
+    known_comments: Mutex<HashMap<Vec<u8>, String>>,
+}
+
+impl<'a> Inspector<'a> {
+    fn new(client: &'a ClientInfo) -> Self {
+        Self {
+            client,
+            known_comments: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 检查客户端请求，决定转发给 upstream 还是由 bridge 本地应答
+    async fn check_request(&self, options: &BridgeOptions, msg: &SshAgentMessage) -> Verdict {
+        let msg_type = msg.payload[0];
+        let parsed = (msg_type == proto::SSH_AGENTC_SIGN_REQUEST).then(|| msg.parse());
+        let sign = match parsed {
+            // OpenSSH 的 agent 会忽略多余的字节照常签名，无法解析的签名请求必须拒绝，否则会绕过策略
+            Some(Err(e)) => {
+                warn!("Denied malformed SIGN_REQUEST from {}: {}", self.client, e);
+                // 仍以能读出的密钥记入审计与事件
+                let key_blob = proto::Reader::new(&msg.payload[1..])
+                    .read_string()
+                    .unwrap_or_default();
+                let context = self.sign_context(options, key_blob, 0, SignedData::Unknown);
+                return self.deny(options, Some(context), "malformed request");
+            }
+            Some(Ok(AgentMessage::SignRequest {
+                key_blob,
+                data,
+                flags,
+            })) => {
+                let data = SignedData::parse(&data);
+                if let SignedData::UserAuth {
+                    key_blob: auth_key, ..
+                } = &data
+                    && *auth_key != key_blob
+                {
+                    warn!(
+                        "SIGN_REQUEST from {} authenticates with a different key than it signs with",
+                        self.client
+                    );
+                }
+                Some(self.sign_context(options, &key_blob, flags, data))
+            }
+            _ => None,
+        };
+
+        let allowed = Operation::of_request(&msg.payload)
+            .is_some_and(|operation| options.allowed_operations.contains(&operation));
+        if !allowed {
+            warn!(
+                "Denied {} ({}) from {}: operation not permitted",
+                proto::message_name(msg_type),
+                msg_type,
+                self.client
+            );
+            return self.deny(options, sign, "operation not permitted");
+        }
+        if let (Some(policy), Some(context)) = (&options.policy, &sign)
+            && !policy.is_allowed(&context.key)
+        {
+            warn!(
+                "Denied SIGN_REQUEST from {} for key {} ({}): {}",
+                self.client,
+                context.key.fingerprint,
+                context.key.comment.as_deref().unwrap_or("unknown comment"),
+                context.data
+            );
+            return self.deny(options, sign, "key not allowed by policy");
+        }
+        // 是否需要确认取决于消息类型，而不是能否解析；缺少上下文的签名请求有确认规则时一律拒绝
+        if msg_type == proto::SSH_AGENTC_SIGN_REQUEST
+            && let Some(policy) = &options.policy
+        {
+            let confirmed = match &sign {
+                Some(context) => {
+                    !policy.needs_confirmation(&context.key)
+                        || self.confirm_sign(options, context).await
+                }
+                None => !policy.has_confirm_rules(),
+            };
+            if !confirmed {
+                warn!(
+                    "SIGN_REQUEST from {} for key {} was not confirmed",
+                    self.client,
+                    sign.as_ref()
+                        .map_or("(unknown)", |context| context.key.fingerprint.as_str())
+                );
+                return self.deny(options, sign, "not confirmed");
+            }
+        }
+        Verdict::Forward(PendingRequest {
+            started: Instant::now(),
+            sign,
+        })
+    }
+
+    fn sign_context(
+        &self,
+        options: &BridgeOptions,
+        key_blob: &[u8],
+        flags: u32,
+        data: SignedData,
+    ) -> Box<SignContext> {
+        let comment = self.known_comments.lock().unwrap().get(key_blob).cloned();
+        let context = SignContext {
+            key: KeyInfo::new(key_blob, comment),
+            flags,
+            data,
+        };
+        debug!(
+            "SIGN_REQUEST from {} with key {}: {}",
+            self.client, context.key.fingerprint, context.data
+        );
+        if let Some(events) = &options.events {
+            events.publish(Event::SignRequested {
+                sign: SignEvent::new(self.client, &context),
+            });
+        }
+        Box::new(context)
+    }
+
+    async fn confirm_sign(&self, options: &BridgeOptions, sign: &SignContext) -> bool {
+        let Some(confirmer) = &options.confirm else {
+            return false;
+        };
+        let message = self.confirmation_prompt(sign);
+        debug!("Asking for confirmation: {}", message);
+        confirmer.confirm(&message).await
+    }
+
+    /// 对话框文本；注释、用户名等来自客户端或 upstream，须转义后再显示
+    fn confirmation_prompt(&self, sign: &SignContext) -> String {
+        format!(
+            "Allow {} to use key {} {} for {}?",
+            printable(&self.client.to_string()),
+            printable(sign.key.comment.as_deref().unwrap_or("(unknown comment)")),
+            sign.key.fingerprint,
+            printable(&sign.data.to_string())
+        )
+    }
+
+    /// 记录签名结果到审计日志、指标与事件流
+    fn record_sign(
+        &self,
+        options: &BridgeOptions,
+        sign: &SignContext,
+        result: SignResult,
+        latency: Option<Duration>,
+    ) {
+        if let Some(audit) = &options.audit {
+            audit.record_sign(self.client, sign, result, latency);
+        }
+        if let Some(metrics) = &options.metrics {
+            metrics.record_sign(&sign.key.fingerprint, result);
+        }
+        // 拒绝事件由 deny 带上原因发出
+        if let (Some(events), Some(latency)) = (&options.events, latency) {
+            let event = SignEvent::new(self.client, sign);
+            events.sign_finished(event, result == SignResult::Success, latency);
+        }
+    }
+
+    fn deny(
+        &self,
+        options: &BridgeOptions,
+        sign: Option<Box<SignContext>>,
+        reason: &'static str,
+    ) -> Verdict {
+        if let Some(sign) = &sign {
+            self.record_sign(options, sign, SignResult::Denied, None);
+            if let Some(events) = &options.events {
+                events.publish(Event::SignDenied {
+                    sign: SignEvent::new(self.client, sign),
+                    reason,
+                });
+            }
+        }
+        Verdict::Deny(sign)
+    }
+
+    /// 连接中断，请求不会再得到响应
+    fn abandon(&self, options: &BridgeOptions, request: PendingRequest) {
+        if let Some(sign) = request.sign {
+            self.record_sign(
+                options,
+                &sign,
+                SignResult::Failure,
+                Some(request.started.elapsed()),
+            );
+        }
+    }
+
+    /// 处理 upstream 响应：记录签名审计，按策略过滤 IDENTITIES_ANSWER
+    fn filter_response(
+        &self,
+        options: &BridgeOptions,
+        msg: SshAgentMessage,
+        request: PendingRequest,
+    ) -> SshAgentMessage {
+        let msg_type = msg.payload[0];
+        if let PendingRequest {
+            started,
+            sign: Some(sign),
+        } = request
+        {
+            let result = if msg_type == proto::SSH_AGENT_SIGN_RESPONSE {
+                SignResult::Success
+            } else {
+                SignResult::Failure
+            };
+            debug!(
+                "SIGN_REQUEST from {} for key {} ({}): {:?} in {:?}",
+                self.client,
+                sign.key.fingerprint,
+                sign.data,
+                result,
+                started.elapsed()
+            );
+            self.record_sign(options, &sign, result, Some(started.elapsed()));
+        }
+
+        if msg_type != proto::SSH_AGENT_IDENTITIES_ANSWER {
+            return msg;
+        }
+        let identities = match msg.parse() {
+            Ok(AgentMessage::IdentitiesAnswer(identities)) => identities,
+            // 无法过滤时不能把原始列表交给客户端，否则会暴露被策略隐藏的密钥
+            Err(e) if options.policy.is_some() => {
+                warn!(
+                    "Failing IDENTITIES_ANSWER for {} that cannot be filtered: {}",
+                    self.client, e
+                );
+                return SshAgentMessage::from(&AgentMessage::Failure);
+            }
+            _ => return msg,
+        };
+        let mut known_comments = self.known_comments.lock().unwrap();
+        for identity in &identities {
+            known_comments.insert(identity.key_blob.clone(), identity.comment.clone());
+        }
+        drop(known_comments);
+        let Some(policy) = options.policy.as_ref() else {
+            self.identities_seen(options, &identities);
+            return msg;
+        };
+        let total = identities.len();
+        let allowed: Vec<Identity> = identities
+            .into_iter()
+            .filter(|identity| {
+                let key = KeyInfo::new(&identity.key_blob, Some(identity.comment.clone()));
+                let allowed = policy.is_allowed(&key);
+                if !allowed {
+                    debug!(
+                        "Hiding key {} ({}) from {}",
+                        key.fingerprint, identity.comment, self.client
+                    );
+                }
+                allowed
+            })
+            .collect();
+        debug!(
+            "Exposing {} of {} identities to {}",
+            allowed.len(),
+            total,
+            self.client
+        );
+        self.identities_seen(options, &allowed);
+        SshAgentMessage::from(&AgentMessage::IdentitiesAnswer(allowed))
+    }
+
+    /// 只报告客户端能看到的密钥，被策略隐藏的密钥不会出现在事件流中
+    fn identities_seen(&self, options: &BridgeOptions, visible: &[Identity]) {
+        if let Some(events) = &options.events {
+            events.identities_seen(visible);
+        }
+    }
+}
+
+/// 转发一个请求；超时后重置 upstream 连接，避免迟到的响应错配给下一个请求
+async fn request_with_timeout(
+    upstreams: &mut Upstreams,
+    msg: SshAgentMessage,
+    timeouts: &RequestTimeouts,
+) -> Result<SshAgentMessage> {
+    let msg_type = msg.payload[0];
+    let Some(limit) = timeouts.for_message(msg_type) else {
+        return upstreams.request(msg).await;
+    };
+    match tokio::time::timeout(limit, upstreams.request(msg)).await {
+        Ok(result) => result,
+        Err(_) => {
+            upstreams.reset();
+            Err(anyhow!(
+                "{} timed out after {:?}, resetting upstream connection",
+                proto::message_name(msg_type),
+                limit
+            ))
+        }
+    }
+}
+
+/// 转发一个请求，列出密钥时优先使用缓存
+async fn forward(
+    upstreams: &mut Upstreams,
+    msg: SshAgentMessage,
+    options: &BridgeOptions,
+) -> Result<SshAgentMessage> {
+    let msg_type = msg.payload[0];
+    let Some(cache) = &options.cache else {
+        return request_with_timeout(upstreams, msg, &options.timeouts).await;
+    };
+    if msg_type == proto::SSH_AGENTC_REQUEST_IDENTITIES {
+        return cache
+            .get_or_fetch(request_with_timeout(upstreams, msg, &options.timeouts))
+            .await;
+    }
+    let result = request_with_timeout(upstreams, msg, &options.timeouts).await;
+    if IdentityCache::invalidated_by(msg_type) {
+        cache.invalidate();
+    }
+    result
+}
+
+/// 在客户端与 upstream agent 之间转发 SSH agent 消息
+///
+/// Both directions are driven concurrently: client frames keep being read
+/// while a request is in flight, so pipelined requests queue up in arrival
+/// order and a client that half-closes its side still gets the answers to
+/// everything it sent. Requests are routed one at a time and answered in
+/// arrival order, which keeps replies generated by the bridge itself behind
+/// upstream responses. Between requests the upstream connections are watched
+/// too, so a frame the upstream sends unprompted is discarded instead of
+/// being taken as the reply to the next request.
+///
+/// Requests arriving while the upstream connection is still being
+/// established are queued (or, with [`WaitMode::FailFast`], answered with
+/// SSH_AGENT_FAILURE); if the client goes away in the meantime the connection
+/// attempt is abandoned. A request the upstream cannot answer is failed with
+/// SSH_AGENT_FAILURE and the session continues, reconnecting on the next
+/// request. Each request is checked against the settings current when it
+/// arrives, so a reload applies to existing clients without reconnecting them.
+pub async fn bridge_connection<R, W>(
+    client_read: R,
+    client_write: W,
+    upstream: &UpstreamOptions,
+    client: &ClientInfo,
+    live: &Live<BridgeOptions>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let options = live.load();
+    let codec = || SshAgentCodec::new(options.max_message_size);
+    let mut client_reader = FramedRead::new(client_read, codec());
+    let mut client_writer = FramedWrite::new(client_write, codec());
+
+    let request_direction = format!("[{} -> pipe]", client.label);
+    let response_direction = format!("[pipe -> {}]", client.label);
+    let inspector = Inspector::new(client);
+    let metrics = options.metrics.as_deref();
+    let _active = metrics.map(Metrics::client_connected);
+
+    // 客户端的帧按到达顺序交给处理端，读取不必等待上一个请求完成
+    let (frames, mut requests) = mpsc::channel(MAX_QUEUED_REQUESTS);
+    let reader = async {
+        while let Some(frame) = client_reader.next().await {
+            let frame = frame?;
+            let disconnect =
+                frame.is_err() && live.load().invalid_frame == InvalidFramePolicy::Disconnect;
+            // 处理端已经结束
+            if frames.send(frame).await.is_err() {
+                break;
+            }
+            // 由处理端按顺序应答之前的请求后断开
+            if disconnect {
+                break;
+            }
+        }
+        debug!("{} stream closed", request_direction);
+        drop(frames);
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let processor = async {
+        // 连接 upstream 的同时继续接收请求，以便客户端断开时取消重试
+        let mut queued = VecDeque::new();
+        let mut client_eof = false;
+        let connect = Upstreams::connect(upstream);
+        tokio::pin!(connect);
+        let fail_fast = upstream.wait == Some(WaitMode::FailFast);
+        let mut upstreams = loop {
+            let reading = !client_eof && (fail_fast || queued.len() < MAX_QUEUED_REQUESTS);
+            tokio::select! {
+                result = &mut connect => break result?,
+                frame = requests.recv(), if reading => match frame {
+                    Some(frame) if fail_fast => {
+                        match frame {
+                            Ok(msg) => {
+                                log_message(&request_direction, &msg);
+                                if let Some(metrics) = metrics {
+                                    metrics.record_request(msg.payload[0]);
+                                }
+                            }
+                            Err(e) if live.load().invalid_frame == InvalidFramePolicy::Disconnect => {
+                                return Err(anyhow!("Invalid {} frame: {}", request_direction, e));
+                            }
+                            Err(e) => warn!("Rejected {} frame: {}", request_direction, e),
+                        }
+                        debug!("No upstream available yet, failing request from {}", client);
+                        client_writer
+                            .send(SshAgentMessage::from(&AgentMessage::Failure))
+                            .await?;
+                    }
+                    Some(frame) => queued.push_back(frame),
+                    // 客户端发完请求后半关闭，仍需等待连接并应答
+                    None if !queued.is_empty() => client_eof = true,
+                    None => {
+                        debug!("{} disconnected before the upstream was reached", client);
+                        return Ok(());
+                    }
+                },
+            }
+        };
+        debug!("{} is served by {}", client, upstreams);
+
+        loop {
+            let frame = match queued.pop_front() {
+                Some(frame) => frame,
+                None => tokio::select! {
+                    frame = requests.recv() => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    _ = upstreams.watch_idle() => continue,
+                },
+            };
+            let options = live.load();
+            let msg = match frame {
+                Ok(msg) => msg,
+                Err(e) if options.invalid_frame == InvalidFramePolicy::Failure => {
+                    warn!("Rejected {} frame: {}", request_direction, e);
+                    client_writer
+                        .send(SshAgentMessage::from(&AgentMessage::Failure))
+                        .await?;
+                    continue;
+                }
+                Err(e) => return Err(anyhow!("Invalid {} frame: {}", request_direction, e)),
+            };
+            log_message(&request_direction, &msg);
+            let msg_type = msg.payload[0];
+            client.requests.fetch_add(1, Ordering::Relaxed);
+            if let Some(metrics) = metrics {
+                metrics.record_request(msg_type);
+            }
+            let received = Instant::now();
+            let verdict = inspector.check_request(&options, &msg).await;
+            let key = options
+                .events
+                .as_ref()
+                .map(|_| RequestKey::new(verdict.sign()));
+            let (reply, result) = match verdict {
+                Verdict::Forward(pending) => match forward(&mut upstreams, msg, &options).await {
+                    Ok(reply) => {
+                        log_message(&response_direction, &reply);
+                        if let Some(metrics) = metrics {
+                            metrics.record_latency(msg_type, pending.started.elapsed());
+                        }
+                        let result = if reply.payload[0] == proto::SSH_AGENT_FAILURE {
+                            "failure"
+                        } else {
+                            "success"
+                        };
+                        (inspector.filter_response(&options, reply, pending), result)
+                    }
+                    Err(e) => {
+                        warn!("{}", e);
+                        inspector.abandon(&options, pending);
+                        (SshAgentMessage::from(&AgentMessage::Failure), "failure")
+                    }
+                },
+                Verdict::Deny(_) => {
+                    debug!("Answering {} locally", response_direction);
+                    (SshAgentMessage::from(&AgentMessage::Failure), "denied")
+                }
+            };
+            if let (Some(events), Some(key)) = (&options.events, key) {
+                events.publish(Event::Request {
+                    client: client.into(),
+                    request: proto::message_name(msg_type),
+                    key,
+                    result,
+                    latency_ms: received.elapsed().as_millis(),
+                });
+            }
+            client_writer.send(reply).await?;
+            debug!("Flushed {}", response_direction);
+        }
+        debug!("{} stream closed", response_direction);
+        client_writer.close().await?;
+        Ok(())
+    };
+
+    tokio::try_join!(reader, processor)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::Writer;
+    use crate::testing::{Connect, FakeAgent, bridge_options, upstream_options};
+    use crate::upstream::WaitMode;
+    use tokio::io::AsyncWriteExt;
+
+    fn key_blob() -> Vec<u8> {
+        crate::testing::key_blob(7)
+    }
+
+    fn with_policy(policy: &str) -> BridgeOptions {
+        BridgeOptions {
+            policy: Some(Arc::new(toml::from_str(policy).unwrap())),
+            ..bridge_options()
+        }
+    }
+
+    fn sign_request(trailing: &[u8]) -> SshAgentMessage {
+        let mut payload = AgentMessage::SignRequest {
+            key_blob: key_blob(),
+            data: b"data".to_vec(),
+            flags: 0,
+        }
+        .encode();
+        payload.extend_from_slice(trailing);
+        SshAgentMessage::from_payload(payload)
+    }
+
+    #[tokio::test]
+    async fn sign_request_with_trailing_bytes_is_denied() {
+        let client = ClientInfo::new("client #1");
+        let inspector = Inspector::new(&client);
+        // 策略允许该密钥，但多余的字节让请求无法解析
+        let options = with_policy("[[allow]]\nkey_type = \"ssh-ed25519\"\n");
+        let verdict = inspector.check_request(&options, &sign_request(b"")).await;
+        assert!(matches!(verdict, Verdict::Forward(_)));
+        let verdict = inspector.check_request(&options, &sign_request(b"x")).await;
+        assert!(matches!(verdict, Verdict::Deny(_)));
+
+        // 不能靠多余的字节绕过 deny
+        let options = with_policy("[[deny]]\nkey_type = \"ssh-ed25519\"\n");
+        let verdict = inspector
+            .check_request(&options, &sign_request(b"\0\0"))
+            .await;
+        assert!(matches!(verdict, Verdict::Deny(_)));
+    }
+
+    #[tokio::test]
+    async fn malformed_sign_request_is_audited_as_denied() {
+        let client = ClientInfo::new("client #1");
+        let inspector = Inspector::new(&client);
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let events = Arc::new(Events::default());
+        let mut subscriber = events.subscribe();
+        let options = BridgeOptions {
+            audit: Some(Arc::new(AuditLog::open(&path).unwrap())),
+            events: Some(events),
+            ..with_policy("")
+        };
+        let verdict = inspector.check_request(&options, &sign_request(b"x")).await;
+        assert!(matches!(verdict, Verdict::Deny(Some(_))));
+
+        let audit = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(audit.trim()).unwrap();
+        assert_eq!(record["result"], "denied");
+        assert_eq!(record["key_fingerprint"], proto::fingerprint(&key_blob()));
+        let names: Vec<&str> = std::iter::from_fn(|| subscriber.try_recv().ok())
+            .map(|stamped| stamped.event.name())
+            .collect();
+        assert_eq!(names, ["sign-requested", "sign-denied"]);
+    }
+
+    #[tokio::test]
+    async fn confirmation_applies_to_every_sign_request() {
+        let client = ClientInfo::new("client #1");
+        let inspector = Inspector::new(&client);
+        let confirmed = |command: &str| BridgeOptions {
+            confirm: Some(Arc::new(Confirmer::new(command, Duration::from_secs(5)))),
+            ..with_policy("[[confirm]]\nkey_type = \"ssh-ed25519\"\n")
+        };
+        let verdict = inspector
+            .check_request(&confirmed("true"), &sign_request(b""))
+            .await;
+        assert!(matches!(verdict, Verdict::Forward(_)));
+        let verdict = inspector
+            .check_request(&confirmed("false"), &sign_request(b""))
+            .await;
+        assert!(matches!(verdict, Verdict::Deny(_)));
+        // 无法解析的请求即使用户会同意也不能直接签名
+        let verdict = inspector
+            .check_request(&confirmed("true"), &sign_request(b"x"))
+            .await;
+        assert!(matches!(verdict, Verdict::Deny(_)));
+    }
+
+    #[test]
+    fn confirmation_prompt_escapes_client_supplied_text() {
+        let client = ClientInfo::new("client #1");
+        let inspector = Inspector::new(&client);
+        let sign = SignContext {
+            key: KeyInfo::new(&key_blob(), Some("laptop\nAllow anything?".to_string())),
+            flags: 0,
+            data: SignedData::SshSig {
+                namespace: format!("git\x1b[2K{}", "n".repeat(500)),
+                hash_algorithm: "sha512".to_string(),
+            },
+        };
+        let prompt = inspector.confirmation_prompt(&sign);
+        assert!(!prompt.contains(char::is_control), "{prompt:?}");
+        assert!(prompt.contains("laptop\\nAllow anything?"));
+        assert!(prompt.contains("git\\u{1b}[2K"));
+        assert!(prompt.chars().count() < 400);
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_ignore_unsolicited_upstream_frames() {
+        // upstream 每个应答之后都多发一个 SSH_AGENT_SUCCESS
+        let agent = FakeAgent::new("chatty");
+        agent.set_chatty(true);
+        let upstream = upstream_options(&[agent]);
+        let client = ClientInfo::new("client #1");
+        let live = Live::new(with_policy(""));
+        let (test_side, bridge_side) = tokio::io::duplex(4096);
+        let (read, write) = tokio::io::split(bridge_side);
+        let bridge = bridge_connection(read, write, &upstream, &client, &live);
+
+        let session = async {
+            let (read, write) = tokio::io::split(test_side);
+            let mut reader = FramedRead::new(read, SshAgentCodec::default());
+            let mut writer = FramedWrite::new(write, SshAgentCodec::default());
+            let list = || SshAgentMessage::from(&AgentMessage::RequestIdentities);
+            let mut reply = async || reader.next().await.unwrap().unwrap().unwrap().payload[0];
+            // 连续发出两个请求，不等待响应
+            writer.send(list()).await.unwrap();
+            writer.send(list()).await.unwrap();
+            assert_eq!(reply().await, proto::SSH_AGENT_IDENTITIES_ANSWER);
+            assert_eq!(reply().await, proto::SSH_AGENT_IDENTITIES_ANSWER);
+            // 空闲时到达的 SSH_AGENT_SUCCESS 不能成为下一个请求的响应
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.send(list()).await.unwrap();
+            // 半关闭后仍能收到已发出请求的响应
+            writer.close().await.unwrap();
+            assert_eq!(reply().await, proto::SSH_AGENT_IDENTITIES_ANSWER);
+            assert!(reader.next().await.is_none());
+        };
+        let (result, ()) = tokio::join!(bridge, session);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_fast_follows_reloaded_settings() {
+        let agent = FakeAgent::new("down");
+        agent.set_connect(Connect::Refuse);
+        let mut upstream = upstream_options(&[agent]);
+        upstream.wait = Some(WaitMode::FailFast);
+        upstream.retry.delay = Duration::from_millis(10);
+        let client = ClientInfo::new("client #1");
+        let live = Live::new(bridge_options());
+        let (test_side, bridge_side) = tokio::io::duplex(4096);
+        let (read, write) = tokio::io::split(bridge_side);
+        let bridge = bridge_connection(read, write, &upstream, &client, &live);
+
+        let session = async {
+            let (read, mut write) = tokio::io::split(test_side);
+            let mut reader = FramedRead::new(read, SshAgentCodec::default());
+            let list = SshAgentMessage::from(&AgentMessage::RequestIdentities);
+            write.write_all(&list.length.to_be_bytes()).await.unwrap();
+            write.write_all(&list.payload).await.unwrap();
+            let reply = reader.next().await.unwrap().unwrap().unwrap();
+            assert_eq!(reply.payload, [proto::SSH_AGENT_FAILURE]);
+            // 重新加载后，等待期间的空消息按新设置断开连接
+            live.store(BridgeOptions {
+                invalid_frame: InvalidFramePolicy::Disconnect,
+                ..bridge_options()
+            });
+            write.write_all(&[0; 4]).await.unwrap();
+            assert!(reader.next().await.is_none());
+        };
+        let (result, ()) = tokio::time::timeout(Duration::from_secs(1), async {
+            tokio::join!(bridge, session)
+        })
+        .await
+        .expect("the bridge kept waiting");
+        assert!(result.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_request_does_not_leak_its_late_reply() {
+        // 每个应答都在 5 秒后才发出，签名只等 1 秒
+        let agent = FakeAgent::new("slow");
+        agent.set_delay(Duration::from_secs(5));
+        let upstream = upstream_options(std::slice::from_ref(&agent));
+        let client = ClientInfo::new("client #1");
+        let live = Live::new(BridgeOptions {
+            timeouts: RequestTimeouts {
+                list: None,
+                sign: Some(Duration::from_secs(1)),
+                other: None,
+            },
+            ..bridge_options()
+        });
+        let (test_side, bridge_side) = tokio::io::duplex(4096);
+        let (read, write) = tokio::io::split(bridge_side);
+        let bridge = bridge_connection(read, write, &upstream, &client, &live);
+
+        let session = async {
+            let (read, write) = tokio::io::split(test_side);
+            let mut reader = FramedRead::new(read, SshAgentCodec::default());
+            let mut writer = FramedWrite::new(write, SshAgentCodec::default());
+            let started = tokio::time::Instant::now();
+            writer.send(sign_request(b"")).await.unwrap();
+            let reply = reader.next().await.unwrap().unwrap().unwrap();
+            assert_eq!(reply.payload, [proto::SSH_AGENT_FAILURE]);
+            assert_eq!(started.elapsed(), Duration::from_secs(1));
+            // 超时后重新连接，迟到的 SIGN_RESPONSE 不会成为下一个请求的响应
+            writer
+                .send(SshAgentMessage::from(&AgentMessage::RequestIdentities))
+                .await
+                .unwrap();
+            let reply = reader.next().await.unwrap().unwrap().unwrap();
+            assert_eq!(reply.payload[0], proto::SSH_AGENT_IDENTITIES_ANSWER);
+            assert_eq!(agent.attempts(), 2);
+            writer.close().await.unwrap();
+        };
+        let (result, ()) = tokio::join!(bridge, session);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn forwarded_key_changes_invalidate_the_cache() {
+        let agent = FakeAgent::new("agent");
+        let mut upstreams = Upstreams::connect(&upstream_options(std::slice::from_ref(&agent)))
+            .await
+            .unwrap();
+        let options = BridgeOptions {
+            cache: Some(Arc::new(IdentityCache::new(Duration::from_secs(60)))),
+            ..bridge_options()
+        };
+        let list = || SshAgentMessage::from(&AgentMessage::RequestIdentities);
+        let lock = SshAgentMessage::from(&AgentMessage::Lock {
+            passphrase: b"secret".to_vec(),
+        });
+        for msg in [list(), list(), sign_request(b""), list(), lock, list()] {
+            forward(&mut upstreams, msg, &options).await.unwrap();
+        }
+        let (listing, sign) = (
+            proto::SSH_AGENTC_REQUEST_IDENTITIES,
+            proto::SSH_AGENTC_SIGN_REQUEST,
+        );
+        assert_eq!(
+            agent.requests(),
+            [listing, sign, proto::SSH_AGENTC_LOCK, listing]
+        );
+    }
+
+    #[test]
+    fn hidden_keys_are_not_reported_as_identity_changes() {
+        let client = ClientInfo::new("client #1");
+        let inspector = Inspector::new(&client);
+        let events = Arc::new(Events::default());
+        let mut subscriber = events.subscribe();
+        let options = BridgeOptions {
+            events: Some(events),
+            ..with_policy("[[deny]]\ncomment = \"hidden\"\n")
+        };
+        let answer = |identities: Vec<Identity>| {
+            let answer = SshAgentMessage::from(&AgentMessage::IdentitiesAnswer(identities));
+            let request = PendingRequest {
+                started: Instant::now(),
+                sign: None,
+            };
+            inspector.filter_response(&options, answer, request)
+        };
+        let visible = crate::testing::identity(1, "visible");
+        answer(vec![visible.clone()]);
+        // 新增的密钥被策略隐藏，客户端看到的列表没有变化
+        answer(vec![visible.clone(), crate::testing::identity(2, "hidden")]);
+        assert!(subscriber.try_recv().is_err());
+        answer(vec![crate::testing::identity(3, "added")]);
+        let stamped = subscriber.try_recv().unwrap();
+        let Event::IdentitiesChanged {
+            added,
+            removed,
+            total,
+        } = &stamped.event
+        else {
+            panic!("unexpected {}", stamped.event.name());
+        };
+        assert_eq!(added[0].comment, "added");
+        assert_eq!(removed[0].comment, "visible");
+        assert_eq!(*total, 1);
+    }
+
+    #[test]
+    fn unparseable_identities_answer_fails_closed() {
+        let client = ClientInfo::new("client #1");
+        let inspector = Inspector::new(&client);
+        let options = with_policy("[[deny]]\nkey_type = \"ssh-ed25519\"\n");
+        let mut writer = Writer::new();
+        // 声明两个密钥却在第一个注释之后截断
+        writer
+            .write_u8(proto::SSH_AGENT_IDENTITIES_ANSWER)
+            .write_u32(2)
+            .write_string(&key_blob())
+            .write_string(b"comment");
+        let answer = SshAgentMessage::from_payload(writer.into_bytes());
+        let request = PendingRequest {
+            started: Instant::now(),
+            sign: None,
+        };
+        let reply = inspector.filter_response(&options, answer, request);
+        assert_eq!(reply.payload, vec![proto::SSH_AGENT_FAILURE]);
+    }
+}
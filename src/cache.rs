@@ -0,0 +1,194 @@
+//! Shared cache of the upstream IDENTITIES_ANSWER
+//!
+//! The cache stores the answer exactly as the upstream sent it; per-client
+//! policy filtering still happens afterwards in the bridge. Concurrent
+//! requests are coalesced: while one client fetches the list the others wait
+//! for it instead of asking the upstream themselves.
+
+use crate::codec::SshAgentMessage;
+use crate::proto;
+use anyhow::Result;
+use log::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+struct Entry {
+    fetched: Instant,
+    // 写入时的失效代数，与当前代数不同即已失效
+    generation: u64,
+    answer: SshAgentMessage,
+}
+
+pub struct IdentityCache {
+    ttl: Duration,
+    generation: AtomicU64,
+    // 获取列表期间一直持有锁，以合并并发请求
+    entry: tokio::sync::Mutex<Option<Entry>>,
+}
+
+impl IdentityCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            generation: AtomicU64::new(0),
+            entry: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// 返回缓存的列表，缓存缺失或过期时调用 `fetch` 向 upstream 获取
+    pub async fn get_or_fetch<F>(&self, fetch: F) -> Result<SshAgentMessage>
+    where
+        F: Future<Output = Result<SshAgentMessage>>,
+    {
+        let mut entry = self.entry.lock().await;
+        let generation = self.generation.load(Ordering::SeqCst);
+        if let Some(cached) = entry.as_ref()
+            && cached.generation == generation
+            && cached.fetched.elapsed() < self.ttl
+        {
+            debug!("Answering REQUEST_IDENTITIES from cache");
+            return Ok(cached.answer.clone());
+        }
+        let answer = fetch.await?;
+        // 获取期间发生的失效会改变代数，这样的结果不写入缓存
+        if answer.payload[0] == proto::SSH_AGENT_IDENTITIES_ANSWER {
+            *entry = Some(Entry {
+                fetched: Instant::now(),
+                generation,
+                answer: answer.clone(),
+            });
+        }
+        Ok(answer)
+    }
+
+    /// 密钥列表可能已改变（添加、删除、锁定、解锁）
+    pub fn invalidate(&self) {
+        debug!("Invalidating cached identities");
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// 该请求经过 upstream 后是否可能改变密钥列表
+    pub fn invalidated_by(msg_type: u8) -> bool {
+        matches!(
+            msg_type,
+            proto::SSH_AGENTC_ADD_IDENTITY
+                | proto::SSH_AGENTC_ADD_ID_CONSTRAINED
+                | proto::SSH_AGENTC_REMOVE_IDENTITY
+                | proto::SSH_AGENTC_REMOVE_ALL_IDENTITIES
+                | proto::SSH_AGENTC_ADD_SMARTCARD_KEY
+                | proto::SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED
+                | proto::SSH_AGENTC_REMOVE_SMARTCARD_KEY
+                | proto::SSH_AGENTC_LOCK
+                | proto::SSH_AGENTC_UNLOCK
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::AgentMessage;
+    use crate::testing::{FakeAgent, identity, upstream_options};
+    use crate::upstream::Upstreams;
+    use std::sync::Arc;
+
+    const TTL: Duration = Duration::from_secs(10);
+
+    fn list() -> SshAgentMessage {
+        SshAgentMessage::from(&AgentMessage::RequestIdentities)
+    }
+
+    async fn client(agent: &Arc<FakeAgent>) -> Upstreams {
+        Upstreams::connect(&upstream_options(std::slice::from_ref(agent)))
+            .await
+            .unwrap()
+    }
+
+    /// 经缓存列出密钥，返回 upstream 至今收到的请求数
+    async fn list_through(
+        cache: &IdentityCache,
+        upstreams: &mut Upstreams,
+        agent: &FakeAgent,
+    ) -> usize {
+        let answer = cache.get_or_fetch(upstreams.request(list())).await.unwrap();
+        assert_eq!(answer.payload[0], proto::SSH_AGENT_IDENTITIES_ANSWER);
+        agent.requests().len()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answer_expires_after_the_ttl() {
+        let agent = FakeAgent::with_identities("agent", vec![identity(1, "one")]);
+        let mut upstreams = client(&agent).await;
+        let cache = IdentityCache::new(TTL);
+        assert_eq!(list_through(&cache, &mut upstreams, &agent).await, 1);
+        tokio::time::advance(TTL - Duration::from_millis(1)).await;
+        assert_eq!(list_through(&cache, &mut upstreams, &agent).await, 1);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(list_through(&cache, &mut upstreams, &agent).await, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_requests_share_one_fetch() {
+        let agent = FakeAgent::with_identities("agent", vec![identity(1, "one")]);
+        agent.set_delay(Duration::from_secs(1));
+        let cache = Arc::new(IdentityCache::new(TTL));
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (cache, mut upstreams) = (cache.clone(), client(&agent).await);
+            clients.push(tokio::spawn(async move {
+                cache.get_or_fetch(upstreams.request(list())).await.unwrap()
+            }));
+        }
+        for client in clients {
+            let answer = client.await.unwrap();
+            assert_eq!(answer.payload[0], proto::SSH_AGENT_IDENTITIES_ANSWER);
+        }
+        assert_eq!(agent.requests().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn key_changes_invalidate_the_answer() {
+        let agent = FakeAgent::with_identities("agent", vec![identity(1, "one")]);
+        let mut upstreams = client(&agent).await;
+        let cache = IdentityCache::new(TTL);
+        let mut fetched = list_through(&cache, &mut upstreams, &agent).await;
+        for msg_type in [
+            proto::SSH_AGENTC_ADD_IDENTITY,
+            proto::SSH_AGENTC_REMOVE_IDENTITY,
+            proto::SSH_AGENTC_REMOVE_ALL_IDENTITIES,
+            proto::SSH_AGENTC_LOCK,
+            proto::SSH_AGENTC_UNLOCK,
+        ] {
+            assert!(IdentityCache::invalidated_by(msg_type));
+            cache.invalidate();
+            let now = list_through(&cache, &mut upstreams, &agent).await;
+            assert_eq!(now, fetched + 1, "{}", proto::message_name(msg_type));
+            fetched = now;
+        }
+        assert!(!IdentityCache::invalidated_by(
+            proto::SSH_AGENTC_SIGN_REQUEST
+        ));
+        assert_eq!(list_through(&cache, &mut upstreams, &agent).await, fetched);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answer_fetched_across_an_invalidation_is_not_kept() {
+        let agent = FakeAgent::with_identities("agent", vec![identity(1, "one")]);
+        agent.set_delay(Duration::from_secs(1));
+        let mut upstreams = client(&agent).await;
+        let cache = IdentityCache::new(TTL);
+        // 获取期间密钥被删除，得到的列表可能已经过时
+        let fetch = cache.get_or_fetch(upstreams.request(list()));
+        let invalidate = async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            cache.invalidate();
+        };
+        let (answer, ()) = tokio::join!(fetch, invalidate);
+        assert_eq!(
+            answer.unwrap().payload[0],
+            proto::SSH_AGENT_IDENTITIES_ANSWER
+        );
+        assert_eq!(list_through(&cache, &mut upstreams, &agent).await, 2);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::proto::AgentMessage;
+use log::debug;
+use tokio_util::bytes::Buf;
+
+const HEADER_SIZE: usize = std::mem::size_of::<u32>(); // SSH agent protocol uses a 4-byte length header
+
+#[derive(Clone)]
+pub struct SshAgentMessage {
+    pub length: u32,
+    pub payload: Vec<u8>,
+}
+impl SshAgentMessage {
+    pub fn new(length: u32, payload: Vec<u8>) -> Self {
+        Self { length, payload }
+    }
+
+    pub fn from_payload(payload: Vec<u8>) -> Self {
+        Self::new(payload.len() as u32, payload)
+    }
+
+    /// 将负载解析为类型化的协议消息
+    pub fn parse(&self) -> anyhow::Result<AgentMessage> {
+        AgentMessage::parse(&self.payload)
+    }
+}
+
+impl From<&AgentMessage> for SshAgentMessage {
+    fn from(message: &AgentMessage) -> Self {
+        Self::from_payload(message.encode())
+    }
+}
+
+/// OpenSSH 的 ssh-agent 同样以 256 KiB 作为单条消息上限
+pub const DEFAULT_MAX_MESSAGE_SIZE: u32 = 256 * 1024;
+
+/// A frame rejected by [`SshAgentCodec`]; its payload has been discarded
+#[derive(Debug)]
+pub enum FrameError {
+    Empty,
+    TooLarge { length: u32, max: u32 },
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Empty => write!(f, "empty message"),
+            FrameError::TooLarge { length, max } => write!(
+                f,
+                "message length {} exceeds maximum of {} bytes",
+                length, max
+            ),
+        }
+    }
+}
+
+pub struct SshAgentCodec {
+    max_message_size: u32,
+    // 被拒绝的超长消息中尚未丢弃的字节数
+    discarding: usize,
+}
+
+impl SshAgentCodec {
+    pub fn new(max_message_size: u32) -> Self {
+        Self {
+            max_message_size,
+            discarding: 0,
+        }
+    }
+}
+
+impl Default for SshAgentCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+// impl tokio encoder decoder for SshAgentMessage
+//
+// Invalid frames are reported as `Ok(Some(Err(_)))` rather than as a decode
+// error so the stream stays usable: the offending payload is skipped without
+// ever being buffered and the caller decides whether to answer or disconnect.
+impl tokio_util::codec::Decoder for SshAgentCodec {
+    type Item = Result<SshAgentMessage, FrameError>;
+    type Error = std::io::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        debug!("Decoding message, buffer length: {}", src.len());
+        if self.discarding > 0 {
+            let skip = self.discarding.min(src.len());
+            src.advance(skip);
+            self.discarding -= skip;
+            if self.discarding > 0 {
+                return Ok(None);
+            }
+        }
+        if src.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(src[..HEADER_SIZE].try_into().map_err(|_| {
+            Self::Error::new(std::io::ErrorKind::InvalidData, "Failed to read length")
+        })?);
+        if length == 0 {
+            src.advance(HEADER_SIZE);
+            return Ok(Some(Err(FrameError::Empty)));
+        }
+        if length > self.max_message_size {
+            src.advance(HEADER_SIZE);
+            self.discarding = length as usize;
+            let skip = self.discarding.min(src.len());
+            src.advance(skip);
+            self.discarding -= skip;
+            return Ok(Some(Err(FrameError::TooLarge {
+                length,
+                max: self.max_message_size,
+            })));
+        }
+        if src.len() < HEADER_SIZE + length as usize {
+            src.reserve(HEADER_SIZE + length as usize - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_SIZE);
+        debug!("Decoding message, Message length: {}", length);
+        let payload = src.split_to(length as usize).to_vec();
+        Ok(Some(Ok(SshAgentMessage::new(length, payload))))
+    }
+
+    fn decode_eof(
+        &mut self,
+        buf: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() && self.discarding == 0 => Ok(None),
+            None => Err(Self::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Stream closed in the middle of a message",
+            )),
+        }
+    }
+}
+
+impl tokio_util::codec::Encoder<SshAgentMessage> for SshAgentCodec {
+    type Error = std::io::Error;
+    fn encode(
+        &mut self,
+        item: SshAgentMessage,
+        dst: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item.length.to_be_bytes());
+        dst.extend_from_slice(&item.payload);
+        debug!("Encoded message of length: {}", item.length);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// 按给定的分块依次喂给解码器，模拟多次读取，返回解出的全部帧
+    fn decode_chunks(
+        codec: &mut SshAgentCodec,
+        chunks: &[&[u8]],
+    ) -> Vec<Result<SshAgentMessage, FrameError>> {
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            buf.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn oversized_frame_split_across_reads_is_skipped() {
+        let mut codec = SshAgentCodec::new(16);
+        let oversized = frame(&[0xAA; 40]);
+        let valid = frame(&[11]);
+        let mut tail = oversized[30..].to_vec();
+        tail.extend_from_slice(&valid[..2]);
+        let frames = decode_chunks(
+            &mut codec,
+            &[
+                &oversized[..2],
+                &oversized[2..10],
+                &oversized[10..30],
+                &tail,
+                &valid[2..],
+            ],
+        );
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(
+            frames[0],
+            Err(FrameError::TooLarge {
+                length: 40,
+                max: 16
+            })
+        ));
+        let message = frames[1].as_ref().unwrap();
+        assert_eq!((message.length, message.payload.as_slice()), (1, &[11][..]));
+    }
+
+    #[test]
+    fn empty_frame_is_reported_and_skipped() {
+        let mut codec = SshAgentCodec::default();
+        let mut input = frame(&[]);
+        input.extend_from_slice(&frame(&[11]));
+        let frames = decode_chunks(&mut codec, &[&input]);
+        assert!(matches!(frames[0], Err(FrameError::Empty)));
+        assert_eq!(frames[1].as_ref().unwrap().payload, [11]);
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn eof_between_frames_ends_the_stream() {
+        let mut codec = SshAgentCodec::default();
+        let mut buf = BytesMut::from(&frame(&[11])[..]);
+        assert!(codec.decode_eof(&mut buf).unwrap().unwrap().is_ok());
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn eof_inside_a_frame_is_an_error() {
+        let input = frame(&[11, 0, 0]);
+        for cut in [2, HEADER_SIZE + 1] {
+            let mut codec = SshAgentCodec::default();
+            let mut buf = BytesMut::from(&input[..cut]);
+            let error = codec.decode_eof(&mut buf).err().expect("incomplete frame");
+            assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+        // 超长消息尚未丢弃完就关闭，同样是不完整的消息
+        let mut codec = SshAgentCodec::new(4);
+        let mut buf = BytesMut::from(&frame(&[0; 8])[..6]);
+        assert!(codec.decode_eof(&mut buf).unwrap().unwrap().is_err());
+        let error = codec.decode_eof(&mut buf).err().expect("incomplete frame");
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,591 @@
+//! Layered settings from a TOML file, named profiles and the environment
+//!
+//! Every command line option can also be set in
+//! `~/.config/wsl2-ssh-agent/config.toml` under its long name, in a
+//! `[profiles.NAME]` table selected with `--profile`, or through a
+//! `WSL2_SSH_AGENT_<NAME>` environment variable. `serve` options live in a
+//! `[serve]` table (`[profiles.NAME.serve]` for a profile).
+//!
+//! The layers are applied as clap default values, so the precedence is:
+//! command line, environment, profile, config file, built-in default.
+
+use anyhow::{Result, anyhow};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+pub const ENV_PREFIX: &str = "WSL2_SSH_AGENT_";
+
+/// 由调用方处理、不能写进配置文件的选项
+const RESERVED: [&str; 4] = ["help", "version", "config", "profile"];
+
+/// Where a setting came from
+#[derive(Clone, Debug)]
+enum Source {
+    File,
+    Profile(String),
+    Env(String),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::File => write!(f, "config file"),
+            Source::Profile(name) => write!(f, "profile '{}'", name),
+            Source::Env(var) => write!(f, "environment {}", var),
+        }
+    }
+}
+
+/// 查找环境变量；测试时换成内存中的环境
+type Env<'a> = &'a dyn Fn(&str) -> Option<OsString>;
+
+fn process_env(var: &str) -> Option<OsString> {
+    std::env::var_os(var)
+}
+
+struct Setting {
+    values: Vec<String>,
+    source: Source,
+}
+
+/// Settings collected from the config file, the selected profile and the environment
+pub struct Settings {
+    /// 实际读取的配置文件，文件不存在时为 `None`
+    pub path: Option<PathBuf>,
+    // 以长选项名为键
+    global: BTreeMap<String, Setting>,
+    serve: BTreeMap<String, Setting>,
+}
+
+/// `$XDG_CONFIG_HOME/wsl2-ssh-agent/config.toml`，否则为 `~/.config/wsl2-ssh-agent/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir(&process_env).map(|home| home.join(".config")))?;
+    Some(config_home.join("wsl2-ssh-agent").join("config.toml"))
+}
+
+/// Windows 上通常没有 HOME，退回 USERPROFILE
+fn home_dir(env: Env) -> Option<PathBuf> {
+    env("HOME")
+        .or_else(|| env("USERPROFILE"))
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+fn expand_home(value: &str, home: Option<&Path>) -> String {
+    match (value.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().into_owned(),
+        _ => value.to_string(),
+    }
+}
+
+fn find_arg<'a>(command: &'a Command, long: &str) -> Option<&'a Arg> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long))
+}
+
+fn takes_multiple(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append) || arg.get_value_delimiter().is_some()
+}
+
+fn is_flag(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::SetTrue)
+}
+
+fn configurable(command: &Command) -> impl Iterator<Item = &Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| !RESERVED.contains(&arg.get_id().as_str()) && arg.get_long().is_some())
+}
+
+/// `--upstream-mode` 对应 `WSL2_SSH_AGENT_UPSTREAM_MODE`
+fn env_var(arg: &Arg) -> String {
+    format!("{}{}", ENV_PREFIX, arg.get_id().as_str().to_uppercase())
+}
+
+fn toml_values(
+    key: &str,
+    value: &toml::Value,
+    source: &Source,
+    home: Option<&Path>,
+) -> Result<Vec<String>> {
+    let scalar = |value: &toml::Value| match value {
+        toml::Value::String(s) => Ok(expand_home(s, home)),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(anyhow!(
+            "Setting '{}' in {} must be a string, number, boolean or array of them",
+            key,
+            source
+        )),
+    };
+    match value {
+        toml::Value::Array(items) => items.iter().map(scalar).collect(),
+        value => Ok(vec![scalar(value)?]),
+    }
+}
+
+/// 环境变量中的布尔值接受常见写法
+fn env_flag(var: &str, value: &str) -> Result<String> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok("true".to_string()),
+        "0" | "false" | "no" | "off" | "" => Ok("false".to_string()),
+        _ => Err(anyhow!(
+            "Environment variable {} must be true or false, got '{}'",
+            var,
+            value
+        )),
+    }
+}
+
+impl Settings {
+    /// 读取配置文件与环境变量；`path` 未指定且默认文件不存在时只使用环境变量
+    pub fn load(path: Option<&Path>, profile: Option<&str>, command: &Command) -> Result<Self> {
+        let file = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => default_path().filter(|path| path.exists()),
+        };
+        let file = match file {
+            Some(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow!("Failed to read config '{}': {}", path.display(), e))?;
+                Some((path, text))
+            }
+            None => None,
+        };
+        Self::parse(file, profile, command, &process_env)
+    }
+
+    /// 由已读取的配置文件内容与给定的环境变量构建各层配置
+    fn parse(
+        file: Option<(PathBuf, String)>,
+        profile: Option<&str>,
+        command: &Command,
+        env: Env,
+    ) -> Result<Self> {
+        let serve = command
+            .find_subcommand("serve")
+            .expect("serve subcommand is defined");
+        let home = home_dir(env);
+        let home = home.as_deref();
+        let mut settings = Self {
+            path: None,
+            global: BTreeMap::new(),
+            serve: BTreeMap::new(),
+        };
+
+        let mut table = match &file {
+            Some((path, text)) => text
+                .parse::<toml::Table>()
+                .map_err(|e| anyhow!("Invalid config '{}': {}", path.display(), e))?,
+            None => toml::Table::new(),
+        };
+        settings.path = file.map(|(path, _)| path);
+
+        let profiles = match table.remove("profiles") {
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => return Err(anyhow!("'profiles' in the config file must be a table")),
+            None => toml::Table::new(),
+        };
+        settings.merge(table, Source::File, command, serve, home)?;
+        // 未选中的 profile 也检查一遍，拼错的选项名不会等到切换时才发现
+        for (name, table) in &profiles {
+            let toml::Value::Table(table) = table else {
+                return Err(anyhow!("Profile '{}' must be a table", name));
+            };
+            let mut scratch = Self {
+                path: None,
+                global: BTreeMap::new(),
+                serve: BTreeMap::new(),
+            };
+            let source = Source::Profile(name.clone());
+            scratch.merge(table.clone(), source, command, serve, home)?;
+        }
+        if let Some(name) = profile {
+            let Some(toml::Value::Table(table)) = profiles.get(name) else {
+                return Err(anyhow!(
+                    "Profile '{}' is not defined in {}",
+                    name,
+                    settings
+                        .path
+                        .as_ref()
+                        .map_or("any config file".to_string(), |path| format!(
+                            "'{}'",
+                            path.display()
+                        ))
+                ));
+            };
+            let source = Source::Profile(name.to_string());
+            settings.merge(table.clone(), source, command, serve, home)?;
+        }
+
+        for (target, command) in [
+            (&mut settings.global, command),
+            (&mut settings.serve, serve),
+        ] {
+            for arg in configurable(command) {
+                let var = env_var(arg);
+                let Some(value) = env(&var) else {
+                    continue;
+                };
+                let value = value.to_string_lossy().into_owned();
+                let value = if is_flag(arg) {
+                    env_flag(&var, &value)?
+                } else {
+                    value
+                };
+                let long = arg.get_long().unwrap().to_string();
+                let setting = Setting {
+                    values: vec![value],
+                    source: Source::Env(var),
+                };
+                target.insert(long, setting);
+            }
+        }
+        Ok(settings)
+    }
+
+    /// 合并一层配置；`[serve]` 子表对应 serve 子命令的选项
+    fn merge(
+        &mut self,
+        mut table: toml::Table,
+        source: Source,
+        command: &Command,
+        serve: &Command,
+        home: Option<&Path>,
+    ) -> Result<()> {
+        if let Some(value) = table.remove("serve") {
+            let toml::Value::Table(serve_table) = value else {
+                return Err(anyhow!("'serve' in {} must be a table", source));
+            };
+            for (key, value) in serve_table {
+                let arg = find_arg(serve, &key)
+                    .filter(|arg| !RESERVED.contains(&arg.get_id().as_str()))
+                    .ok_or_else(|| anyhow!("Unknown serve setting '{}' in {}", key, source))?;
+                let setting = Self::setting(arg, &key, &value, &source, home)?;
+                self.serve.insert(key, setting);
+            }
+        }
+        for (key, value) in table {
+            let Some(arg) =
+                find_arg(command, &key).filter(|arg| !RESERVED.contains(&arg.get_id().as_str()))
+            else {
+                let hint = if find_arg(serve, &key).is_some() {
+                    " (serve options belong in a [serve] table)"
+                } else {
+                    ""
+                };
+                return Err(anyhow!("Unknown setting '{}' in {}{}", key, source, hint));
+            };
+            let setting = Self::setting(arg, &key, &value, &source, home)?;
+            self.global.insert(key, setting);
+        }
+        Ok(())
+    }
+
+    fn setting(
+        arg: &Arg,
+        key: &str,
+        value: &toml::Value,
+        source: &Source,
+        home: Option<&Path>,
+    ) -> Result<Setting> {
+        let values = toml_values(key, value, source, home)?;
+        if values.len() != 1 && !takes_multiple(arg) {
+            return Err(anyhow!(
+                "Setting '{}' in {} takes a single value",
+                key,
+                source
+            ));
+        }
+        Ok(Setting {
+            values,
+            source: source.clone(),
+        })
+    }
+
+    /// 把各层配置设为 clap 默认值，命令行参数仍然优先
+    pub fn apply(&self, mut command: Command) -> Command {
+        fn set_default(arg: Arg, setting: &Setting) -> Arg {
+            // 已配置的必填参数（如 serve 的 --listen）不再要求出现在命令行上
+            let arg = arg.default_values(setting.values.clone()).required(false);
+            // 配置文件与 WSL2_SSH_AGENT_* 优先于 SSH_ASKPASS 这类通用环境变量
+            if arg.get_env().is_some() {
+                arg.env(None)
+            } else {
+                arg
+            }
+        }
+        for (long, setting) in &self.global {
+            let id = find_arg(&command, long).unwrap().get_id().to_string();
+            command = command.mut_arg(id, |arg| set_default(arg, setting));
+        }
+        command.mut_subcommand("serve", |mut serve| {
+            for (long, setting) in &self.serve {
+                let id = find_arg(&serve, long).unwrap().get_id().to_string();
+                serve = serve.mut_arg(id, |arg| set_default(arg, setting));
+            }
+            serve
+        })
+    }
+
+    /// 生效的配置，每项注明来源
+    ///
+    /// `command` 应为未应用配置的命令，以保持选项的声明顺序。
+    pub fn entries(&self, command: &Command, matches: &ArgMatches) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        for arg in configurable(command) {
+            let id = arg.get_id().as_str();
+            let long = arg.get_long().unwrap();
+            let values: Option<Vec<String>> = matches.get_raw(id).map(|raw| {
+                raw.map(|value| value.to_string_lossy().into_owned())
+                    .collect()
+            });
+            let source = match matches.value_source(id) {
+                Some(ValueSource::CommandLine) => "command line".to_string(),
+                Some(ValueSource::EnvVariable) => format!(
+                    "environment {}",
+                    arg.get_env().unwrap_or_default().to_string_lossy()
+                ),
+                _ => self
+                    .global
+                    .get(long)
+                    .map_or("default".to_string(), |setting| setting.source.to_string()),
+            };
+            entries.push(Entry::new(long.to_string(), arg, values, source));
+        }
+
+        let serve = command
+            .find_subcommand("serve")
+            .expect("serve subcommand is defined");
+        for arg in configurable(serve) {
+            let long = arg.get_long().unwrap();
+            let (values, source) = match self.serve.get(long) {
+                Some(setting) => (Some(setting.values.clone()), setting.source.to_string()),
+                None => {
+                    let defaults: Vec<String> = arg
+                        .get_default_values()
+                        .iter()
+                        .map(|value| value.to_string_lossy().into_owned())
+                        .collect();
+                    (
+                        (!defaults.is_empty()).then_some(defaults),
+                        "default".to_string(),
+                    )
+                }
+            };
+            entries.push(Entry::new(format!("serve.{}", long), arg, values, source));
+        }
+        entries
+    }
+
+    /// 以 TOML 输出生效的配置，每项注明来源
+    pub fn render(&self, command: &Command, matches: &ArgMatches) -> String {
+        let mut out = String::new();
+        let mut in_serve = false;
+        for entry in self.entries(command, matches) {
+            let key = match entry.key.strip_prefix("serve.") {
+                Some(key) => {
+                    if !in_serve {
+                        out.push_str("\n[serve]\n");
+                        in_serve = true;
+                    }
+                    key
+                }
+                None => &entry.key,
+            };
+            match &entry.value {
+                Some(value) => {
+                    let _ = writeln!(out, "{} = {}  # {}", key, value, entry.source);
+                }
+                None => {
+                    let _ = writeln!(out, "# {} is not set", key);
+                }
+            }
+        }
+        out
+    }
+
+    /// serve 子命令配置的值，用于 `config check` 校验监听地址
+    pub fn serve_value(&self, long: &str) -> Option<&str> {
+        self.serve
+            .get(long)
+            .and_then(|setting| setting.values.first())
+            .map(String::as_str)
+    }
+}
+
+/// One effective setting
+pub struct Entry {
+    /// 长选项名，serve 的选项以 `serve.` 开头
+    pub key: String,
+    /// TOML 形式的值，未设置时为 `None`
+    pub value: Option<String>,
+    pub source: String,
+}
+
+impl Entry {
+    fn new(key: String, arg: &Arg, values: Option<Vec<String>>, source: String) -> Self {
+        let value = values.map(|values| {
+            if takes_multiple(arg) {
+                let items: Vec<String> = values.iter().map(|value| toml_literal(value)).collect();
+                format!("[{}]", items.join(", "))
+            } else {
+                toml_literal(values.first().map_or("", String::as_str))
+            }
+        });
+        Self { key, value, source }
+    }
+}
+
+/// 数字与布尔值原样输出，其余作为字符串
+fn toml_literal(value: &str) -> String {
+    if value == "true" || value == "false" || value.parse::<i64>().is_ok() {
+        value.to_string()
+    } else {
+        toml::Value::String(value.to_string()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::CommandFactory;
+
+    const CONFIG: &str = r#"
+retries = 1
+policy = "~/policy.toml"
+
+[serve]
+listen = "unix:/tmp/agent.sock"
+
+[profiles.work]
+retries = 2
+"#;
+
+    fn parse(text: &str, profile: Option<&str>, env: &[(&str, &str)]) -> Result<Settings> {
+        let env: Vec<(String, OsString)> = env
+            .iter()
+            .map(|(var, value)| (var.to_string(), OsString::from(value)))
+            .collect();
+        let lookup = |var: &str| {
+            env.iter()
+                .find(|(name, _)| name == var)
+                .map(|(_, value)| value.clone())
+        };
+        let file = Some((PathBuf::from("config.toml"), text.to_string()));
+        Settings::parse(file, profile, &Cli::command(), &lookup)
+    }
+
+    fn matches(settings: &Settings, args: &[&str]) -> ArgMatches {
+        let args = std::iter::once("wsl2-ssh-agent").chain(args.iter().copied());
+        settings
+            .apply(Cli::command())
+            .try_get_matches_from(args)
+            .unwrap()
+    }
+
+    fn retries(settings: &Settings, args: &[&str]) -> u32 {
+        *matches(settings, args).get_one::<u32>("retries").unwrap()
+    }
+
+    #[test]
+    fn layers_apply_in_order_of_precedence() {
+        let env = [("WSL2_SSH_AGENT_RETRIES", "3")];
+        assert_eq!(retries(&parse("", None, &[]).unwrap(), &[]), 30);
+        assert_eq!(retries(&parse(CONFIG, None, &[]).unwrap(), &[]), 1);
+        assert_eq!(retries(&parse(CONFIG, Some("work"), &[]).unwrap(), &[]), 2);
+        let settings = parse(CONFIG, Some("work"), &env).unwrap();
+        assert_eq!(retries(&settings, &[]), 3);
+        assert_eq!(retries(&settings, &["--retries", "4"]), 4);
+    }
+
+    #[test]
+    fn environment_flags_accept_common_spellings() {
+        for (value, expected) in [("yes", true), ("1", true), ("off", false), ("", false)] {
+            let settings = parse("", None, &[("WSL2_SSH_AGENT_WAIT", value)]).unwrap();
+            assert_eq!(
+                matches(&settings, &[]).get_flag("wait"),
+                expected,
+                "{value:?}"
+            );
+        }
+        assert!(parse("", None, &[("WSL2_SSH_AGENT_WAIT", "maybe")]).is_err());
+    }
+
+    #[test]
+    fn unknown_and_misplaced_settings_are_rejected() {
+        let error = |text: &str| parse(text, None, &[]).err().unwrap().to_string();
+        assert!(error("retrys = 1").contains("Unknown setting 'retrys'"));
+        assert!(error("listen = \"unix:/x\"").contains("belong in a [serve] table"));
+        assert!(error("[serve]\nlistn = \"unix:/x\"").contains("Unknown serve setting"));
+        assert!(error("config = \"other.toml\"").contains("Unknown setting 'config'"));
+        // 未选中的 profile 同样检查
+        assert!(error("[profiles.home]\nretrys = 1").contains("profile 'home'"));
+        assert!(error("retries = [1, 2]").contains("takes a single value"));
+        assert!(error("retries = 1\nretries = 2").contains("Invalid config"));
+        let missing = parse(CONFIG, Some("home"), &[]).err().unwrap();
+        assert!(
+            missing
+                .to_string()
+                .contains("Profile 'home' is not defined")
+        );
+    }
+
+    #[test]
+    fn home_is_expanded_at_the_start_of_strings_only() {
+        let text = "policy = \"~/policy.toml\"\nhelper = \"a~/b\"";
+        let expanded = matches(&parse(text, None, &[("HOME", "/home/me")]).unwrap(), &[]);
+        let policy = expanded.get_one::<PathBuf>("policy").unwrap();
+        assert_eq!(policy, Path::new("/home/me/policy.toml"));
+        assert_eq!(expanded.get_one::<String>("helper").unwrap(), "a~/b");
+        // 没有 HOME 时原样保留
+        let kept = matches(&parse(text, None, &[]).unwrap(), &[]);
+        let policy = kept.get_one::<PathBuf>("policy").unwrap();
+        assert_eq!(policy, Path::new("~/policy.toml"));
+    }
+
+    #[test]
+    fn effective_settings_name_their_source() {
+        let env = [
+            ("HOME", "/home/me"),
+            ("WSL2_SSH_AGENT_UPSTREAM", "unix:/tmp/up.sock"),
+        ];
+        let settings = parse(CONFIG, Some("work"), &env).unwrap();
+        let matches = matches(&settings, &["--verbose"]);
+        let entries = settings.entries(&Cli::command(), &matches);
+        let entry = |key: &str| entries.iter().find(|entry| entry.key == key).unwrap();
+        assert_eq!(entry("retries").value.as_deref(), Some("2"));
+        assert_eq!(entry("retries").source, "profile 'work'");
+        assert_eq!(entry("verbose").source, "command line");
+        assert_eq!(entry("serve.pool-size").source, "default");
+        assert_eq!(entry("audit-log").value, None);
+
+        let rendered = settings.render(&Cli::command(), &matches);
+        for line in [
+            "upstream = [\"unix:/tmp/up.sock\"]  # environment WSL2_SSH_AGENT_UPSTREAM",
+            "policy = \"/home/me/policy.toml\"  # config file",
+            "retries = 2  # profile 'work'",
+            "verbose = true  # command line",
+            "# audit-log is not set",
+            "[serve]",
+            "listen = \"unix:/tmp/agent.sock\"  # config file",
+            "pool-size = 0  # default",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{line}\n{rendered}");
+        }
+        // serve 的选项都在 [serve] 表之后
+        let serve = rendered.find("[serve]").unwrap();
+        assert!(rendered.find("pool-size").unwrap() > serve);
+        assert!(rendered.find("retries").unwrap() < serve);
+    }
+}
@@ -0,0 +1,99 @@
+//! Interactive approval of sign requests through an SSH_ASKPASS-style program
+
+use log::{debug, warn};
+use std::time::Duration;
+
+/// 对话框中每一段外部文本最多显示的字符数
+const MAX_FIELD_CHARS: usize = 120;
+
+/// 把客户端或 upstream 提供的文本转为可安全显示在对话框中的形式
+///
+/// Control characters (newlines, escape sequences, ...) are escaped so they
+/// cannot fake extra lines in the prompt, and long text is cut off.
+pub fn printable(text: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in text.chars().enumerate() {
+        if i == MAX_FIELD_CHARS {
+            out.push('…');
+            break;
+        }
+        if c.is_control() {
+            out.extend(c.escape_default());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Runs `command <message>` and treats exit status 0 as approval, like
+/// `ssh-agent` does for keys added with `ssh-add -c`.
+pub struct Confirmer {
+    command: String,
+    timeout: Duration,
+}
+
+impl Confirmer {
+    pub fn new(command: impl Into<String>, timeout: Duration) -> Self {
+        Self {
+            command: command.into(),
+            timeout,
+        }
+    }
+
+    /// 询问用户是否允许；程序无法启动、超时或非零退出都视为拒绝
+    pub async fn confirm(&self, message: &str) -> bool {
+        let child = tokio::process::Command::new(&self.command)
+            .arg(message)
+            // OpenSSH 通过该变量告诉 askpass 这是确认对话框而非口令输入
+            .env("SSH_ASKPASS_PROMPT", "confirm")
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run confirm command '{}': {}", self.command, e);
+                return false;
+            }
+        };
+        match tokio::time::timeout(self.timeout, child.wait()).await {
+            Ok(Ok(status)) => {
+                debug!("Confirm command exited with {}", status);
+                status.success()
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to wait for confirm command: {}", e);
+                false
+            }
+            Err(_) => {
+                warn!(
+                    "Confirm command did not answer within {:?}, denying",
+                    self.timeout
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printable_escapes_control_characters_and_caps_the_length() {
+        assert_eq!(printable("git@github.com"), "git@github.com");
+        assert_eq!(printable("密钥 ok"), "密钥 ok");
+        assert_eq!(
+            printable("a\nAllow everything?\x1b[2J\u{7f}"),
+            "a\\nAllow everything?\\u{1b}[2J\\u{7f}"
+        );
+        let long = "x".repeat(1000);
+        assert_eq!(
+            printable(&long),
+            format!("{}…", "x".repeat(MAX_FIELD_CHARS))
+        );
+    }
+}
@@ -0,0 +1,642 @@
+//! JSON-RPC admin API of a running `serve` process
+//!
+//! The control socket (`serve --control-listen unix:/path`) speaks JSON-RPC
+//! 2.0 with one JSON object per line in each direction. It is only reachable
+//! by the owning user (mode 0600) and is not subject to the key policy or
+//! `--allow-operations`. `wsl2-ssh-agent ctl` is the matching client.
+//!
+//! Methods: `status`, `list-clients`, `disconnect-client` (`{"id": N}`),
+//! `list-upstreams`, `reload`, `flush-cache`, `lock`/`unlock`
+//! (`{"passphrase": "..."}`) and `stats`.
+//!
+//! Requests without an `id` are notifications: they are carried out but never
+//! answered. Every method gives up after 30 seconds.
+//!
+//! `subscribe` (optionally `{"events": ["sign-approved", ...]}`) answers once
+//! and then turns the connection into a stream of event objects, one per
+//! line, until the client disconnects (see [`crate::events`]).
+
+use crate::bridge::BridgeOptions;
+use crate::codec::SshAgentMessage;
+use crate::events::{Event, Events};
+use crate::metrics::Metrics;
+use crate::pool::PoolStats;
+use crate::proto::{self, AgentMessage};
+use crate::reload::{Live, ReloadHandle};
+use crate::server::{self, Clients};
+use crate::upstream::{UpstreamOptions, Upstreams};
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use futures::SinkExt;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+/// 单个请求行的长度上限
+const MAX_LINE: usize = 64 * 1024;
+/// `list-upstreams` 探测每个 upstream 的最长时间
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// 单个方法调用的最长时间，例如 upstream 不可用时的 lock/unlock
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// JSON-RPC 2.0 预定义的错误码
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// 调用本身失败，例如 upstream 拒绝 LOCK
+const CALL_FAILED: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(CALL_FAILED, e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct ClientParams {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct LockParams {
+    passphrase: String,
+}
+
+#[derive(Default, Deserialize)]
+struct SubscribeParams {
+    /// 只接收这些事件，未设置时接收全部
+    events: Option<Vec<String>>,
+}
+
+/// Everything the control socket can inspect or act on
+pub struct Control {
+    pub listen: String,
+    pub started: SystemTime,
+    pub clients: Arc<Clients>,
+    pub upstream: UpstreamOptions,
+    pub options: Arc<Live<BridgeOptions>>,
+    pub reload: ReloadHandle,
+    pub metrics: Arc<Metrics>,
+    pub events: Arc<Events>,
+}
+
+/// 绑定控制 socket 并在后台处理请求
+#[cfg(unix)]
+pub async fn spawn(spec: &str, control: Arc<Control>) -> Result<()> {
+    let path = server::parse_listen(spec)?;
+    match std::fs::remove_file(&path) {
+        Ok(()) => debug!("Removed stale socket {}", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(anyhow!("Failed to remove stale socket '{}': {}", path, e)),
+    }
+    let listener = server::bind_private(&path)
+        .map_err(|e| anyhow!("Failed to listen for control requests on '{}': {}", path, e))?;
+    debug!("Accepting control requests on unix:{}", path);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &control).await {
+                            debug!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept control client: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn spawn(spec: &str, _control: Arc<Control>) -> Result<()> {
+    server::parse_listen(spec)?;
+    Err(anyhow!("The control socket is only supported on Unix"))
+}
+
+/// 逐行读取请求并按顺序应答，直到客户端关闭连接；通知（没有 `id` 的请求）不应答
+#[cfg(unix)]
+async fn handle_connection(stream: tokio::net::UnixStream, control: &Control) -> Result<()> {
+    let (read, write) = stream.into_split();
+    let mut reader = FramedRead::new(read, LinesCodec::new_with_max_length(MAX_LINE));
+    let mut writer = FramedWrite::new(write, LinesCodec::new());
+    while let Some(line) = reader.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value = match serde_json::from_str::<Value>(&line) {
+            Ok(value) => value,
+            Err(e) => {
+                let response =
+                    error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()));
+                writer.send(response.to_string()).await?;
+                continue;
+            }
+        };
+        let notification = value.get("id").is_none();
+        let request = match serde_json::from_value::<Request>(value) {
+            Ok(request) => request,
+            Err(e) => {
+                let response =
+                    error_response(Value::Null, RpcError::new(INVALID_REQUEST, e.to_string()));
+                writer.send(response.to_string()).await?;
+                continue;
+            }
+        };
+        if request.method == "subscribe" {
+            let filter = match subscribe_filter(request.params) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    if !notification {
+                        writer
+                            .send(error_response(request.id, e).to_string())
+                            .await?;
+                    }
+                    continue;
+                }
+            };
+            // 先订阅再应答，应答之后发生的事件都不会丢失
+            let receiver = control.events.subscribe();
+            if !notification {
+                let names: Vec<&str> = match &filter {
+                    Some(names) => names.iter().map(String::as_str).collect(),
+                    None => Event::NAMES.to_vec(),
+                };
+                let result = json!({"subscribed": names});
+                let response = json!({"jsonrpc": "2.0", "id": request.id, "result": result});
+                writer.send(response.to_string()).await?;
+            }
+            return stream_events(reader, writer, receiver, filter).await;
+        }
+        debug!("Control request: {}", request.method);
+        let result = tokio::time::timeout(
+            CALL_TIMEOUT,
+            control.dispatch(&request.method, request.params),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(RpcError::new(
+                CALL_FAILED,
+                format!("'{}' timed out after {:?}", request.method, CALL_TIMEOUT),
+            ))
+        });
+        if notification {
+            continue;
+        }
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": request.id, "result": result}),
+            Err(e) => error_response(request.id, e),
+        };
+        writer.send(response.to_string()).await?;
+    }
+    Ok(())
+}
+
+fn subscribe_filter(params: Value) -> Result<Option<Vec<String>>, RpcError> {
+    let params: SubscribeParams = if params.is_null() {
+        SubscribeParams::default()
+    } else {
+        self::params(params)?
+    };
+    if let Some(unknown) = params
+        .events
+        .iter()
+        .flatten()
+        .find(|name| !Event::NAMES.contains(&name.as_str()))
+    {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!(
+                "Unknown event '{}', expected one of {}",
+                unknown,
+                Event::NAMES.join(", ")
+            ),
+        ));
+    }
+    Ok(params.events)
+}
+
+/// 把事件逐行写给订阅者，直到它关闭连接；跟不上时告知丢失的事件数
+#[cfg(unix)]
+async fn stream_events(
+    mut reader: Reader,
+    mut writer: Writer,
+    mut receiver: tokio::sync::broadcast::Receiver<Arc<crate::events::Stamped>>,
+    filter: Option<Vec<String>>,
+) -> Result<()> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    debug!("Control client subscribed to events");
+    loop {
+        let line = tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(stamped) => {
+                    let name = stamped.event.name();
+                    if filter.as_ref().is_some_and(|names| !names.iter().any(|n| n == name)) {
+                        continue;
+                    }
+                    serde_json::to_string(&*stamped)?
+                }
+                Err(RecvError::Lagged(missed)) => json!({"event": "lagged", "missed": missed}).to_string(),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // 订阅后不再处理请求，客户端关闭即结束
+            line = reader.next() => match line {
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+                None => {
+                    debug!("Event subscriber disconnected");
+                    return Ok(());
+                }
+            },
+        };
+        writer.send(line).await?;
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": error.code, "message": error.message},
+    })
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+fn pool_json(stats: &PoolStats) -> Value {
+    json!({
+        "size": stats.size,
+        "idle": stats.idle,
+        "in_use": stats.in_use,
+        "borrows": stats.borrows,
+        "reused": stats.reused,
+        "opened": stats.opened,
+        "discarded": stats.discarded,
+        "waits": stats.waits,
+        "wait_seconds": stats.wait_time.as_secs_f64(),
+        "max_wait_seconds": stats.max_wait.as_secs_f64(),
+    })
+}
+
+impl Control {
+    async fn dispatch(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "status" => Ok(self.status()),
+            "list-clients" => Ok(self.list_clients()),
+            "disconnect-client" => {
+                let ClientParams { id } = self::params(params)?;
+                if !self.clients.disconnect(id) {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        format!("No client #{} is connected", id),
+                    ));
+                }
+                Ok(json!({"disconnected": id}))
+            }
+            "list-upstreams" => Ok(self.list_upstreams().await),
+            "reload" => {
+                let changes = self.reload.reload().await?;
+                Ok(json!({"changes": changes}))
+            }
+            "flush-cache" => {
+                let options = self.options.load();
+                let Some(cache) = &options.cache else {
+                    return Ok(json!({"flushed": false}));
+                };
+                cache.invalidate();
+                Ok(json!({"flushed": true}))
+            }
+            "lock" | "unlock" => {
+                let LockParams { passphrase } = self::params(params)?;
+                let passphrase = passphrase.into_bytes();
+                let msg = if method == "lock" {
+                    AgentMessage::Lock { passphrase }
+                } else {
+                    AgentMessage::Unlock { passphrase }
+                };
+                self.forward(SshAgentMessage::from(&msg)).await?;
+                Ok(json!({"ok": true}))
+            }
+            "stats" => {
+                let mut stats = self.metrics.snapshot();
+                stats["pools"] = self.pools();
+                Ok(stats)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method '{}'", method),
+            )),
+        }
+    }
+
+    fn status(&self) -> Value {
+        let options = self.options.load();
+        let uptime = self.started.elapsed().unwrap_or_default();
+        let operations: Vec<String> = options
+            .allowed_operations
+            .iter()
+            .filter_map(|operation| operation.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "pid": std::process::id(),
+            "started": timestamp(self.started),
+            "uptime_seconds": uptime.as_secs(),
+            "listen": self.listen,
+            "clients": self.clients.len(),
+            "clients_total": self.clients.total(),
+            "upstreams": self.upstream.transports.len(),
+            "upstream_mode": self.upstream.mode.to_possible_value().map(|value| value.get_name().to_string()),
+            "pooled": self.upstream.pools.is_some(),
+            "policy_rules": options.policy.as_ref().map(|policy| policy.rules().len()),
+            "allowed_operations": operations,
+            "identity_cache": options.cache.is_some(),
+            "audit_log": options.audit.as_ref().map(|audit| audit.path().display().to_string()),
+        })
+    }
+
+    fn list_clients(&self) -> Value {
+        let mut clients = Vec::new();
+        self.clients.for_each(|entry| {
+            let info = &entry.info;
+            clients.push(json!({
+                "id": entry.id,
+                "pid": info.pid,
+                "uid": info.uid,
+                "process": info.process,
+                "connected": timestamp(entry.connected),
+                "requests": info.requests.load(std::sync::atomic::Ordering::Relaxed),
+            }));
+        });
+        Value::Array(clients)
+    }
+
+    /// 逐个尝试连接 upstream，报告是否可达及连接池状态
+    async fn list_upstreams(&self) -> Value {
+        let mut upstreams = Vec::new();
+        for (index, transport) in self.upstream.transports.iter().enumerate() {
+            let (reachable, error) =
+                match tokio::time::timeout(PROBE_TIMEOUT, transport.connect()).await {
+                    Ok(Ok(_)) => (true, None),
+                    Ok(Err(e)) => (false, Some(e.to_string())),
+                    Err(_) => (false, Some(format!("timed out after {:?}", PROBE_TIMEOUT))),
+                };
+            let pool = self
+                .upstream
+                .pools
+                .as_ref()
+                .map(|pools| pool_json(&pools[index].stats()));
+            upstreams.push(json!({
+                "index": index,
+                "address": transport.to_string(),
+                "reachable": reachable,
+                "error": error,
+                "pool": pool,
+            }));
+        }
+        Value::Array(upstreams)
+    }
+
+    fn pools(&self) -> Value {
+        let Some(pools) = &self.upstream.pools else {
+            return Value::Null;
+        };
+        pools
+            .iter()
+            .map(|pool| {
+                let mut stats = pool_json(&pool.stats());
+                stats["address"] = json!(pool.transport().to_string());
+                stats
+            })
+            .collect()
+    }
+
+    /// 通过独立的 upstream 会话发送一个请求，要求 upstream 回复 SUCCESS
+    async fn forward(&self, msg: SshAgentMessage) -> Result<()> {
+        let options = self.options.load();
+        let name = proto::message_name(msg.payload[0]);
+        let exchange = async {
+            let mut upstreams = Upstreams::connect(&self.upstream).await?;
+            upstreams.request(msg).await
+        };
+        let reply = match options.timeouts.other {
+            Some(limit) => tokio::time::timeout(limit, exchange)
+                .await
+                .map_err(|_| anyhow!("{} timed out after {:?}", name, limit))??,
+            None => exchange.await?,
+        };
+        if let Some(cache) = &options.cache {
+            cache.invalidate();
+        }
+        if reply.payload[0] != proto::SSH_AGENT_SUCCESS {
+            return Err(anyhow!("The upstream agent refused {}", name));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+type Reader = FramedRead<tokio::net::unix::OwnedReadHalf, LinesCodec>;
+#[cfg(unix)]
+type Writer = FramedWrite<tokio::net::unix::OwnedWriteHalf, LinesCodec>;
+
+/// 连接控制 socket，发送一个请求并读取应答
+#[cfg(unix)]
+async fn request(spec: &str, method: &str, params: Value) -> Result<(Reader, Writer, Value)> {
+    let path = server::parse_listen(spec)?;
+    let stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .map_err(|e| anyhow!("Failed to connect to control socket '{}': {}", path, e))?;
+    let (read, write) = stream.into_split();
+    let mut reader = FramedRead::new(read, LinesCodec::new());
+    let mut writer = FramedWrite::new(write, LinesCodec::new());
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    writer.send(request.to_string()).await?;
+    let line = reader
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("The control socket closed the connection"))??;
+    let mut response: Value = serde_json::from_str(&line)?;
+    if let Some(error) = response.get("error") {
+        return Err(anyhow!(
+            "{} (code {})",
+            error["message"].as_str().unwrap_or("unknown error"),
+            error["code"]
+        ));
+    }
+    let result = response["result"].take();
+    Ok((reader, writer, result))
+}
+
+/// 向控制 socket 发送一个请求并返回结果
+#[cfg(unix)]
+pub async fn call(spec: &str, method: &str, params: Value) -> Result<Value> {
+    let (_, _, result) = request(spec, method, params).await?;
+    Ok(result)
+}
+
+/// An event stream opened with `subscribe`
+#[cfg(unix)]
+pub struct Subscription {
+    reader: Reader,
+    // 保持连接打开，关闭写端会被视为取消订阅
+    _writer: Writer,
+}
+
+#[cfg(unix)]
+impl Subscription {
+    /// 下一个事件；serve 退出时返回 `None`
+    pub async fn next(&mut self) -> Result<Option<Value>> {
+        match self.reader.next().await {
+            Some(line) => Ok(Some(serde_json::from_str(&line?)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// 订阅事件流，`events` 为空时接收全部事件
+#[cfg(unix)]
+pub async fn subscribe(spec: &str, events: &[String]) -> Result<Subscription> {
+    let params = if events.is_empty() {
+        Value::Null
+    } else {
+        json!({"events": events})
+    };
+    let (reader, writer, _) = request(spec, "subscribe", params).await?;
+    Ok(Subscription {
+        reader,
+        _writer: writer,
+    })
+}
+
+#[cfg(not(unix))]
+pub async fn call(spec: &str, _method: &str, _params: Value) -> Result<Value> {
+    server::parse_listen(spec)?;
+    Err(anyhow!("The control socket is only supported on Unix"))
+}
+
+#[cfg(not(unix))]
+pub struct Subscription(std::convert::Infallible);
+
+#[cfg(not(unix))]
+impl Subscription {
+    pub async fn next(&mut self) -> Result<Option<Value>> {
+        match self.0 {}
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn subscribe(spec: &str, _events: &[String]) -> Result<Subscription> {
+    server::parse_listen(spec)?;
+    Err(anyhow!("The control socket is only supported on Unix"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::{Connect, FakeAgent, bridge_options, upstream_options};
+
+    fn control() -> Control {
+        let options = Arc::new(Live::new(bridge_options()));
+        let reload = crate::reload::spawn(options.clone(), Vec::new(), |_| {
+            Err(anyhow!("not reloadable"))
+        });
+        // upstream 连接永远不返回
+        let agent = FakeAgent::new("hanging");
+        agent.set_connect(Connect::Hang);
+        Control {
+            listen: "unix:/tmp/agent.sock".to_string(),
+            started: SystemTime::now(),
+            clients: Arc::new(Clients::default()),
+            upstream: upstream_options(&[agent]),
+            options,
+            reload,
+            metrics: Arc::new(Metrics::default()),
+            events: Arc::new(Events::default()),
+        }
+    }
+
+    /// 在内存中的 socket 上运行控制连接，逐行发送请求并返回第一条应答
+    async fn first_response(lines: &[Value]) -> Value {
+        let (client, server) = tokio::net::UnixStream::pair().unwrap();
+        let control = control();
+        let serve = async {
+            let _ = handle_connection(server, &control).await;
+        };
+        let exchange = async {
+            let (read, write) = client.into_split();
+            let mut reader = FramedRead::new(read, LinesCodec::new());
+            let mut writer = FramedWrite::new(write, LinesCodec::new());
+            for line in lines {
+                writer.send(line.to_string()).await.unwrap();
+            }
+            let line = reader.next().await.unwrap().unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        tokio::select! {
+            _ = serve => panic!("control connection ended early"),
+            response = exchange => response,
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_are_not_answered() {
+        let response = first_response(&[
+            json!({"jsonrpc": "2.0", "method": "status"}),
+            json!({"jsonrpc": "2.0", "method": "no-such-method"}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "status"}),
+        ])
+        .await;
+        assert_eq!(response["id"], 2);
+        assert!(response["result"].is_object());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lock_gives_up_when_the_upstream_hangs() {
+        let lock =
+            json!({"jsonrpc": "2.0", "id": 1, "method": "lock", "params": {"passphrase": "x"}});
+        let response = first_response(&[lock]).await;
+        assert_eq!(response["error"]["code"], CALL_FAILED);
+        let message = response["error"]["message"].as_str().unwrap();
+        assert!(message.contains("timed out"), "{}", message);
+    }
+}
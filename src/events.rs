@@ -0,0 +1,400 @@
+//! Live events of a `serve` process for the control socket's `subscribe` method
+//!
+//! Events are broadcast to every subscriber as they happen and are not
+//! stored: a subscriber only sees what happens after it subscribed, and one
+//! that falls too far behind is told how many events it missed. Identity
+//! and upstream events are only sent on a change, compared with the last
+//! identity list shown to clients and the last connect outcome.
+
+use crate::bridge::{ClientInfo, SignContext};
+use crate::proto::{self, Identity, SignedData};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+
+/// 每个订阅者最多积压的事件数
+const BACKLOG: usize = 256;
+
+/// The client an event is about
+#[derive(Clone, Debug, Serialize)]
+pub struct EventClient {
+    pub client: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+}
+
+impl From<&ClientInfo> for EventClient {
+    fn from(client: &ClientInfo) -> Self {
+        Self {
+            client: client.label.clone(),
+            pid: client.pid,
+            process: client.process.clone(),
+        }
+    }
+}
+
+/// 签名请求的客户端、密钥与用途，字段名与审计日志一致
+#[derive(Clone, Debug, Serialize)]
+pub struct SignEvent {
+    #[serde(flatten)]
+    pub client: EventClient,
+    pub key_fingerprint: String,
+    pub key_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_comment: Option<String>,
+    #[serde(flatten)]
+    pub data: SignedData,
+}
+
+impl SignEvent {
+    pub fn new(client: &ClientInfo, sign: &SignContext) -> Self {
+        Self {
+            client: client.into(),
+            key_fingerprint: sign.key.fingerprint.clone(),
+            key_type: sign.key.key_type.clone(),
+            key_comment: sign.key.comment.clone(),
+            data: sign.data.clone(),
+        }
+    }
+}
+
+/// 请求事件中与签名相关的字段，其他请求均为空
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RequestKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_comment: Option<String>,
+    /// 登录的目标用户
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// sshsig 签名的命名空间，例如 `git`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+impl RequestKey {
+    pub fn new(sign: Option<&SignContext>) -> Self {
+        let Some(sign) = sign else {
+            return Self::default();
+        };
+        let (user, namespace) = match &sign.data {
+            SignedData::UserAuth { user, .. } => (Some(user.clone()), None),
+            SignedData::SshSig { namespace, .. } => (None, Some(namespace.clone())),
+            SignedData::Unknown => (None, None),
+        };
+        Self {
+            key_fingerprint: Some(sign.key.fingerprint.clone()),
+            key_comment: sign.key.comment.clone(),
+            user,
+            namespace,
+        }
+    }
+}
+
+/// A key in an identities-changed event
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EventKey {
+    pub fingerprint: String,
+    pub key_type: String,
+    pub comment: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    ClientConnected {
+        #[serde(flatten)]
+        client: EventClient,
+    },
+    ClientDisconnected {
+        #[serde(flatten)]
+        client: EventClient,
+    },
+    SignRequested {
+        #[serde(flatten)]
+        sign: SignEvent,
+    },
+    /// upstream 完成了签名
+    SignApproved {
+        #[serde(flatten)]
+        sign: SignEvent,
+        latency_ms: u128,
+    },
+    /// bridge 按操作权限、密钥策略或确认结果拒绝，或请求无法解析
+    SignDenied {
+        #[serde(flatten)]
+        sign: SignEvent,
+        reason: &'static str,
+    },
+    /// upstream 拒绝、超时或连接中断
+    SignFailed {
+        #[serde(flatten)]
+        sign: SignEvent,
+        latency_ms: u128,
+    },
+    IdentitiesChanged {
+        added: Vec<EventKey>,
+        removed: Vec<EventKey>,
+        total: usize,
+    },
+    UpstreamUp {
+        upstream: String,
+    },
+    UpstreamDown {
+        upstream: String,
+        error: String,
+    },
+    /// 一个请求处理完毕；`result` 为 `success`、`failure` 或 `denied`
+    Request {
+        #[serde(flatten)]
+        client: EventClient,
+        request: &'static str,
+        #[serde(flatten)]
+        key: RequestKey,
+        result: &'static str,
+        latency_ms: u128,
+    },
+}
+
+impl Event {
+    /// 事件名，与序列化后的 `event` 字段相同
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ClientConnected { .. } => "client-connected",
+            Event::ClientDisconnected { .. } => "client-disconnected",
+            Event::SignRequested { .. } => "sign-requested",
+            Event::SignApproved { .. } => "sign-approved",
+            Event::SignDenied { .. } => "sign-denied",
+            Event::SignFailed { .. } => "sign-failed",
+            Event::IdentitiesChanged { .. } => "identities-changed",
+            Event::UpstreamUp { .. } => "upstream-up",
+            Event::UpstreamDown { .. } => "upstream-down",
+            Event::Request { .. } => "request",
+        }
+    }
+
+    pub const NAMES: [&'static str; 10] = [
+        "client-connected",
+        "client-disconnected",
+        "sign-requested",
+        "sign-approved",
+        "sign-denied",
+        "sign-failed",
+        "identities-changed",
+        "upstream-up",
+        "upstream-down",
+        "request",
+    ];
+}
+
+/// An event with the time it happened
+#[derive(Debug, Serialize)]
+pub struct Stamped {
+    pub time: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Broadcasts events to the subscribers of the control socket
+pub struct Events {
+    sender: broadcast::Sender<Arc<Stamped>>,
+    // 最近一次从 upstream 看到的密钥列表，以指纹为键
+    identities: Mutex<Option<BTreeMap<String, EventKey>>>,
+    // 每个 upstream 最近一次连接是否成功
+    upstreams: Mutex<HashMap<String, bool>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(BACKLOG).0,
+            identities: Mutex::new(None),
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Events {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Stamped>> {
+        self.sender.subscribe()
+    }
+
+    /// 没有订阅者时事件直接丢弃
+    pub fn publish(&self, event: Event) {
+        let stamped = Stamped {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+        };
+        let _ = self.sender.send(Arc::new(stamped));
+    }
+
+    pub fn sign_finished(&self, sign: SignEvent, succeeded: bool, latency: Duration) {
+        let latency_ms = latency.as_millis();
+        self.publish(if succeeded {
+            Event::SignApproved { sign, latency_ms }
+        } else {
+            Event::SignFailed { sign, latency_ms }
+        });
+    }
+
+    /// 与上一次的密钥列表比较；第一次看到的列表只作为基准
+    pub fn identities_seen(&self, identities: &[Identity]) {
+        let current: BTreeMap<String, EventKey> = identities
+            .iter()
+            .map(|identity| {
+                let key = EventKey {
+                    fingerprint: proto::fingerprint(&identity.key_blob),
+                    key_type: proto::key_type(&identity.key_blob).unwrap_or_default(),
+                    comment: identity.comment.clone(),
+                };
+                (key.fingerprint.clone(), key)
+            })
+            .collect();
+        let mut known = self.identities.lock().unwrap();
+        let Some(previous) = known.replace(current.clone()) else {
+            return;
+        };
+        let added: Vec<EventKey> = current
+            .iter()
+            .filter(|(fingerprint, _)| !previous.contains_key(*fingerprint))
+            .map(|(_, key)| key.clone())
+            .collect();
+        let removed: Vec<EventKey> = previous
+            .iter()
+            .filter(|(fingerprint, _)| !current.contains_key(*fingerprint))
+            .map(|(_, key)| key.clone())
+            .collect();
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        self.publish(Event::IdentitiesChanged {
+            added,
+            removed,
+            total: current.len(),
+        });
+    }
+
+    /// 记录一次连接 upstream 的结果，状态改变时发出事件
+    pub fn upstream_connected(&self, upstream: &str, error: Option<&anyhow::Error>) {
+        let up = error.is_none();
+        let previous = self
+            .upstreams
+            .lock()
+            .unwrap()
+            .insert(upstream.to_string(), up);
+        if previous == Some(up) {
+            return;
+        }
+        let upstream = upstream.to_string();
+        self.publish(match error {
+            None => Event::UpstreamUp { upstream },
+            Some(e) => Event::UpstreamDown {
+                upstream,
+                error: e.to_string(),
+            },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::identity;
+
+    fn received(subscriber: &mut broadcast::Receiver<Arc<Stamped>>) -> Vec<Event> {
+        std::iter::from_fn(|| subscriber.try_recv().ok())
+            .map(|stamped| stamped.event.clone())
+            .collect()
+    }
+
+    /// 事件中的密钥按指纹排序，这里按注释排序以便比较
+    fn comments(keys: &[EventKey]) -> Vec<&str> {
+        let mut comments: Vec<&str> = keys.iter().map(|key| key.comment.as_str()).collect();
+        comments.sort();
+        comments
+    }
+
+    #[test]
+    fn identity_changes_are_diffed_against_the_last_list() {
+        let events = Events::default();
+        let mut subscriber = events.subscribe();
+        // 第一次只作为基准，相同的列表不产生事件
+        events.identities_seen(&[identity(1, "one"), identity(2, "two")]);
+        events.identities_seen(&[identity(2, "two"), identity(1, "one")]);
+        assert!(received(&mut subscriber).is_empty());
+
+        events.identities_seen(&[identity(2, "two"), identity(3, "three")]);
+        let [
+            Event::IdentitiesChanged {
+                added,
+                removed,
+                total,
+            },
+        ] = &received(&mut subscriber)[..]
+        else {
+            panic!("expected one identities-changed event");
+        };
+        assert_eq!(comments(added), ["three"]);
+        assert_eq!(comments(removed), ["one"]);
+        assert_eq!(*total, 2);
+        assert_eq!(added[0].key_type, "ssh-ed25519");
+        assert_eq!(
+            added[0].fingerprint,
+            proto::fingerprint(&identity(3, "").key_blob)
+        );
+
+        events.identities_seen(&[]);
+        let [
+            Event::IdentitiesChanged {
+                added,
+                removed,
+                total,
+            },
+        ] = &received(&mut subscriber)[..]
+        else {
+            panic!("expected one identities-changed event");
+        };
+        assert!(added.is_empty());
+        assert_eq!(comments(removed), ["three", "two"]);
+        assert_eq!(*total, 0);
+    }
+
+    #[test]
+    fn upstream_events_are_sent_on_state_changes_only() {
+        let events = Events::default();
+        let mut subscriber = events.subscribe();
+        let refused = anyhow::anyhow!("connection refused");
+        events.upstream_connected("unix:/a", None);
+        events.upstream_connected("unix:/a", None);
+        events.upstream_connected("unix:/b", Some(&refused));
+        events.upstream_connected("unix:/a", Some(&refused));
+        events.upstream_connected("unix:/a", Some(&refused));
+        events.upstream_connected("unix:/b", None);
+        let seen: Vec<(&str, String)> = received(&mut subscriber)
+            .iter()
+            .map(|event| match event {
+                Event::UpstreamUp { upstream } => ("up", upstream.clone()),
+                Event::UpstreamDown { upstream, error } => {
+                    assert_eq!(error, "connection refused");
+                    ("down", upstream.clone())
+                }
+                other => panic!("unexpected {}", other.name()),
+            })
+            .collect();
+        let expected = [
+            ("up", "unix:/a"),
+            ("down", "unix:/b"),
+            ("down", "unix:/a"),
+            ("up", "unix:/b"),
+        ];
+        assert_eq!(
+            seen,
+            expected.map(|(state, upstream)| (state, upstream.to_string()))
+        );
+    }
+}
@@ -1,10 +1,40 @@
+mod audit;
+mod bridge;
+mod cache;
+mod codec;
+mod config;
+mod confirm;
+mod control;
+mod events;
+mod metrics;
+mod monitor;
+mod mux;
+mod policy;
+mod pool;
+mod proto;
+mod reload;
+mod retry;
+mod server;
+#[cfg(test)]
+mod testing;
+mod transport;
+mod upstream;
+
 use anyhow::{Result, anyhow};
-use clap::Parser;
-use futures::SinkExt;
+use bridge::{BridgeOptions, ClientInfo, InvalidFramePolicy, RequestTimeouts};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use log::{LevelFilter, Metadata, Record, SetLoggerError, debug};
+use pool::{ConnectionPool, PinMode};
+use reload::{Live, Reloaded};
+use retry::{Backoff, RetryPolicy};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::io::Write;
-use tokio_stream::StreamExt;
-use tokio_util::bytes::Buf;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use transport::UpstreamTransport;
+use upstream::{UpstreamMode, UpstreamOptions, WaitMode};
 
 const OPENSSH_PIPE_NAME: &str = r"\\.\pipe\openssh-ssh-agent";
 
@@ -12,185 +42,238 @@ const OPENSSH_PIPE_NAME: &str = r"\\.\pipe\openssh-ssh-agent";
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file (default: ~/.config/wsl2-ssh-agent/config.toml)
+    #[arg(long, global = true, env = "WSL2_SSH_AGENT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Profile from the configuration file to apply on top of its top-level settings
+    #[arg(long, global = true, env = "WSL2_SSH_AGENT_PROFILE")]
+    profile: Option<String>,
+
     /// Named pipe name
-    #[arg(short, long, default_value = OPENSSH_PIPE_NAME)]
+    #[arg(short, long, global = true, default_value = OPENSSH_PIPE_NAME)]
     pipe: String,
 
+    /// Upstream agent address (`unix:/path/to/agent.sock` or `pipe:NAME`), overrides --pipe; repeat to merge several agents
+    #[arg(short, long, global = true)]
+    upstream: Vec<String>,
+
+    /// How to combine several upstreams
+    #[arg(long, global = true, value_enum, default_value = "merge")]
+    upstream_mode: UpstreamMode,
+
+    /// Keep accepting clients while no upstream agent is available instead of failing
+    #[arg(long, global = true)]
+    wait: bool,
+
+    /// How requests are handled while --wait is waiting for an upstream
+    #[arg(long, global = true, value_enum, default_value = "queue")]
+    wait_mode: WaitMode,
+
     /// Enable verbose logging
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 
     /// Connection retry count
-    #[arg(short, long, default_value = "30")]
+    #[arg(short, long, global = true, default_value = "30")]
     retries: u32,
 
-    /// Retry delay (milliseconds)
-    #[arg(long, default_value = "100")]
+    /// Retry delay (milliseconds), the base delay for linear and exponential backoff
+    #[arg(long, global = true, default_value = "100")]
     retry_delay: u64,
-}
 
-const HEADER_SIZE: usize = std::mem::size_of::<u32>(); // SSH agent protocol uses a 4-byte length header
-
-struct SshAgentMessage {
-    length: u32,
-    payload: Vec<u8>,
-}
-impl SshAgentMessage {
-    fn new(length: u32, payload: Vec<u8>) -> Self {
-        Self { length, payload }
-    }
-}
-
-struct SshAgentCodec;
-// impl tokio encoder decoder for SshAgentMessage
-impl tokio_util::codec::Decoder for SshAgentCodec {
-    type Item = SshAgentMessage;
-    type Error = std::io::Error;
-
-    fn decode(
-        &mut self,
-        src: &mut tokio_util::bytes::BytesMut,
-    ) -> Result<Option<Self::Item>, Self::Error> {
-        debug!("Decoding message, buffer length: {}", src.len());
-        if src.len() < HEADER_SIZE {
-            return Ok(None);
-        }
-        let length = u32::from_be_bytes(src[..HEADER_SIZE].try_into().map_err(|_| {
-            Self::Error::new(std::io::ErrorKind::InvalidData, "Failed to read length")
-        })?);
-        if src.len() < HEADER_SIZE + length as usize {
-            return Ok(None);
-        }
-        src.advance(HEADER_SIZE);
-        debug!("Decoding message, Message length: {}", length);
-        let payload = src.split_to(length as usize).to_vec();
-        Ok(Some(SshAgentMessage::new(length, payload)))
-    }
-}
-
-impl tokio_util::codec::Encoder<SshAgentMessage> for SshAgentCodec {
-    type Error = std::io::Error;
-    fn encode(
-        &mut self,
-        item: SshAgentMessage,
-        dst: &mut tokio_util::bytes::BytesMut,
-    ) -> Result<(), Self::Error> {
-        dst.extend_from_slice(&item.length.to_be_bytes());
-        dst.extend_from_slice(&item.payload);
-        debug!("Encoded message of length: {}", item.length);
-        Ok(())
-    }
-}
-
-// 辅助函数：转发流数据
-async fn forward_stream<R, W>(
-    reader: &mut tokio_util::codec::FramedRead<R, SshAgentCodec>,
-    writer: &mut tokio_util::codec::FramedWrite<W, SshAgentCodec>,
-    direction: &str,
-) -> Result<bool>
-where
-    R: tokio::io::AsyncRead + Unpin,
-    W: tokio::io::AsyncWrite + Unpin,
-{
-    if let Some(msg) = reader.next().await {
-        let msg = msg?;
-        debug!("Forwarding {} message of length: {}", direction, msg.length);
-        writer.send(msg).await?;
-        writer.flush().await?;
-        debug!("Flushed {}", direction);
-        Ok(true)
-    } else {
-        debug!("{} stream closed", direction);
-        Ok(false)
-    }
-}
+    /// How the retry delay grows between attempts
+    #[arg(long, global = true, value_enum, default_value = "constant")]
+    retry_backoff: Backoff,
 
-/// 使用正确的 SSH 协议消息帧处理
-async fn handle_ssh_protocol_framing() -> Result<()> {
-    let cli = Cli::parse();
+    /// Upper bound for a single retry delay (milliseconds)
+    #[arg(long, global = true, default_value = "5000")]
+    retry_max_delay: u64,
 
-    debug!(
-        "Starting SSH agent bridge with proper framing to: {}",
-        cli.pipe
-    );
+    /// Randomize each retry delay by up to half so clients do not retry in lockstep
+    #[arg(long, global = true)]
+    retry_jitter: bool,
 
-    // 连接命名管道
-    let pipe = tokio::task::spawn_blocking(move || {
-        connect_to_named_pipe(&cli.pipe, cli.retries, cli.retry_delay)
-    })
-    .await??;
+    /// Give up once this much time (milliseconds) has been spent connecting
+    #[arg(long, global = true)]
+    retry_max_elapsed: Option<u64>,
 
-    debug!("Connected to named pipe successfully");
+    /// Maximum SSH agent message size (bytes)
+    #[arg(long, global = true, default_value_t = codec::DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: u32,
 
-    // 转换为异步文件
-    let pipe_file = tokio::fs::File::from_std(pipe);
-    let (pipe_read, pipe_write) = tokio::io::split(pipe_file);
-    let mut stdin_reader = tokio_util::codec::FramedRead::new(tokio::io::stdin(), SshAgentCodec);
-    let mut stdout_writer = tokio_util::codec::FramedWrite::new(tokio::io::stdout(), SshAgentCodec);
-    let mut pipe_reader = tokio_util::codec::FramedRead::new(pipe_read, SshAgentCodec);
-    let mut pipe_writer = tokio_util::codec::FramedWrite::new(pipe_write, SshAgentCodec);
+    /// What to do when a client sends an empty or oversized message
+    #[arg(long, global = true, value_enum, default_value = "failure")]
+    on_invalid_frame: InvalidFramePolicy,
 
-    loop {
-        if !forward_stream(&mut stdin_reader, &mut pipe_writer, "[stdin -> pipe]").await? {
-            break;
-        }
-        if !forward_stream(&mut pipe_reader, &mut stdout_writer, "[pipe -> stdout]").await? {
-            break;
-        }
-    }
+    /// Key allow/deny policy file (TOML)
+    #[arg(long, global = true)]
+    policy: Option<PathBuf>,
 
-    debug!("SSH agent bridge terminated");
-    Ok(())
+    /// Agent operations WSL clients may perform (read-only by default)
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_delimiter = ',',
+        default_values = ["list", "sign", "session-bind"]
+    )]
+    allow_operations: Vec<policy::Operation>,
+
+    /// Append a JSON Lines record for every sign request to this file
+    #[arg(long, global = true)]
+    audit_log: Option<PathBuf>,
+
+    /// Program asked to approve sign requests for keys listed under [[confirm]] in the policy
+    #[arg(long, global = true, env = "SSH_ASKPASS")]
+    confirm_command: Option<String>,
+
+    /// Seconds to wait for the confirm program before denying
+    #[arg(long, global = true, default_value = "30")]
+    confirm_timeout: u64,
+
+    /// Seconds to wait for the upstream to list identities (0 waits forever)
+    #[arg(long, global = true, default_value = "10")]
+    list_timeout: u64,
+
+    /// Seconds to wait for the upstream to sign, including any unlock prompt (0 waits forever)
+    #[arg(long, global = true, default_value = "120")]
+    sign_timeout: u64,
+
+    /// Seconds to wait for the upstream to answer any other request (0 waits forever)
+    #[arg(long, global = true, default_value = "30")]
+    request_timeout: u64,
+
+    /// Windows helper started once to carry every `helper:` upstream
+    #[arg(long, global = true, default_value = "wsl2-ssh-agent.exe")]
+    helper: String,
+
+    /// Seconds between keepalive pings to the helper
+    #[arg(long, global = true, default_value = "15")]
+    helper_keepalive: u64,
+
+    /// Seconds to reuse the upstream identity list before asking again (0 disables the cache)
+    #[arg(long, global = true, default_value = "0")]
+    identity_cache_ttl: u64,
 }
 
-/// 连接到 Windows 命名管道
-fn connect_to_named_pipe(
-    pipe_name: &str,
-    max_retries: u32,
-    retry_delay_ms: u64,
-) -> Result<std::fs::File> {
-    use std::os::windows::fs::OpenOptionsExt;
-    use std::time::Duration;
-    const FILE_FLAG_OVERLAPPED: u32 = 0x40000000; // from winapi::um::winbase::FILE_FLAG_OVERLAPPED
-    // 尝试多次连接
-    for attempt in 0..=max_retries {
-        match std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            // 使用 OVERLAPPED I/O 以便与异步代码兼容
-            .custom_flags(FILE_FLAG_OVERLAPPED)
-            .open(pipe_name)
-        {
-            Ok(pipe) => {
-                debug!(
-                    "Successfully connected to named pipe on attempt {}",
-                    attempt + 1
-                );
-                return Ok(pipe);
-            }
-            Err(e) => {
-                if attempt == max_retries {
-                    return Err(anyhow!(
-                        "Failed to connect to named pipe '{}' after {} attempts: {}",
-                        pipe_name,
-                        max_retries + 1,
-                        e
-                    ));
-                }
+#[derive(Subcommand)]
+enum Command {
+    /// Listen on a local socket and forward every client to the upstream agent
+    Serve {
+        /// Listen address (`unix:/path/to/agent.sock`)
+        #[arg(short, long)]
+        listen: String,
+        /// Share at most this many connections per upstream between all clients (0 gives every client its own)
+        #[arg(long, default_value = "0")]
+        pool_size: usize,
+        /// Seconds an idle pooled connection is kept before it is closed (0 keeps it forever)
+        #[arg(long, default_value = "300")]
+        pool_idle_timeout: u64,
+        /// When a client keeps its pooled connection for the rest of its session
+        #[arg(long, value_enum, default_value = "stateful")]
+        pool_pin: PinMode,
+        /// Serve Prometheus metrics on `127.0.0.1:PORT` or `unix:/path/to/metrics.sock`
+        #[arg(long)]
+        metrics_listen: Option<String>,
+        /// Accept JSON-RPC admin requests on `unix:/path/to/control.sock`
+        #[arg(long)]
+        control_listen: Option<String>,
+    },
+    /// Carry multiplexed upstream connections over stdin/stdout for a `serve` process
+    Helper,
+    /// Inspect the configuration file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Manage a running `serve` process through its control socket
+    Ctl {
+        /// Control socket address (default: `control-listen` from the [serve] config)
+        #[arg(long, env = "WSL2_SSH_AGENT_CONTROL")]
+        control: Option<String>,
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+    /// Watch clients, upstreams and requests of a running `serve` process in a terminal UI
+    Monitor {
+        /// Control socket address (default: `control-listen` from the [serve] config)
+        #[arg(long, env = "WSL2_SSH_AGENT_CONTROL")]
+        control: Option<String>,
+    },
+}
 
-                debug!(
-                    "Connection attempt {} failed: {}, retrying in {}ms",
-                    attempt + 1,
-                    e,
-                    retry_delay_ms
-                );
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Validate the configuration and print the effective settings
+    Check,
+}
 
-                std::thread::sleep(Duration::from_millis(retry_delay_ms));
-            }
-        }
-    }
+#[derive(Subcommand)]
+enum CtlAction {
+    /// Show the version, uptime and settings of the running bridge
+    Status,
+    /// List connected clients
+    ListClients,
+    /// Close a client connection
+    DisconnectClient {
+        /// Client id as shown by list-clients
+        id: u64,
+    },
+    /// Check whether each upstream is reachable and show its connection pool
+    ListUpstreams,
+    /// Reload the configuration and policy files now
+    Reload,
+    /// Forget the cached identity list
+    FlushCache,
+    /// Lock the upstream agents with a passphrase
+    Lock {
+        /// Read the passphrase from the first line of stdin instead of the terminal
+        #[arg(long)]
+        passphrase_stdin: bool,
+    },
+    /// Unlock the upstream agents
+    Unlock {
+        /// Read the passphrase from the first line of stdin instead of the terminal
+        #[arg(long)]
+        passphrase_stdin: bool,
+    },
+    /// Show request, sign and connection counters
+    Stats,
+    /// Print bridge events as newline-delimited JSON as they happen
+    Watch {
+        /// Only print these events, e.g. `sign-approved,sign-denied` (default: all)
+        #[arg(long, value_delimiter = ',')]
+        events: Vec<String>,
+    },
+}
+
+/// 使用正确的 SSH 协议消息帧处理
+async fn handle_ssh_protocol_framing(
+    upstream: &UpstreamOptions,
+    options: BridgeOptions,
+) -> Result<()> {
+    debug!("Starting SSH agent bridge with proper framing");
+
+    bridge::bridge_connection(
+        tokio::io::stdin(),
+        tokio::io::stdout(),
+        upstream,
+        &ClientInfo::new("stdio"),
+        &Live::new(options),
+    )
+    .await?;
 
-    Err(anyhow!("Unexpected error connecting to named pipe"))
+    debug!("SSH agent bridge terminated");
+    Ok(())
+}
+
+/// 0 表示不设超时
+fn seconds(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value))
 }
 
 struct SimpleLogger;
@@ -211,28 +294,550 @@ impl log::Log for SimpleLogger {
 pub fn log_init() -> Result<(), SetLoggerError> {
     log::set_logger(&SimpleLogger).map(|()| log::set_max_level(LevelFilter::Debug))
 }
+type Transports = Vec<Arc<dyn UpstreamTransport>>;
+
+/// 解析 upstream 地址，`helper:` 前缀的 upstream 共用一个 helper 进程
+fn upstream_transports(cli: &Cli) -> Result<(Vec<String>, Transports)> {
+    let specs = if cli.upstream.is_empty() {
+        vec![cli.pipe.clone()]
+    } else {
+        cli.upstream.clone()
+    };
+    let helper = Arc::new(mux::Helper::new(
+        cli.helper.clone(),
+        Duration::from_secs(cli.helper_keepalive.max(1)),
+    ));
+    let upstreams = specs
+        .iter()
+        .map(|spec| match spec.strip_prefix("helper:") {
+            Some(upstream) => {
+                let transport = mux::HelperTransport::new(helper.clone(), upstream);
+                Ok(Arc::new(transport) as Arc<dyn UpstreamTransport>)
+            }
+            None => transport::parse_upstream(spec),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((specs, upstreams))
+}
+
+type PolicyOptions = (Option<Arc<policy::Policy>>, Option<Arc<confirm::Confirmer>>);
+
+/// 加载密钥策略；策略要求确认时必须配置确认程序
+fn load_policy(cli: &Cli) -> Result<PolicyOptions> {
+    let policy = match &cli.policy {
+        Some(path) => {
+            debug!("Loading key policy from {}", path.display());
+            Some(Arc::new(policy::Policy::load(path)?))
+        }
+        None => None,
+    };
+    let confirm = match &cli.confirm_command {
+        Some(command) => Some(Arc::new(confirm::Confirmer::new(
+            command,
+            Duration::from_secs(cli.confirm_timeout),
+        ))),
+        None if policy.as_ref().is_some_and(|p| p.has_confirm_rules()) => {
+            return Err(anyhow!(
+                "Policy requires confirmation but no --confirm-command or SSH_ASKPASS is set"
+            ));
+        }
+        None => None,
+    };
+    Ok((policy, confirm))
+}
+
+/// 构建转发设置；重新加载时沿用 `previous` 中需要重启才能改变的部分
+fn bridge_options(cli: &Cli, previous: Option<&BridgeOptions>) -> Result<BridgeOptions> {
+    let (policy, confirm) = load_policy(cli)?;
+    let audit = match &cli.audit_log {
+        // 路径不变时继续使用已打开的审计日志
+        Some(path) => match previous
+            .and_then(|previous| previous.audit.clone())
+            .filter(|audit| audit.path() == path)
+        {
+            Some(audit) => Some(audit),
+            None => {
+                debug!("Writing sign audit records to {}", path.display());
+                Some(Arc::new(audit::AuditLog::open(path)?))
+            }
+        },
+        None => None,
+    };
+    Ok(BridgeOptions {
+        max_message_size: previous.map_or(cli.max_message_size, |p| p.max_message_size),
+        invalid_frame: cli.on_invalid_frame,
+        policy,
+        allowed_operations: cli.allow_operations.clone(),
+        audit,
+        confirm,
+        timeouts: RequestTimeouts {
+            list: seconds(cli.list_timeout),
+            sign: seconds(cli.sign_timeout),
+            other: seconds(cli.request_timeout),
+        },
+        cache: match previous {
+            Some(previous) => previous.cache.clone(),
+            None => {
+                seconds(cli.identity_cache_ttl).map(|ttl| Arc::new(cache::IdentityCache::new(ttl)))
+            }
+        },
+        metrics: previous.and_then(|previous| previous.metrics.clone()),
+        events: previous.and_then(|previous| previous.events.clone()),
+    })
+}
+
+/// 重新加载即可生效的设置，其余设置需要重启 serve
+const RELOADABLE: [&str; 9] = [
+    "on-invalid-frame",
+    "policy",
+    "allow-operations",
+    "audit-log",
+    "confirm-command",
+    "confirm-timeout",
+    "list-timeout",
+    "sign-timeout",
+    "request-timeout",
+];
+
+type Effective = BTreeMap<String, Option<String>>;
+
+/// serve 重新加载配置与策略时的状态
+struct Reloader {
+    config: Option<PathBuf>,
+    profile: Option<String>,
+    /// 启动时的命令行，重新加载时按新配置重新解析
+    args: Vec<OsString>,
+    /// 启动时的设置，用于提示哪些修改需要重启
+    startup: Effective,
+    current: Effective,
+    rules: Vec<String>,
+}
+
+impl Reloader {
+    fn new(
+        config: Option<PathBuf>,
+        profile: Option<String>,
+        settings: &config::Settings,
+        matches: &ArgMatches,
+        options: &BridgeOptions,
+    ) -> Self {
+        let effective = Self::effective(settings, matches);
+        Self {
+            config,
+            profile,
+            args: std::env::args_os().collect(),
+            startup: effective.clone(),
+            current: effective,
+            rules: options.policy.as_ref().map_or(Vec::new(), |p| p.rules()),
+        }
+    }
+
+    fn effective(settings: &config::Settings, matches: &ArgMatches) -> Effective {
+        settings
+            .entries(&Cli::command(), matches)
+            .into_iter()
+            .map(|entry| (entry.key, entry.value))
+            .collect()
+    }
+
+    /// 需要监视的配置文件与策略文件
+    fn watched(&self, settings: &config::Settings, cli: &Cli) -> Vec<PathBuf> {
+        settings
+            .path
+            .clone()
+            .or_else(|| self.config.clone())
+            .or_else(config::default_path)
+            .into_iter()
+            .chain(cli.policy.clone())
+            .collect()
+    }
+
+    /// 重新读取配置与策略，全部校验通过后才返回新的设置
+    fn reload(&mut self, running: &BridgeOptions) -> Result<Reloaded<BridgeOptions>> {
+        let settings = config::Settings::load(
+            self.config.as_deref(),
+            self.profile.as_deref(),
+            &Cli::command(),
+        )?;
+        let matches = settings
+            .apply(Cli::command())
+            .try_get_matches_from(&self.args)
+            .map_err(|e| {
+                // clap 的错误信息带有 "error: " 前缀和用法提示，只保留第一行
+                let message = e.to_string();
+                let first = message.lines().next().unwrap_or_default();
+                anyhow!("Invalid settings: {}", first.trim_start_matches("error: "))
+            })?;
+        let cli = Cli::from_arg_matches(&matches)?;
+        let options = bridge_options(&cli, Some(running))?;
+
+        let effective = Self::effective(&settings, &matches);
+        let mut changes = Vec::new();
+        for (key, value) in &effective {
+            let reloadable = RELOADABLE.contains(&key.as_str());
+            let previous = if reloadable {
+                self.current.get(key)
+            } else {
+                self.startup.get(key)
+            };
+            let Some(previous) = previous.filter(|previous| *previous != value) else {
+                continue;
+            };
+            let show = |value: &Option<String>| value.clone().unwrap_or("(unset)".to_string());
+            let note = if reloadable {
+                ""
+            } else {
+                " (restart serve to apply)"
+            };
+            changes.push(format!(
+                "{}: {} -> {}{}",
+                key,
+                show(previous),
+                show(value),
+                note
+            ));
+        }
+        let rules = options.policy.as_ref().map_or(Vec::new(), |p| p.rules());
+        for rule in self.rules.iter().filter(|rule| !rules.contains(rule)) {
+            changes.push(format!("policy: - {}", rule));
+        }
+        for rule in rules.iter().filter(|rule| !self.rules.contains(rule)) {
+            changes.push(format!("policy: + {}", rule));
+        }
+
+        let watched = self.watched(&settings, &cli);
+        self.current = effective;
+        self.rules = rules;
+        Ok(Reloaded {
+            value: options,
+            changes,
+            watched,
+        })
+    }
+}
+
+/// 从终端或标准输入读取口令；在终端上锁定时要求输入两次
+fn read_passphrase(from_stdin: bool, confirm: bool) -> Result<String> {
+    if from_stdin {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let passphrase = rpassword::prompt_password("Passphrase: ")?;
+    if confirm && rpassword::prompt_password("Again: ")? != passphrase {
+        return Err(anyhow!("Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+/// 控制 socket 的地址：`--control` 优先，其次是配置中的 `control-listen`
+fn control_socket<'a>(
+    control: &'a Option<String>,
+    settings: &'a config::Settings,
+) -> Result<&'a str> {
+    control
+        .as_deref()
+        .or_else(|| settings.serve_value("control-listen"))
+        .ok_or_else(|| {
+            anyhow!(
+                "No control socket given, use --control or set control-listen in the [serve] config"
+            )
+        })
+}
+
+/// 调用运行中的 serve 的控制接口，并输出 JSON 结果；`watch` 持续输出事件
+async fn run_ctl(
+    control: &Option<String>,
+    action: &CtlAction,
+    settings: &config::Settings,
+) -> Result<()> {
+    let spec = control_socket(control, settings)?;
+    let (method, params) = match action {
+        CtlAction::Status => ("status", serde_json::Value::Null),
+        CtlAction::ListClients => ("list-clients", serde_json::Value::Null),
+        CtlAction::DisconnectClient { id } => ("disconnect-client", serde_json::json!({"id": id})),
+        CtlAction::ListUpstreams => ("list-upstreams", serde_json::Value::Null),
+        CtlAction::Reload => ("reload", serde_json::Value::Null),
+        CtlAction::FlushCache => ("flush-cache", serde_json::Value::Null),
+        CtlAction::Lock { passphrase_stdin } => {
+            let passphrase = read_passphrase(*passphrase_stdin, true)?;
+            ("lock", serde_json::json!({"passphrase": passphrase}))
+        }
+        CtlAction::Unlock { passphrase_stdin } => {
+            let passphrase = read_passphrase(*passphrase_stdin, false)?;
+            ("unlock", serde_json::json!({"passphrase": passphrase}))
+        }
+        CtlAction::Stats => ("stats", serde_json::Value::Null),
+        CtlAction::Watch { events } => {
+            let mut subscription = control::subscribe(spec, events).await?;
+            while let Some(event) = subscription.next().await? {
+                // 每个事件单独一行并立即刷新，便于管道中的程序逐行处理
+                let mut stdout = std::io::stdout().lock();
+                writeln!(stdout, "{}", event)?;
+                stdout.flush()?;
+            }
+            return Err(anyhow!("The bridge closed the event stream"));
+        }
+    };
+    let result = control::call(spec, method, params).await?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+/// 校验生效的配置，并以 TOML 输出每项设置及其来源
+fn check_config(
+    cli: &Cli,
+    settings: &config::Settings,
+    command: &clap::Command,
+    matches: &ArgMatches,
+) -> Result<()> {
+    upstream_transports(cli)?;
+    load_policy(cli)?;
+    if let Some(listen) = settings.serve_value("listen") {
+        server::parse_listen(listen)?;
+        // 让 clap 按 serve 的参数类型检查配置的值
+        command
+            .clone()
+            .try_get_matches_from(["wsl2-ssh-agent", "serve"])
+            .map_err(|e| anyhow!("Invalid serve settings: {}", e.kind()))?;
+    }
+    if let Some(spec) = settings.serve_value("metrics-listen") {
+        metrics::parse_metrics_listen(spec)?;
+    }
+    if let Some(spec) = settings.serve_value("control-listen") {
+        server::parse_listen(spec)?;
+    }
+
+    match (&settings.path, &cli.config) {
+        (Some(path), Some(_)) => println!("# config file: {}", path.display()),
+        (Some(path), None) => println!("# config file: {} (default location)", path.display()),
+        (None, _) => println!("# no config file found"),
+    }
+    if let Some(profile) = &cli.profile {
+        println!("# profile: {}", profile);
+    }
+    print!("{}", settings.render(&Cli::command(), matches));
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    // 先找出配置文件与 profile，再把配置作为默认值重新解析命令行
+    let early = Cli::command().ignore_errors(true).get_matches();
+    let config_path = early.get_one::<PathBuf>("config").cloned();
+    let profile = early.get_one::<String>("profile").cloned();
+    let settings =
+        config::Settings::load(config_path.as_deref(), profile.as_deref(), &Cli::command())?;
+    let command = settings.apply(Cli::command());
+    let matches = command.clone().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     if cli.verbose {
         log_init()?;
     }
+    match &cli.command {
+        Some(Command::Helper) => return mux::run_helper().await,
+        Some(Command::Config {
+            action: ConfigAction::Check,
+        }) => return check_config(&cli, &settings, &command, &matches),
+        Some(Command::Ctl { control, action }) => {
+            return run_ctl(control, action, &settings).await;
+        }
+        Some(Command::Monitor { control }) => {
+            return monitor::run(control_socket(control, &settings)?).await;
+        }
+        _ => {}
+    }
+    let (specs, upstreams) = upstream_transports(&cli)?;
     debug!("Windows SSH Agent Bridge starting...");
-    debug!("Target pipe: {}", cli.pipe);
-    // check if the named pipe exists
-    if let Err(_) = tokio::fs::metadata(&cli.pipe).await {
-        debug!(
-            "Warning: Named pipe '{}' does not exist or is not accessible",
-            cli.pipe
-        );
+    // check if the upstream endpoints exist
+    let mut available = 0;
+    for upstream in &upstreams {
+        debug!("Target upstream: {}", upstream);
+        // 经由 helper 的 upstream 无法在本地检查
+        let Some(address) = upstream.address() else {
+            available += 1;
+            continue;
+        };
+        if tokio::fs::metadata(address).await.is_ok() {
+            available += 1;
+        } else {
+            debug!(
+                "Warning: Upstream '{}' does not exist or is not accessible",
+                upstream
+            );
+        }
+    }
+    if available == 0 {
         debug!("Make sure SSH Agent is running on Windows");
         debug!("You can start it with: net start ssh-agent");
-        return Err(anyhow!(
-            "Named pipe '{}' does not exist or is not accessible",
-            cli.pipe
-        ));
+        if cli.wait {
+            debug!("Waiting for an upstream agent to appear");
+        } else {
+            return Err(anyhow!(
+                "No upstream agent ({}) exists or is accessible",
+                specs.join(", ")
+            ));
+        }
+    }
+
+    let options = bridge_options(&cli, None)?;
+    let upstream = UpstreamOptions {
+        transports: upstreams.into(),
+        mode: cli.upstream_mode,
+        retry: RetryPolicy {
+            max_retries: cli.retries,
+            backoff: cli.retry_backoff,
+            delay: Duration::from_millis(cli.retry_delay),
+            max_delay: Duration::from_millis(cli.retry_max_delay),
+            jitter: cli.retry_jitter,
+            max_elapsed: cli.retry_max_elapsed.map(Duration::from_millis),
+        },
+        max_message_size: cli.max_message_size,
+        wait: cli.wait.then_some(cli.wait_mode),
+        pools: None,
+        pin: PinMode::Never,
+        metrics: None,
+        events: None,
+    };
+    match &cli.command {
+        Some(Command::Serve {
+            listen,
+            pool_size,
+            pool_idle_timeout,
+            pool_pin,
+            metrics_listen,
+            control_listen,
+        }) => {
+            let mut upstream = upstream;
+            let mut options = options;
+            if *pool_size > 0 {
+                let pools: Vec<_> = upstream
+                    .transports
+                    .iter()
+                    .map(|transport| {
+                        Arc::new(ConnectionPool::new(
+                            transport.clone(),
+                            *pool_size,
+                            seconds(*pool_idle_timeout),
+                        ))
+                    })
+                    .collect();
+                upstream.pools = Some(pools.into());
+                upstream.pin = *pool_pin;
+            }
+            // 控制接口的 stats 也使用这些计数
+            if metrics_listen.is_some() || control_listen.is_some() {
+                let metrics = Arc::new(metrics::Metrics::default());
+                if let Some(metrics_listen) = metrics_listen {
+                    metrics::spawn(metrics_listen, metrics.clone(), upstream.pools.clone()).await?;
+                }
+                upstream.metrics = Some(metrics.clone());
+                options.metrics = Some(metrics);
+            }
+            if control_listen.is_some() {
+                let events = Arc::new(events::Events::default());
+                upstream.events = Some(events.clone());
+                options.events = Some(events);
+            }
+            let mut reloader = Reloader::new(config_path, profile, &settings, &matches, &options);
+            let watched = reloader.watched(&settings, &cli);
+            let metrics = options.metrics.clone();
+            let events = options.events.clone();
+            let live = Arc::new(Live::new(options));
+            let reload = reload::spawn(live.clone(), watched, move |running| {
+                reloader.reload(running)
+            });
+            let clients = Arc::new(server::Clients::default());
+            if let (Some(spec), Some(metrics), Some(events)) = (control_listen, metrics, events) {
+                let control = control::Control {
+                    listen: listen.clone(),
+                    started: std::time::SystemTime::now(),
+                    clients: clients.clone(),
+                    upstream: upstream.clone(),
+                    options: live.clone(),
+                    reload,
+                    metrics,
+                    events,
+                };
+                control::spawn(spec, Arc::new(control)).await?;
+            }
+            server::serve(listen, upstream, live, clients).await
+        }
+        Some(
+            Command::Helper
+            | Command::Config { .. }
+            | Command::Ctl { .. }
+            | Command::Monitor { .. },
+        ) => {
+            unreachable!("handled before upstream setup")
+        }
+        None => handle_ssh_protocol_framing(&upstream, options).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str =
+        "sign-timeout = 60\nmax-message-size = 65536\n[serve]\nlisten = \"unix:/tmp/x.sock\"\n";
+
+    fn reloader(path: &std::path::Path) -> (Reloader, BridgeOptions) {
+        let args = ["wsl2-ssh-agent", "serve"];
+        let settings = config::Settings::load(Some(path), None, &Cli::command()).unwrap();
+        let matches = settings
+            .apply(Cli::command())
+            .try_get_matches_from(args)
+            .unwrap();
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let options = bridge_options(&cli, None).unwrap();
+        let mut reloader = Reloader::new(
+            Some(path.to_path_buf()),
+            None,
+            &settings,
+            &matches,
+            &options,
+        );
+        reloader.args = args.map(OsString::from).to_vec();
+        (reloader, options)
     }
 
-    handle_ssh_protocol_framing().await
+    #[test]
+    fn reload_applies_reloadable_settings_and_reports_the_rest() {
+        let path = std::env::temp_dir().join(format!("reload-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let (mut reloader, running) = reloader(&path);
+
+        let changed =
+            "retries = 5\n".to_string() + &CONFIG.replace("60", "90").replace("65536", "1024");
+        std::fs::write(&path, &changed).unwrap();
+        let reloaded = reloader.reload(&running).unwrap();
+        assert_eq!(
+            reloaded.changes,
+            [
+                "max-message-size: 65536 -> 1024 (restart serve to apply)",
+                "retries: 30 -> 5 (restart serve to apply)",
+                "sign-timeout: 60 -> 90",
+            ]
+        );
+        assert_eq!(reloaded.value.timeouts.sign, Some(Duration::from_secs(90)));
+        assert_eq!(reloaded.value.max_message_size, 65536);
+        assert_eq!(reloaded.watched, std::slice::from_ref(&path));
+
+        // 无效的配置被拒绝，之后的变更仍与最后一次生效的设置比较
+        std::fs::write(&path, "retrys = 5\n").unwrap();
+        assert!(reloader.reload(&reloaded.value).is_err());
+        std::fs::write(&path, changed.replace("90", "120")).unwrap();
+        let reloaded = reloader.reload(&reloaded.value).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            reloaded.changes,
+            [
+                "max-message-size: 65536 -> 1024 (restart serve to apply)",
+                "retries: 30 -> 5 (restart serve to apply)",
+                "sign-timeout: 90 -> 120",
+            ]
+        );
+    }
 }
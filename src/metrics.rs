@@ -0,0 +1,716 @@
+//! Prometheus metrics served on a local HTTP `/metrics` endpoint
+//!
+//! The endpoint only listens on a loopback address or a Unix socket. The
+//! exposition is written by hand in the Prometheus text format; the HTTP side
+//! understands just enough of HTTP/1.x to answer `GET /metrics`.
+
+use crate::audit::SignResult;
+use crate::pool::{ConnectionPool, PoolStats};
+use crate::proto;
+use anyhow::{Result, anyhow};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// 请求耗时直方图的上界（秒），签名可能包含较长的解锁提示
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+/// 读取 HTTP 请求头的上限
+const MAX_REQUEST_HEAD: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// 指标名、说明与取值函数
+type Counter<T> = (&'static str, &'static str, fn(&T) -> f64);
+
+#[derive(Default)]
+struct ConnectCounts {
+    attempts: u64,
+    retries: u64,
+    failures: u64,
+}
+
+/// 带标签的指标
+#[derive(Default)]
+struct Series {
+    requests: BTreeMap<&'static str, u64>,
+    signs: BTreeMap<(String, &'static str), u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+    connects: BTreeMap<String, ConnectCounts>,
+}
+
+/// Counters shared by every client connection
+#[derive(Default)]
+pub struct Metrics {
+    series: Mutex<Series>,
+    bytes_to_upstream: AtomicU64,
+    bytes_from_upstream: AtomicU64,
+    active_clients: AtomicU64,
+    clients: AtomicU64,
+}
+
+/// Decrements the active client gauge when the connection ends
+pub struct ClientGuard<'a>(&'a Metrics);
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn sign_result_label(result: SignResult) -> &'static str {
+    match result {
+        SignResult::Success => "success",
+        SignResult::Failure => "failure",
+        SignResult::Denied => "denied",
+    }
+}
+
+impl Metrics {
+    pub fn client_connected(&self) -> ClientGuard<'_> {
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        self.active_clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self)
+    }
+
+    /// 客户端发来的请求，包括被拒绝或本地应答的请求
+    pub fn record_request(&self, msg_type: u8) {
+        let name = proto::message_name(msg_type);
+        *self
+            .series
+            .lock()
+            .unwrap()
+            .requests
+            .entry(name)
+            .or_default() += 1;
+    }
+
+    /// 一次 upstream 往返（含重连与故障转移）的耗时
+    pub fn record_latency(&self, msg_type: u8, elapsed: Duration) {
+        let name = proto::message_name(msg_type);
+        self.series
+            .lock()
+            .unwrap()
+            .latency
+            .entry(name)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_sign(&self, fingerprint: &str, result: SignResult) {
+        let key = (fingerprint.to_string(), sign_result_label(result));
+        *self.series.lock().unwrap().signs.entry(key).or_default() += 1;
+    }
+
+    /// 与 upstream 之间传输的消息字节数（含长度头）
+    pub fn record_forwarded(&self, to_upstream: bool, bytes: usize) {
+        let counter = if to_upstream {
+            &self.bytes_to_upstream
+        } else {
+            &self.bytes_from_upstream
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// 一次连接尝试；`retrying` 表示失败后将按重试策略再试
+    pub fn record_connect(&self, upstream: &str, succeeded: bool, retrying: bool) {
+        let mut series = self.series.lock().unwrap();
+        let counts = series.connects.entry(upstream.to_string()).or_default();
+        counts.attempts += 1;
+        if retrying {
+            counts.retries += 1;
+        } else if !succeeded {
+            counts.failures += 1;
+        }
+    }
+
+    /// 以 JSON 输出计数，供控制接口的 `stats` 调用使用
+    pub fn snapshot(&self) -> serde_json::Value {
+        let series = self.series.lock().unwrap();
+        let signs: Vec<_> = series
+            .signs
+            .iter()
+            .map(|((fingerprint, result), count)| {
+                serde_json::json!({
+                    "fingerprint": fingerprint,
+                    "result": result,
+                    "count": count,
+                })
+            })
+            .collect();
+        let latency: BTreeMap<_, _> = series
+            .latency
+            .iter()
+            .map(|(name, histogram)| {
+                let value = serde_json::json!({
+                    "count": histogram.count,
+                    "sum_seconds": histogram.sum,
+                });
+                (*name, value)
+            })
+            .collect();
+        let connects: BTreeMap<_, _> = series
+            .connects
+            .iter()
+            .map(|(upstream, counts)| {
+                let value = serde_json::json!({
+                    "attempts": counts.attempts,
+                    "retries": counts.retries,
+                    "failures": counts.failures,
+                });
+                (upstream.as_str(), value)
+            })
+            .collect();
+        serde_json::json!({
+            "clients_total": self.clients.load(Ordering::Relaxed),
+            "clients_active": self.active_clients.load(Ordering::Relaxed),
+            "requests": series.requests,
+            "signs": signs,
+            "latency": latency,
+            "bytes_to_upstream": self.bytes_to_upstream.load(Ordering::Relaxed),
+            "bytes_from_upstream": self.bytes_from_upstream.load(Ordering::Relaxed),
+            "connects": connects,
+        })
+    }
+
+    /// 以 Prometheus 文本格式输出所有指标
+    pub fn render(&self, pools: Option<&[Arc<ConnectionPool>]>) -> String {
+        let mut out = String::new();
+        let series = self.series.lock().unwrap();
+
+        header(
+            &mut out,
+            "requests_total",
+            "counter",
+            "Client requests by message type",
+        );
+        for (name, count) in &series.requests {
+            sample(&mut out, "requests_total", &[("type", name)], *count);
+        }
+
+        header(
+            &mut out,
+            "sign_requests_total",
+            "counter",
+            "Sign requests by key fingerprint and result",
+        );
+        for ((fingerprint, result), count) in &series.signs {
+            let labels = [("fingerprint", fingerprint.as_str()), ("result", result)];
+            sample(&mut out, "sign_requests_total", &labels, *count);
+        }
+
+        header(
+            &mut out,
+            "request_duration_seconds",
+            "histogram",
+            "Time the upstream took to answer, by request type",
+        );
+        for (name, histogram) in &series.latency {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let le = bound.to_string();
+                let labels = [("type", *name), ("le", le.as_str())];
+                sample(&mut out, "request_duration_seconds_bucket", &labels, count);
+            }
+            let labels = [("type", *name), ("le", "+Inf")];
+            let count = histogram.count;
+            sample(&mut out, "request_duration_seconds_bucket", &labels, count);
+            sample(
+                &mut out,
+                "request_duration_seconds_sum",
+                &[("type", name)],
+                histogram.sum,
+            );
+            sample(
+                &mut out,
+                "request_duration_seconds_count",
+                &[("type", name)],
+                count,
+            );
+        }
+
+        let connect_counters: [Counter<ConnectCounts>; 3] = [
+            (
+                "upstream_connect_attempts_total",
+                "Connection attempts per upstream",
+                |counts| counts.attempts as f64,
+            ),
+            (
+                "upstream_connect_retries_total",
+                "Failed connection attempts that were retried",
+                |counts| counts.retries as f64,
+            ),
+            (
+                "upstream_connect_failures_total",
+                "Connections given up after exhausting retries",
+                |counts| counts.failures as f64,
+            ),
+        ];
+        for (metric, help, count) in connect_counters {
+            header(&mut out, metric, "counter", help);
+            for (upstream, counts) in &series.connects {
+                sample(&mut out, metric, &[("upstream", upstream)], count(counts));
+            }
+        }
+        drop(series);
+
+        header(
+            &mut out,
+            "forwarded_bytes_total",
+            "counter",
+            "Bytes of agent messages exchanged with upstreams, including length headers",
+        );
+        for (direction, counter) in [
+            ("to_upstream", &self.bytes_to_upstream),
+            ("from_upstream", &self.bytes_from_upstream),
+        ] {
+            let bytes = counter.load(Ordering::Relaxed);
+            sample(
+                &mut out,
+                "forwarded_bytes_total",
+                &[("direction", direction)],
+                bytes,
+            );
+        }
+
+        header(&mut out, "active_clients", "gauge", "Connected clients");
+        sample(
+            &mut out,
+            "active_clients",
+            &[],
+            self.active_clients.load(Ordering::Relaxed),
+        );
+        header(
+            &mut out,
+            "clients_total",
+            "counter",
+            "Clients accepted since start",
+        );
+        sample(
+            &mut out,
+            "clients_total",
+            &[],
+            self.clients.load(Ordering::Relaxed),
+        );
+
+        if let Some(pools) = pools {
+            render_pools(&mut out, pools);
+        }
+        out
+    }
+}
+
+fn render_pools(out: &mut String, pools: &[Arc<ConnectionPool>]) {
+    let stats: Vec<_> = pools
+        .iter()
+        .map(|pool| (pool.transport().to_string(), pool.stats()))
+        .collect();
+    header(
+        out,
+        "pool_size",
+        "gauge",
+        "Maximum connections per upstream pool",
+    );
+    for (upstream, stats) in &stats {
+        sample(out, "pool_size", &[("upstream", upstream)], stats.size);
+    }
+    header(
+        out,
+        "pool_connections",
+        "gauge",
+        "Pooled connections by state",
+    );
+    for (upstream, stats) in &stats {
+        let labels = [("upstream", upstream.as_str()), ("state", "in_use")];
+        sample(out, "pool_connections", &labels, stats.in_use);
+        let labels = [("upstream", upstream.as_str()), ("state", "idle")];
+        sample(out, "pool_connections", &labels, stats.idle);
+    }
+    let counters: [Counter<PoolStats>; 5] = [
+        (
+            "pool_borrows_total",
+            "Connections borrowed from the pool",
+            |stats| stats.borrows as f64,
+        ),
+        (
+            "pool_reused_total",
+            "Borrows served by an idle connection",
+            |stats| stats.reused as f64,
+        ),
+        (
+            "pool_discarded_total",
+            "Idle connections closed as broken or expired",
+            |stats| stats.discarded as f64,
+        ),
+        (
+            "pool_waits_total",
+            "Borrows that waited for a free connection",
+            |stats| stats.waits as f64,
+        ),
+        (
+            "pool_wait_seconds_total",
+            "Time spent waiting for a free connection",
+            |stats| stats.wait_time.as_secs_f64(),
+        ),
+    ];
+    for (metric, help, value) in counters {
+        header(out, metric, "counter", help);
+        for (upstream, stats) in &stats {
+            sample(out, metric, &[("upstream", upstream)], value(stats));
+        }
+    }
+}
+
+fn header(out: &mut String, metric: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP wsl2_ssh_agent_{} {}.", metric, help);
+    let _ = writeln!(out, "# TYPE wsl2_ssh_agent_{} {}", metric, kind);
+}
+
+fn sample(out: &mut String, metric: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let _ = write!(out, "wsl2_ssh_agent_{}", metric);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (name, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", name, escape_label(value));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// 标签值中的反斜杠（命名管道路径）、引号与换行需要转义
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Where the metrics endpoint listens
+pub enum MetricsListen {
+    Tcp(SocketAddr),
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Unix(String),
+}
+
+/// 解析 `127.0.0.1:9464`、`[::1]:9464` 或 `unix:/path/to/metrics.sock`，只允许回环地址
+pub fn parse_metrics_listen(spec: &str) -> Result<MetricsListen> {
+    if let Some(path) = spec.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err(anyhow!(
+                "Empty unix socket path in metrics address '{}'",
+                spec
+            ));
+        }
+        return Ok(MetricsListen::Unix(path.to_string()));
+    }
+    let addr: SocketAddr = spec.parse().map_err(|_| {
+        anyhow!(
+            "Invalid metrics address '{}', expected 127.0.0.1:PORT or unix:/path/to/metrics.sock",
+            spec
+        )
+    })?;
+    if !addr.ip().is_loopback() {
+        return Err(anyhow!(
+            "Metrics address '{}' is not a loopback address",
+            spec
+        ));
+    }
+    Ok(MetricsListen::Tcp(addr))
+}
+
+/// 绑定监听地址并在后台提供 `/metrics`
+pub async fn spawn(
+    spec: &str,
+    metrics: Arc<Metrics>,
+    pools: Option<Arc<[Arc<ConnectionPool>]>>,
+) -> Result<()> {
+    match parse_metrics_listen(spec)? {
+        MetricsListen::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow!("Failed to listen for metrics on '{}': {}", addr, e))?;
+            debug!("Serving metrics on http://{}/metrics", addr);
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => answer(stream, &metrics, &pools),
+                        Err(e) => warn!("Failed to accept metrics client: {}", e),
+                    }
+                }
+            });
+        }
+        #[cfg(unix)]
+        MetricsListen::Unix(path) => {
+            match std::fs::remove_file(&path) {
+                Ok(()) => debug!("Removed stale socket {}", path),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(anyhow!("Failed to remove stale socket '{}': {}", path, e)),
+            }
+            let listener = crate::server::bind_private(&path)
+                .map_err(|e| anyhow!("Failed to listen for metrics on '{}': {}", path, e))?;
+            debug!("Serving metrics on unix:{}", path);
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => answer(stream, &metrics, &pools),
+                        Err(e) => warn!("Failed to accept metrics client: {}", e),
+                    }
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        MetricsListen::Unix(_) => {
+            return Err(anyhow!(
+                "Serving metrics on Unix sockets is only supported on Unix"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn answer<S>(stream: S, metrics: &Arc<Metrics>, pools: &Option<Arc<[Arc<ConnectionPool>]>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let metrics = metrics.clone();
+    let pools = pools.clone();
+    tokio::spawn(async move {
+        let result = tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            handle_http(stream, &metrics, pools.as_deref()),
+        )
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Metrics request failed: {}", e),
+            Err(_) => debug!("Metrics request timed out"),
+        }
+    });
+}
+
+/// 读取请求头并应答，每个连接只处理一个请求
+async fn handle_http<S>(
+    mut stream: S,
+    metrics: &Metrics,
+    pools: Option<&[Arc<ConnectionPool>]>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(anyhow!("request head too large"));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed before the request was complete"));
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default();
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics.render(pools),
+        ),
+        ("GET", _) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(rendered: &str, metric: &str) -> Vec<String> {
+        let prefix = format!("wsl2_ssh_agent_{}", metric);
+        rendered
+            .lines()
+            .filter(|line| line.starts_with(&prefix))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn render_escapes_labels() {
+        let metrics = Metrics::default();
+        metrics.record_connect(r"pipe:\\.\pipe\openssh-ssh-agent", false, true);
+        metrics.record_connect(r"pipe:\\.\pipe\openssh-ssh-agent", true, false);
+        metrics.record_connect("unix:/tmp/\"odd\"\nname", false, false);
+        let rendered = metrics.render(None);
+        assert_eq!(
+            lines(&rendered, "upstream_connect_attempts_total"),
+            [
+                r#"wsl2_ssh_agent_upstream_connect_attempts_total{upstream="pipe:\\\\.\\pipe\\openssh-ssh-agent"} 2"#,
+                r#"wsl2_ssh_agent_upstream_connect_attempts_total{upstream="unix:/tmp/\"odd\"\nname"} 1"#,
+            ]
+        );
+        assert_eq!(
+            lines(&rendered, "upstream_connect_failures_total"),
+            [
+                r#"wsl2_ssh_agent_upstream_connect_failures_total{upstream="pipe:\\\\.\\pipe\\openssh-ssh-agent"} 0"#,
+                r#"wsl2_ssh_agent_upstream_connect_failures_total{upstream="unix:/tmp/\"odd\"\nname"} 1"#,
+            ]
+        );
+        assert!(
+            rendered.contains("# TYPE wsl2_ssh_agent_upstream_connect_retries_total counter\n")
+        );
+    }
+
+    #[test]
+    fn render_writes_cumulative_histogram_buckets() {
+        let metrics = Metrics::default();
+        let sign = proto::SSH_AGENTC_SIGN_REQUEST;
+        metrics.record_latency(sign, Duration::from_millis(30));
+        metrics.record_latency(sign, Duration::from_secs(2));
+        metrics.record_latency(sign, Duration::from_secs(600));
+        let rendered = metrics.render(None);
+        let buckets = lines(&rendered, "request_duration_seconds_bucket");
+        let bucket = |le: &str| {
+            let prefix = format!(
+                "wsl2_ssh_agent_request_duration_seconds_bucket{{type=\"SIGN_REQUEST\",le=\"{}\"}} ",
+                le
+            );
+            let line = buckets
+                .iter()
+                .find(|line| line.starts_with(&prefix))
+                .unwrap();
+            line[prefix.len()..].parse::<u64>().unwrap()
+        };
+        assert_eq!(buckets.len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!(bucket("0.025"), 0);
+        assert_eq!(bucket("0.05"), 1);
+        assert_eq!(bucket("2.5"), 2);
+        assert_eq!(bucket("120"), 2);
+        assert_eq!(bucket("+Inf"), 3);
+        assert_eq!(
+            lines(&rendered, "request_duration_seconds_count"),
+            ["wsl2_ssh_agent_request_duration_seconds_count{type=\"SIGN_REQUEST\"} 3"]
+        );
+        let sum = &lines(&rendered, "request_duration_seconds_sum")[0];
+        let sum: f64 = sum.rsplit(' ').next().unwrap().parse().unwrap();
+        assert!((sum - 602.03).abs() < 1e-9, "{sum}");
+        assert!(rendered.contains("# TYPE wsl2_ssh_agent_request_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn metrics_listen_is_loopback_only() {
+        for spec in ["127.0.0.1:9464", "[::1]:9464", "unix:/tmp/metrics.sock"] {
+            assert!(parse_metrics_listen(spec).is_ok(), "{spec}");
+        }
+        for spec in [
+            "0.0.0.0:9464",
+            "192.168.1.10:9464",
+            "[::]:9464",
+            "localhost:9464",
+            "unix:",
+        ] {
+            assert!(parse_metrics_listen(spec).is_err(), "{spec}");
+        }
+    }
+
+    /// 发送一个请求，返回完整的响应
+    async fn get<S>(mut client: S, request: &str) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn only_get_metrics_is_served() {
+        let metrics = Metrics::default();
+        for (request, status) in [
+            ("GET /metrics?name[]=x HTTP/1.1\r\n\r\n", "200 OK"),
+            ("GET /metrics/ HTTP/1.1\r\n\r\n", "404 Not Found"),
+            ("GET / HTTP/1.0\r\nHost: localhost\r\n\r\n", "404 Not Found"),
+            ("POST /metrics HTTP/1.1\r\n\r\n", "405 Method Not Allowed"),
+        ] {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (response, result) =
+                tokio::join!(get(client, request), handle_http(server, &metrics, None));
+            result.unwrap();
+            let status_line = response.lines().next().unwrap();
+            assert_eq!(status_line, format!("HTTP/1.1 {}", status), "{request:?}");
+        }
+        // 请求头过大时不应答
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let request = format!("GET /metrics HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(10_000));
+        let (response, result) =
+            tokio::join!(get(client, &request), handle_http(server, &metrics, None));
+        assert!(result.is_err());
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_loopback() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_request(proto::SSH_AGENTC_REQUEST_IDENTITIES);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            answer(stream, &metrics, &None);
+        };
+        let client = tokio::net::TcpStream::connect(addr);
+        let ((), client) = tokio::join!(server, client);
+        let response = get(client.unwrap(), "GET /metrics HTTP/1.1\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("wsl2_ssh_agent_requests_total{type=\"REQUEST_IDENTITIES\"} 1\n"));
+    }
+}
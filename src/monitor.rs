@@ -0,0 +1,691 @@
+//! Terminal dashboard of a running `serve` process
+//!
+//! `monitor` subscribes to the event stream of the control socket first and
+//! then seeds its view with `status`, `list-clients` and `list-upstreams`,
+//! so nothing that happens in between is lost. Everything afterwards comes
+//! from events: connected clients, upstream health, every decoded request
+//! and per-key sign counters.
+
+use crate::control;
+use anyhow::Result;
+use crossterm::event::{
+    Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+
+/// 请求表格最多保留的行数
+const MAX_REQUESTS: usize = 1000;
+/// 没有事件时刷新运行时间的间隔
+const TICK: Duration = Duration::from_secs(1);
+
+struct ClientRow {
+    label: String,
+    process: String,
+    requests: u64,
+}
+
+struct UpstreamRow {
+    address: String,
+    up: Option<bool>,
+    error: Option<String>,
+}
+
+struct RequestRow {
+    time: String,
+    client: String,
+    request: String,
+    key: String,
+    target: String,
+    result: String,
+    latency_ms: u64,
+}
+
+#[derive(Default)]
+struct KeyCounts {
+    comment: String,
+    approved: u64,
+    denied: u64,
+    failed: u64,
+    last_used: String,
+}
+
+/// Everything shown on screen
+struct Monitor {
+    title: String,
+    // 订阅时 serve 已运行的时间，以及对应的本地时刻
+    uptime: (Duration, Instant),
+    clients: Vec<ClientRow>,
+    upstreams: Vec<UpstreamRow>,
+    requests: VecDeque<RequestRow>,
+    keys: BTreeMap<String, KeyCounts>,
+    table: TableState,
+    // 跟随最新的请求；向上滚动后暂停
+    follow: bool,
+    notice: String,
+    streaming: bool,
+}
+
+fn text(value: &Value, field: &str) -> String {
+    value[field].as_str().unwrap_or_default().to_string()
+}
+
+/// `2026-10-17T22:52:02.082Z` 只显示时刻部分
+fn clock(time: &str) -> String {
+    time.get(11..23).unwrap_or(time).to_string()
+}
+
+/// 注释未知时显示指纹的开头
+fn key_label(event: &Value) -> String {
+    match event["key_comment"].as_str() {
+        Some(comment) => comment.to_string(),
+        None => event["key_fingerprint"]
+            .as_str()
+            .map(|fingerprint| fingerprint.chars().take(19).collect())
+            .unwrap_or_default(),
+    }
+}
+
+fn process_label(event: &Value) -> String {
+    match (event["process"].as_str(), event["pid"].as_i64()) {
+        (Some(process), Some(pid)) => format!("{} ({})", process, pid),
+        (None, Some(pid)) => format!("pid {}", pid),
+        _ => String::new(),
+    }
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let seconds = uptime.as_secs();
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+fn result_style(result: &str) -> Style {
+    match result {
+        "success" => Style::new().fg(Color::Green),
+        "denied" => Style::new().fg(Color::Red),
+        _ => Style::new().fg(Color::Yellow),
+    }
+}
+
+impl Monitor {
+    fn new(status: &Value, clients: &Value, upstreams: &Value) -> Self {
+        let title = format!(
+            "wsl2-ssh-agent {} · pid {} · {}",
+            text(status, "version"),
+            status["pid"],
+            text(status, "listen")
+        );
+        let uptime = Duration::from_secs(status["uptime_seconds"].as_u64().unwrap_or_default());
+        let clients = clients
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|client| ClientRow {
+                label: format!("client #{}", client["id"]),
+                process: process_label(client),
+                requests: client["requests"].as_u64().unwrap_or_default(),
+            })
+            .collect();
+        let upstreams = upstreams
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|upstream| UpstreamRow {
+                address: text(upstream, "address"),
+                up: upstream["reachable"].as_bool(),
+                error: upstream["error"].as_str().map(str::to_string),
+            })
+            .collect();
+        Self {
+            title,
+            uptime: (uptime, Instant::now()),
+            clients,
+            upstreams,
+            requests: VecDeque::new(),
+            keys: BTreeMap::new(),
+            table: TableState::default(),
+            follow: true,
+            notice: String::new(),
+            streaming: true,
+        }
+    }
+
+    fn apply(&mut self, event: &Value) {
+        let time = clock(event["time"].as_str().unwrap_or_default());
+        match event["event"].as_str().unwrap_or_default() {
+            "client-connected" => {
+                let label = text(event, "client");
+                // 订阅与 list-clients 之间连接的客户端会出现两次
+                if !self.clients.iter().any(|client| client.label == label) {
+                    self.clients.push(ClientRow {
+                        label,
+                        process: process_label(event),
+                        requests: 0,
+                    });
+                }
+            }
+            "client-disconnected" => {
+                let label = text(event, "client");
+                self.clients.retain(|client| client.label != label);
+            }
+            "request" => self.push_request(event, time),
+            name @ ("sign-approved" | "sign-denied" | "sign-failed") => {
+                let counts = self.keys.entry(text(event, "key_fingerprint")).or_default();
+                counts.comment = text(event, "key_comment");
+                match name {
+                    "sign-approved" => counts.approved += 1,
+                    "sign-denied" => counts.denied += 1,
+                    _ => counts.failed += 1,
+                }
+                counts.last_used = time;
+            }
+            "identities-changed" => {
+                let count = |field: &str| event[field].as_array().map_or(0, Vec::len);
+                self.notice = format!(
+                    "{} identities changed: {} added, {} removed, {} total",
+                    time,
+                    count("added"),
+                    count("removed"),
+                    event["total"]
+                );
+            }
+            name @ ("upstream-up" | "upstream-down") => {
+                let address = text(event, "upstream");
+                let up = name == "upstream-up";
+                let error = event["error"].as_str().map(str::to_string);
+                match self.upstreams.iter_mut().find(|row| row.address == address) {
+                    Some(row) => {
+                        row.up = Some(up);
+                        row.error = error;
+                    }
+                    None => self.upstreams.push(UpstreamRow {
+                        address,
+                        up: Some(up),
+                        error,
+                    }),
+                }
+            }
+            "lagged" => {
+                self.notice = format!(
+                    "Missed {} events while the screen was busy",
+                    event["missed"]
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn push_request(&mut self, event: &Value, time: String) {
+        let client = text(event, "client");
+        if let Some(row) = self.clients.iter_mut().find(|row| row.label == client) {
+            row.requests += 1;
+        }
+        let target = match (event["user"].as_str(), event["namespace"].as_str()) {
+            (Some(user), _) => user.to_string(),
+            (None, Some(namespace)) => format!("sshsig:{}", namespace),
+            _ => String::new(),
+        };
+        self.requests.push_back(RequestRow {
+            time,
+            client,
+            request: text(event, "request"),
+            key: key_label(event),
+            target,
+            result: text(event, "result"),
+            latency_ms: event["latency_ms"].as_u64().unwrap_or_default(),
+        });
+        if self.requests.len() > MAX_REQUESTS {
+            self.requests.pop_front();
+            // 保持选中的仍是同一行
+            if let Some(selected) = self.table.selected().filter(|_| !self.follow) {
+                self.table.select(Some(selected.saturating_sub(1)));
+            }
+        }
+    }
+
+    /// 处理按键，返回是否退出
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let last = self.requests.len().saturating_sub(1);
+        let selected = self.table.selected().unwrap_or(last);
+        let moved = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+            KeyCode::Char('c') => {
+                self.requests.clear();
+                self.keys.clear();
+                // 清空后没有可停留的行，恢复跟随最新的请求
+                self.follow = true;
+                self.table.select(None);
+                return false;
+            }
+            KeyCode::Up | KeyCode::Char('k') => Some(selected.saturating_sub(1)),
+            KeyCode::Down | KeyCode::Char('j') => Some(selected + 1),
+            KeyCode::PageUp => Some(selected.saturating_sub(10)),
+            KeyCode::PageDown => Some(selected + 10),
+            KeyCode::Home | KeyCode::Char('g') => Some(0),
+            KeyCode::End | KeyCode::Char('G') => Some(last),
+            _ => None,
+        };
+        if let Some(row) = moved {
+            let row = row.min(last);
+            self.follow = row == last;
+            self.table.select(Some(row));
+        }
+        false
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, top, requests, keys, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(8),
+            Constraint::Min(6),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [clients, upstreams] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+
+        let (uptime, since) = self.uptime;
+        let state = if self.streaming {
+            Span::styled("live", Style::new().fg(Color::Green))
+        } else {
+            Span::styled("disconnected", Style::new().fg(Color::Red))
+        };
+        let title = Line::from(vec![
+            Span::styled(&self.title, Style::new().add_modifier(Modifier::BOLD)),
+            Span::raw(format!(
+                " · up {} · ",
+                format_uptime(uptime + since.elapsed())
+            )),
+            state,
+        ]);
+        frame.render_widget(Paragraph::new(title), header);
+
+        self.draw_clients(frame, clients);
+        self.draw_upstreams(frame, upstreams);
+        self.draw_requests(frame, requests);
+        self.draw_keys(frame, keys);
+
+        let help = if self.notice.is_empty() {
+            "q quit · ↑/↓ PgUp/PgDn scroll · End follow · c clear".to_string()
+        } else {
+            self.notice.clone()
+        };
+        frame.render_widget(
+            Paragraph::new(help).style(Style::new().fg(Color::DarkGray)),
+            footer,
+        );
+    }
+
+    fn draw_clients(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.clients.iter().map(|client| {
+            Row::new(vec![
+                client.label.clone(),
+                client.process.clone(),
+                client.requests.to_string(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(11),
+                Constraint::Fill(1),
+                Constraint::Length(8),
+            ],
+        )
+        .header(Row::new(["Client", "Process", "Requests"]).style(header_style()))
+        .block(Block::bordered().title(format!(" Clients ({}) ", self.clients.len())));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_upstreams(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.upstreams.iter().map(|upstream| {
+            let (state, style) = match upstream.up {
+                Some(true) => ("up", Style::new().fg(Color::Green)),
+                Some(false) => ("down", Style::new().fg(Color::Red)),
+                None => ("?", Style::new()),
+            };
+            Row::new(vec![
+                Line::raw(upstream.address.clone()),
+                Line::styled(state, style),
+                Line::raw(upstream.error.clone().unwrap_or_default()),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Percentage(45),
+                Constraint::Length(5),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(Row::new(["Upstream", "State", "Last error"]).style(header_style()))
+        .block(Block::bordered().title(" Upstreams "));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_requests(&mut self, frame: &mut Frame, area: Rect) {
+        if self.follow {
+            self.table.select(self.requests.len().checked_sub(1));
+        }
+        let rows = self.requests.iter().map(|request| {
+            Row::new(vec![
+                Line::raw(request.time.clone()),
+                Line::raw(request.client.clone()),
+                Line::raw(request.request.clone()),
+                Line::raw(request.key.clone()),
+                Line::raw(request.target.clone()),
+                Line::styled(request.result.clone(), result_style(&request.result)),
+                Line::raw(format!("{} ms", request.latency_ms)).right_aligned(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(12),
+                Constraint::Length(11),
+                Constraint::Length(20),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+                Constraint::Length(7),
+                Constraint::Length(9),
+            ],
+        )
+        .header(
+            Row::new([
+                "Time", "Client", "Request", "Key", "User", "Result", "Latency",
+            ])
+            .style(header_style()),
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(format!(
+            " Requests ({}){} ",
+            self.requests.len(),
+            if self.follow { "" } else { " · paused" }
+        )));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+
+    fn draw_keys(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.keys.iter().map(|(fingerprint, counts)| {
+            Row::new(vec![
+                Line::raw(counts.comment.clone()),
+                Line::raw(fingerprint.clone()),
+                Line::styled(counts.approved.to_string(), result_style("success")),
+                Line::styled(counts.denied.to_string(), result_style("denied")),
+                Line::styled(counts.failed.to_string(), result_style("failure")),
+                Line::raw(counts.last_used.clone()),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(51),
+                Constraint::Length(8),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Length(12),
+            ],
+        )
+        .header(
+            Row::new([
+                "Key",
+                "Fingerprint",
+                "Signed",
+                "Denied",
+                "Failed",
+                "Last used",
+            ])
+            .style(header_style()),
+        )
+        .block(Block::bordered().title(" Keys "));
+        frame.render_widget(table, area);
+    }
+}
+
+fn header_style() -> Style {
+    Style::new().add_modifier(Modifier::BOLD)
+}
+
+/// 连接控制 socket 并运行界面，直到用户退出
+pub async fn run(spec: &str) -> Result<()> {
+    let mut subscription = control::subscribe(spec, &[]).await?;
+    let status = control::call(spec, "status", Value::Null).await?;
+    let clients = control::call(spec, "list-clients", Value::Null).await?;
+    let upstreams = control::call(spec, "list-upstreams", Value::Null).await?;
+    let mut monitor = Monitor::new(&status, &clients, &upstreams);
+
+    // ratatui::init 会在 panic 时恢复终端
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut monitor, &mut subscription).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    monitor: &mut Monitor,
+    subscription: &mut control::Subscription,
+) -> Result<()> {
+    let mut input = EventStream::new();
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        terminal.draw(|frame| monitor.draw(frame))?;
+        tokio::select! {
+            event = subscription.next(), if monitor.streaming => match event {
+                Ok(Some(event)) => monitor.apply(&event),
+                Ok(None) => {
+                    monitor.streaming = false;
+                    monitor.notice = "The bridge closed the event stream".to_string();
+                }
+                Err(e) => {
+                    monitor.streaming = false;
+                    monitor.notice = format!("Event stream failed: {}", e);
+                }
+            },
+            input = input.next() => match input {
+                Some(Ok(TermEvent::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if monitor.handle_key(key) {
+                        return Ok(());
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            _ = tick.tick() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn monitor() -> Monitor {
+        Monitor::new(
+            &json!({"version": "0.1.0", "pid": 42, "listen": "unix:/tmp/agent.sock", "uptime_seconds": 90}),
+            &json!([{"id": 1, "process": "ssh", "pid": 100, "requests": 3}]),
+            &json!([{"address": "unix:/tmp/up.sock", "reachable": true}]),
+        )
+    }
+
+    fn request(n: usize) -> Value {
+        json!({
+            "event": "request",
+            "time": "2026-10-17T22:52:02.082Z",
+            "client": "client #1",
+            "request": "SIGN_REQUEST",
+            "key_fingerprint": "SHA256:abcdefghijklmnopqrstuvwxyz",
+            "user": format!("user{}", n),
+            "result": "success",
+            "latency_ms": 12,
+        })
+    }
+
+    fn press(monitor: &mut Monitor, code: KeyCode) -> bool {
+        monitor.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    #[test]
+    fn events_update_clients_upstreams_and_keys() {
+        let mut monitor = monitor();
+        monitor.apply(&json!({"event": "client-connected", "client": "client #2", "pid": 7}));
+        // list-clients 与事件流都报告的客户端只出现一次
+        monitor.apply(&json!({"event": "client-connected", "client": "client #1"}));
+        monitor.apply(&request(0));
+        let labels: Vec<_> = monitor.clients.iter().map(|c| c.label.as_str()).collect();
+        assert_eq!(labels, ["client #1", "client #2"]);
+        assert_eq!(monitor.clients[0].requests, 4);
+        assert_eq!(monitor.clients[1].process, "pid 7");
+        monitor.apply(&json!({"event": "client-disconnected", "client": "client #1"}));
+        assert_eq!(monitor.clients.len(), 1);
+
+        monitor.apply(
+            &json!({"event": "upstream-down", "upstream": "unix:/tmp/up.sock", "error": "refused"}),
+        );
+        monitor.apply(&json!({"event": "upstream-up", "upstream": "pipe:other"}));
+        assert_eq!(monitor.upstreams[0].up, Some(false));
+        assert_eq!(monitor.upstreams[0].error.as_deref(), Some("refused"));
+        assert_eq!(monitor.upstreams[1].up, Some(true));
+
+        let sign = |event: &str| json!({"event": event, "time": "2026-10-17T22:52:02.082Z", "key_fingerprint": "SHA256:k", "key_comment": "work"});
+        for event in [
+            "sign-approved",
+            "sign-approved",
+            "sign-denied",
+            "sign-failed",
+        ] {
+            monitor.apply(&sign(event));
+        }
+        let counts = &monitor.keys["SHA256:k"];
+        assert_eq!((counts.approved, counts.denied, counts.failed), (2, 1, 1));
+        assert_eq!(counts.comment, "work");
+        assert_eq!(counts.last_used, "22:52:02.082");
+
+        monitor.apply(&json!({"event": "identities-changed", "time": "2026-10-17T22:52:03.000Z", "added": [{}], "removed": [], "total": 3}));
+        assert_eq!(
+            monitor.notice,
+            "22:52:03.000 identities changed: 1 added, 0 removed, 3 total"
+        );
+    }
+
+    #[test]
+    fn requests_keep_their_details_and_the_newest_rows() {
+        let mut monitor = monitor();
+        monitor.apply(&request(0));
+        let row = &monitor.requests[0];
+        assert_eq!(row.time, "22:52:02.082");
+        assert_eq!(row.key, "SHA256:abcdefghijkl");
+        assert_eq!(row.target, "user0");
+        let mut sshsig = request(1);
+        sshsig["user"] = Value::Null;
+        sshsig["namespace"] = json!("git");
+        sshsig["key_comment"] = json!("laptop");
+        monitor.apply(&sshsig);
+        assert_eq!(monitor.requests[1].target, "sshsig:git");
+        assert_eq!(monitor.requests[1].key, "laptop");
+
+        for n in 2..MAX_REQUESTS + 5 {
+            monitor.apply(&request(n));
+        }
+        assert_eq!(monitor.requests.len(), MAX_REQUESTS);
+        assert_eq!(monitor.requests[0].target, "user5");
+        let newest = format!("user{}", MAX_REQUESTS + 4);
+        assert_eq!(monitor.requests.back().unwrap().target, newest);
+    }
+
+    #[test]
+    fn scrolling_up_pauses_following() {
+        let mut monitor = monitor();
+        for n in 0..20 {
+            monitor.apply(&request(n));
+        }
+        press(&mut monitor, KeyCode::Up);
+        assert!(!monitor.follow);
+        assert_eq!(monitor.table.selected(), Some(18));
+        press(&mut monitor, KeyCode::PageUp);
+        assert_eq!(monitor.table.selected(), Some(8));
+        press(&mut monitor, KeyCode::Home);
+        assert_eq!(monitor.table.selected(), Some(0));
+        press(&mut monitor, KeyCode::Up);
+        assert_eq!(monitor.table.selected(), Some(0));
+        press(&mut monitor, KeyCode::PageDown);
+        press(&mut monitor, KeyCode::PageDown);
+        assert_eq!(monitor.table.selected(), Some(19));
+        assert!(monitor.follow);
+        press(&mut monitor, KeyCode::Char('k'));
+        press(&mut monitor, KeyCode::End);
+        assert!(monitor.follow);
+        assert!(press(&mut monitor, KeyCode::Char('q')));
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert!(monitor.handle_key(ctrl_c));
+    }
+
+    #[test]
+    fn paused_selection_stays_on_the_same_row_when_old_rows_drop() {
+        let mut monitor = monitor();
+        for n in 0..MAX_REQUESTS {
+            monitor.apply(&request(n));
+        }
+        press(&mut monitor, KeyCode::Home);
+        press(&mut monitor, KeyCode::PageDown);
+        monitor.apply(&request(MAX_REQUESTS));
+        let selected = monitor.table.selected().unwrap();
+        assert_eq!(monitor.requests[selected].target, "user10");
+    }
+
+    #[test]
+    fn clearing_empties_requests_and_keys_and_follows_again() {
+        let mut monitor = monitor();
+        for n in 0..5 {
+            monitor.apply(&request(n));
+        }
+        monitor.apply(&json!({"event": "sign-approved", "key_fingerprint": "SHA256:k"}));
+        press(&mut monitor, KeyCode::Up);
+        assert!(!press(&mut monitor, KeyCode::Char('c')));
+        assert!(monitor.requests.is_empty());
+        assert!(monitor.keys.is_empty());
+        assert!(monitor.follow);
+        monitor.apply(&request(5));
+        press(&mut monitor, KeyCode::Down);
+        assert_eq!(monitor.table.selected(), Some(0));
+    }
+
+    #[test]
+    fn draws_without_a_terminal() {
+        let mut monitor = monitor();
+        for n in 0..3 {
+            monitor.apply(&request(n));
+        }
+        let backend = ratatui::backend::TestBackend::new(120, 40);
+        let mut terminal = ratatui::Terminal::new(backend).unwrap();
+        terminal.draw(|frame| monitor.draw(frame)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("wsl2-ssh-agent 0.1.0 · pid 42"));
+        assert!(screen.contains("Requests (3)"));
+        assert!(screen.contains("ssh (100)"));
+        // 跟随模式下选中最新的一行
+        assert_eq!(monitor.table.selected(), Some(2));
+    }
+}
@@ -0,0 +1,664 @@
+//! Many upstream connections multiplexed over one helper process
+//!
+//! Launching `wsl2-ssh-agent.exe` through WSL interop for every ssh
+//! invocation dominates connection latency. Instead a Linux-native `serve`
+//! process starts a single long-lived `wsl2-ssh-agent.exe helper` and tunnels
+//! every upstream connection through its stdin/stdout as a numbered channel.
+//!
+//! Frames use the [`SshAgentCodec`] length prefix and carry
+//! `byte kind, uint32 channel, data`:
+//!
+//! | kind        | direction       | data                               |
+//! |-------------|-----------------|------------------------------------|
+//! | HELLO       | both            | uint32 protocol version            |
+//! | OPEN        | daemon → helper | string upstream (`pipe:`/`unix:`)  |
+//! | OPENED      | helper → daemon |                                    |
+//! | OPEN_FAILED | helper → daemon | string error                       |
+//! | DATA        | both            | raw bytes of the agent connection  |
+//! | CLOSE       | both            |                                    |
+//! | PING / PONG | both            | keepalive                          |
+//!
+//! The daemon sends HELLO first and the helper answers with its own version;
+//! a mismatch ends the session. Channel 0 is used for HELLO, PING and PONG.
+
+use crate::codec::{SshAgentCodec, SshAgentMessage};
+use crate::proto::{Reader, Writer};
+use crate::transport::{self, BoxedStream, UpstreamTransport};
+use anyhow::{Result, anyhow};
+use futures::SinkExt;
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const HELLO: u8 = 1;
+const OPEN: u8 = 2;
+const OPENED: u8 = 3;
+const OPEN_FAILED: u8 = 4;
+const DATA: u8 = 5;
+const CLOSE: u8 = 6;
+const PING: u8 = 7;
+const PONG: u8 = 8;
+
+// 单个 DATA 帧携带的最大字节数
+const CHUNK_SIZE: usize = 16 * 1024;
+const MAX_FRAME_SIZE: u32 = 2 * CHUNK_SIZE as u32;
+// 每个通道最多缓存的 DATA 帧，足以容纳一条完整的 agent 消息
+const CHANNEL_QUEUE: usize = 32;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+enum Frame {
+    Hello { version: u32 },
+    Open { channel: u32, upstream: String },
+    Opened { channel: u32 },
+    OpenFailed { channel: u32, error: String },
+    Data { channel: u32, data: Vec<u8> },
+    Close { channel: u32 },
+    Ping,
+    Pong,
+}
+
+impl Frame {
+    fn encode(&self) -> SshAgentMessage {
+        let mut writer = Writer::new();
+        match self {
+            Frame::Hello { version } => writer.write_u8(HELLO).write_u32(0).write_u32(*version),
+            Frame::Open { channel, upstream } => writer
+                .write_u8(OPEN)
+                .write_u32(*channel)
+                .write_string(upstream.as_bytes()),
+            Frame::Opened { channel } => writer.write_u8(OPENED).write_u32(*channel),
+            Frame::OpenFailed { channel, error } => writer
+                .write_u8(OPEN_FAILED)
+                .write_u32(*channel)
+                .write_string(error.as_bytes()),
+            Frame::Data { channel, data } => {
+                writer.write_u8(DATA).write_u32(*channel).write_raw(data)
+            }
+            Frame::Close { channel } => writer.write_u8(CLOSE).write_u32(*channel),
+            Frame::Ping => writer.write_u8(PING).write_u32(0),
+            Frame::Pong => writer.write_u8(PONG).write_u32(0),
+        };
+        SshAgentMessage::from_payload(writer.into_bytes())
+    }
+
+    fn parse(payload: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(payload);
+        let kind = reader.read_u8()?;
+        let channel = reader.read_u32()?;
+        let frame = match kind {
+            HELLO => Frame::Hello {
+                version: reader.read_u32()?,
+            },
+            OPEN => Frame::Open {
+                channel,
+                upstream: reader.read_utf8()?,
+            },
+            OPENED => Frame::Opened { channel },
+            OPEN_FAILED => Frame::OpenFailed {
+                channel,
+                error: reader.read_utf8()?,
+            },
+            DATA => Frame::Data {
+                channel,
+                data: reader.rest().to_vec(),
+            },
+            CLOSE => Frame::Close { channel },
+            PING => Frame::Ping,
+            PONG => Frame::Pong,
+            _ => return Err(anyhow!("Unknown multiplexer frame kind {}", kind)),
+        };
+        reader.finish()?;
+        Ok(frame)
+    }
+}
+
+/// 读取下一帧；对端关闭时返回 `None`
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut FramedRead<R, SshAgentCodec>,
+) -> Result<Option<Frame>> {
+    match reader.next().await {
+        Some(frame) => {
+            let msg = frame?.map_err(|e| anyhow!("Invalid multiplexer frame: {}", e))?;
+            Frame::parse(&msg.payload).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// 所有通道共用一个写入任务，保证帧不会交错
+fn spawn_writer<W>(write: W) -> mpsc::Sender<Frame>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (outgoing, mut frames) = mpsc::channel::<Frame>(64);
+    tokio::spawn(async move {
+        let mut writer = FramedWrite::new(write, SshAgentCodec::new(MAX_FRAME_SIZE));
+        while let Some(frame) = frames.recv().await {
+            if let Err(e) = writer.send(frame.encode()).await {
+                debug!("Multiplexer writer stopped: {}", e);
+                break;
+            }
+        }
+    });
+    outgoing
+}
+
+type Channels = Arc<Mutex<HashMap<u32, mpsc::Sender<Vec<u8>>>>>;
+
+/// 发送 CLOSE；写入队列已满时交给单独的任务等待，不能丢弃
+fn send_close(outgoing: &mpsc::Sender<Frame>, channel: u32) {
+    if let Err(mpsc::error::TrySendError::Full(close)) = outgoing.try_send(Frame::Close { channel })
+    {
+        let outgoing = outgoing.clone();
+        tokio::spawn(async move {
+            let _ = outgoing.send(close).await;
+        });
+    }
+}
+
+/// 把收到的数据交给通道的转发任务
+///
+/// The demultiplexer must not wait on a single channel, so a channel whose
+/// local end stops reading until its queue is full gets closed instead of
+/// buffering without limit. Returns `false` when the channel was closed and
+/// should be forgotten.
+fn forward(
+    data: &mpsc::Sender<Vec<u8>>,
+    channel: u32,
+    bytes: Vec<u8>,
+    outgoing: &mpsc::Sender<Frame>,
+) -> bool {
+    match data.try_send(bytes) {
+        // 转发任务已结束，由它负责清理
+        Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => true,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            warn!("Channel {} is not reading its data, closing it", channel);
+            send_close(outgoing, channel);
+            false
+        }
+    }
+}
+
+/// 在一个通道与本地字节流之间转发数据，直到任一方关闭
+async fn pump_channel<S>(
+    channel: u32,
+    stream: S,
+    mut incoming: mpsc::Receiver<Vec<u8>>,
+    outgoing: mpsc::Sender<Frame>,
+) where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut read, mut write) = tokio::io::split(stream);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut remote_closed = false;
+    loop {
+        tokio::select! {
+            read = read.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let data = buf[..n].to_vec();
+                    if outgoing.send(Frame::Data { channel, data }).await.is_err() {
+                        break;
+                    }
+                }
+            },
+            data = incoming.recv() => match data {
+                Some(data) => {
+                    if write.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    remote_closed = true;
+                    break;
+                }
+            },
+        }
+    }
+    let _ = write.shutdown().await;
+    if !remote_closed {
+        let _ = outgoing.send(Frame::Close { channel }).await;
+    }
+}
+
+/// Windows 端 helper：为每个通道打开一条到 upstream 的连接
+pub async fn run_helper() -> Result<()> {
+    let mut reader = FramedRead::new(tokio::io::stdin(), SshAgentCodec::new(MAX_FRAME_SIZE));
+    let outgoing = spawn_writer(tokio::io::stdout());
+
+    match read_frame(&mut reader).await? {
+        Some(Frame::Hello { version }) if version == PROTOCOL_VERSION => {}
+        Some(Frame::Hello { version }) => {
+            let _ = outgoing
+                .send(Frame::Hello {
+                    version: PROTOCOL_VERSION,
+                })
+                .await;
+            return Err(anyhow!(
+                "Daemon speaks multiplexer protocol {}, helper speaks {}",
+                version,
+                PROTOCOL_VERSION
+            ));
+        }
+        other => return Err(anyhow!("Expected HELLO from daemon, got {:?}", other)),
+    }
+    outgoing
+        .send(Frame::Hello {
+            version: PROTOCOL_VERSION,
+        })
+        .await?;
+    debug!("Helper ready, protocol version {}", PROTOCOL_VERSION);
+
+    let channels: Channels = Arc::default();
+    while let Some(frame) = read_frame(&mut reader).await? {
+        match frame {
+            Frame::Open { channel, upstream } => {
+                let (data, incoming) = mpsc::channel(CHANNEL_QUEUE);
+                channels.lock().unwrap().insert(channel, data);
+                let channels = channels.clone();
+                let outgoing = outgoing.clone();
+                tokio::spawn(async move {
+                    let stream = match transport::parse_upstream(&upstream) {
+                        Ok(transport) => transport.connect().await.map_err(anyhow::Error::from),
+                        Err(e) => Err(e),
+                    };
+                    match stream {
+                        Ok(stream) => {
+                            debug!("Channel {} connected to {}", channel, upstream);
+                            let _ = outgoing.send(Frame::Opened { channel }).await;
+                            pump_channel(channel, stream, incoming, outgoing).await;
+                            debug!("Channel {} closed", channel);
+                        }
+                        Err(e) => {
+                            debug!("Channel {} failed to open {}: {}", channel, upstream, e);
+                            let error = e.to_string();
+                            let _ = outgoing.send(Frame::OpenFailed { channel, error }).await;
+                        }
+                    }
+                    channels.lock().unwrap().remove(&channel);
+                });
+            }
+            Frame::Data { channel, data } => {
+                let mut channels = channels.lock().unwrap();
+                if let Some(sender) = channels.get(&channel)
+                    && !forward(sender, channel, data, &outgoing)
+                {
+                    channels.remove(&channel);
+                }
+            }
+            // 丢弃发送端即通知转发任务关闭连接
+            Frame::Close { channel } => {
+                channels.lock().unwrap().remove(&channel);
+            }
+            Frame::Ping => outgoing.send(Frame::Pong).await?,
+            other => debug!("Ignoring unexpected frame from daemon: {:?}", other),
+        }
+    }
+    debug!("Daemon closed stdin, helper exiting");
+    Ok(())
+}
+
+/// 通道在守护进程一侧的状态
+struct ChannelSlot {
+    opened: Option<oneshot::Sender<std::result::Result<(), String>>>,
+    data: mpsc::Sender<Vec<u8>>,
+}
+
+/// 与一个 helper 进程的多路复用会话
+struct MuxClient {
+    outgoing: mpsc::Sender<Frame>,
+    channels: Arc<Mutex<HashMap<u32, ChannelSlot>>>,
+    next_channel: AtomicU32,
+    alive: Arc<AtomicBool>,
+}
+
+impl MuxClient {
+    async fn start(command: &str, keepalive: Duration) -> Result<Self> {
+        let mut child = tokio::process::Command::new(command)
+            .arg("helper")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start helper '{}': {}", command, e))?;
+        let stdin = child.stdin.take().expect("helper stdin is piped");
+        let stdout = child.stdout.take().expect("helper stdout is piped");
+        let mut reader = FramedRead::new(stdout, SshAgentCodec::new(MAX_FRAME_SIZE));
+        let outgoing = spawn_writer(stdin);
+
+        outgoing
+            .send(Frame::Hello {
+                version: PROTOCOL_VERSION,
+            })
+            .await?;
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(&mut reader)).await {
+            Ok(Ok(Some(Frame::Hello { version }))) if version == PROTOCOL_VERSION => {}
+            Ok(Ok(Some(Frame::Hello { version }))) => {
+                return Err(anyhow!(
+                    "Helper '{}' speaks multiplexer protocol {}, expected {}",
+                    command,
+                    version,
+                    PROTOCOL_VERSION
+                ));
+            }
+            Ok(Ok(other)) => {
+                return Err(anyhow!(
+                    "Helper '{}' did not answer HELLO: {:?}",
+                    command,
+                    other
+                ));
+            }
+            Ok(Err(e)) => return Err(anyhow!("Helper '{}' handshake failed: {}", command, e)),
+            Err(_) => return Err(anyhow!("Helper '{}' did not answer HELLO in time", command)),
+        }
+        debug!("Started helper '{}' (pid {:?})", command, child.id());
+
+        let client = Self {
+            outgoing,
+            channels: Arc::default(),
+            next_channel: AtomicU32::new(1),
+            alive: Arc::new(AtomicBool::new(true)),
+        };
+        tokio::spawn(supervise(
+            child,
+            reader,
+            client.outgoing.clone(),
+            client.channels.clone(),
+            client.alive.clone(),
+            keepalive,
+        ));
+        Ok(client)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    async fn open(&self, upstream: &str) -> std::io::Result<BoxedStream> {
+        let helper_gone = || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "helper exited");
+        let channel = self.next_channel.fetch_add(1, Ordering::Relaxed);
+        let (opened, opened_rx) = oneshot::channel();
+        let (data, incoming) = mpsc::channel(CHANNEL_QUEUE);
+        self.channels.lock().unwrap().insert(
+            channel,
+            ChannelSlot {
+                opened: Some(opened),
+                data,
+            },
+        );
+        let mut pending = PendingChannel {
+            client: self,
+            channel,
+            established: false,
+        };
+        let open = Frame::Open {
+            channel,
+            upstream: upstream.to_string(),
+        };
+        if self.outgoing.send(open).await.is_err() {
+            return Err(helper_gone());
+        }
+        match opened_rx.await {
+            Ok(Ok(())) => pending.established = true,
+            Ok(Err(error)) => return Err(std::io::Error::other(error)),
+            Err(_) => return Err(helper_gone()),
+        }
+
+        let (local, remote) = tokio::io::duplex(4 * CHUNK_SIZE);
+        let outgoing = self.outgoing.clone();
+        let channels = self.channels.clone();
+        tokio::spawn(async move {
+            pump_channel(channel, remote, incoming, outgoing).await;
+            channels.lock().unwrap().remove(&channel);
+        });
+        Ok(Box::new(local))
+    }
+}
+
+/// 正在等待 OPENED 的通道
+///
+/// Dropped without being established (the open failed or the caller gave
+/// up, e.g. on a request timeout) it frees the slot and asks the helper to
+/// close the channel in case the upstream connection was made after all.
+struct PendingChannel<'a> {
+    client: &'a MuxClient,
+    channel: u32,
+    established: bool,
+}
+
+impl Drop for PendingChannel<'_> {
+    fn drop(&mut self) {
+        if self.established {
+            return;
+        }
+        self.client.channels.lock().unwrap().remove(&self.channel);
+        send_close(&self.client.outgoing, self.channel);
+    }
+}
+
+/// 读取 helper 的输出并分发到各通道，同时发送心跳；helper 失联时关闭所有通道
+async fn supervise(
+    mut child: tokio::process::Child,
+    mut reader: FramedRead<tokio::process::ChildStdout, SshAgentCodec>,
+    outgoing: mpsc::Sender<Frame>,
+    channels: Arc<Mutex<HashMap<u32, ChannelSlot>>>,
+    alive: Arc<AtomicBool>,
+    keepalive: Duration,
+) {
+    let mut ticker = tokio::time::interval(keepalive);
+    let mut last_seen = Instant::now();
+    loop {
+        tokio::select! {
+            frame = read_frame(&mut reader) => {
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => {
+                        warn!("Helper closed its output");
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Helper sent an invalid frame: {}", e);
+                        break;
+                    }
+                };
+                last_seen = Instant::now();
+                let mut channels = channels.lock().unwrap();
+                match frame {
+                    Frame::Opened { channel } => {
+                        if let Some(opened) =
+                            channels.get_mut(&channel).and_then(|slot| slot.opened.take())
+                        {
+                            let _ = opened.send(Ok(()));
+                        }
+                    }
+                    Frame::OpenFailed { channel, error } => {
+                        if let Some(opened) = channels.remove(&channel).and_then(|slot| slot.opened) {
+                            let _ = opened.send(Err(error));
+                        }
+                    }
+                    Frame::Data { channel, data } => {
+                        if let Some(slot) = channels.get(&channel)
+                            && !forward(&slot.data, channel, data, &outgoing)
+                        {
+                            channels.remove(&channel);
+                        }
+                    }
+                    Frame::Close { channel } => {
+                        channels.remove(&channel);
+                    }
+                    Frame::Ping => {
+                        let _ = outgoing.try_send(Frame::Pong);
+                    }
+                    Frame::Pong => {}
+                    other => debug!("Ignoring unexpected frame from helper: {:?}", other),
+                }
+            }
+            _ = ticker.tick() => {
+                if last_seen.elapsed() > keepalive * 3 {
+                    warn!("Helper did not answer keepalive for {:?}", last_seen.elapsed());
+                    break;
+                }
+                let _ = outgoing.try_send(Frame::Ping);
+            }
+            status = child.wait() => {
+                warn!("Helper exited: {:?}", status);
+                break;
+            }
+        }
+    }
+    alive.store(false, Ordering::SeqCst);
+    // 丢弃所有通道，正在等待的连接与转发任务随之结束
+    channels.lock().unwrap().clear();
+    let _ = child.start_kill();
+}
+
+/// A long-lived helper process shared by all `helper:` upstreams
+pub struct Helper {
+    command: String,
+    keepalive: Duration,
+    client: tokio::sync::Mutex<Option<Arc<MuxClient>>>,
+}
+
+impl Helper {
+    pub fn new(command: impl Into<String>, keepalive: Duration) -> Self {
+        Self {
+            command: command.into(),
+            keepalive,
+            client: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// 返回正在运行的 helper，必要时（首次使用或已退出）重新启动
+    async fn client(&self) -> std::io::Result<Arc<MuxClient>> {
+        let mut client = self.client.lock().await;
+        if let Some(running) = client.as_ref()
+            && running.is_alive()
+        {
+            return Ok(running.clone());
+        }
+        let started = MuxClient::start(&self.command, self.keepalive)
+            .await
+            .map_err(std::io::Error::other)?;
+        let started = Arc::new(started);
+        *client = Some(started.clone());
+        Ok(started)
+    }
+}
+
+/// 经由 helper 转发的 upstream，例如 `helper:pipe:\\.\pipe\openssh-ssh-agent`
+pub struct HelperTransport {
+    helper: Arc<Helper>,
+    upstream: String,
+}
+
+impl HelperTransport {
+    pub fn new(helper: Arc<Helper>, upstream: impl Into<String>) -> Self {
+        Self {
+            helper,
+            upstream: upstream.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for HelperTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "helper:{}", self.upstream)
+    }
+}
+
+impl UpstreamTransport for HelperTransport {
+    fn address(&self) -> Option<&str> {
+        None
+    }
+
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<BoxedStream>> {
+        Box::pin(async move { self.helper.client().await?.open(&self.upstream).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_open_releases_its_channel() {
+        let (outgoing, mut sent) = mpsc::channel(8);
+        let client = MuxClient {
+            outgoing,
+            channels: Arc::default(),
+            next_channel: AtomicU32::new(1),
+            alive: Arc::new(AtomicBool::new(true)),
+        };
+        // helper 一直不回 OPENED，调用方超时放弃
+        let open = client.open("unix:/nonexistent");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), open)
+                .await
+                .is_err()
+        );
+        assert!(client.channels.lock().unwrap().is_empty());
+        assert!(matches!(
+            sent.recv().await,
+            Some(Frame::Open { channel: 1, .. })
+        ));
+        assert!(matches!(
+            sent.recv().await,
+            Some(Frame::Close { channel: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn close_for_a_cancelled_open_waits_for_queue_space() {
+        // 写入队列只能容纳 OPEN，CLOSE 需要等到队列腾出空间
+        let (outgoing, mut sent) = mpsc::channel(1);
+        let client = MuxClient {
+            outgoing,
+            channels: Arc::default(),
+            next_channel: AtomicU32::new(1),
+            alive: Arc::new(AtomicBool::new(true)),
+        };
+        let open = client.open("unix:/nonexistent");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), open)
+                .await
+                .is_err()
+        );
+        assert!(matches!(
+            sent.recv().await,
+            Some(Frame::Open { channel: 1, .. })
+        ));
+        let close = tokio::time::timeout(Duration::from_secs(1), sent.recv()).await;
+        assert!(matches!(close, Ok(Some(Frame::Close { channel: 1 }))));
+    }
+
+    #[tokio::test]
+    async fn channel_that_stops_reading_is_closed() {
+        let (outgoing, mut sent) = mpsc::channel(8);
+        let (data, mut incoming) = mpsc::channel(CHANNEL_QUEUE);
+        for _ in 0..CHANNEL_QUEUE {
+            assert!(forward(&data, 3, vec![0; 4], &outgoing));
+        }
+        assert!(sent.try_recv().is_err());
+        assert!(!forward(&data, 3, vec![0; 4], &outgoing));
+        assert!(matches!(
+            sent.recv().await,
+            Some(Frame::Close { channel: 3 })
+        ));
+
+        // 转发任务已经结束的通道交给它自己清理
+        incoming.close();
+        while incoming.try_recv().is_ok() {}
+        assert!(forward(&data, 3, vec![0; 4], &outgoing));
+        assert!(sent.try_recv().is_err());
+    }
+}
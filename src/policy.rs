@@ -0,0 +1,395 @@
+//! Which upstream keys and agent operations are exposed to WSL
+//!
+//! The policy file is TOML with optional `[[allow]]` and `[[deny]]` tables:
+//!
+//! ```toml
+//! [[allow]]
+//! comment = "work-*"
+//!
+//! [[deny]]
+//! fingerprint = "SHA256:KC/yxO5Hpmhqswh/v+Ii8AYnLZU3SBY9lxgJE0rtDKI"
+//! ```
+//!
+//! A key is allowed when it matches no `deny` entry and, if any `allow`
+//! entries exist, at least one of them. Sign requests for keys matching a
+//! `[[confirm]]` entry are held until the user approves them.
+
+use crate::proto;
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Agent operations a WSL client may perform
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Operation {
+    /// REQUEST_IDENTITIES
+    List,
+    /// SIGN_REQUEST
+    Sign,
+    /// ADD_IDENTITY, ADD_ID_CONSTRAINED and the smartcard variants
+    Add,
+    /// REMOVE_IDENTITY and REMOVE_SMARTCARD_KEY
+    Remove,
+    /// REMOVE_ALL_IDENTITIES
+    RemoveAll,
+    /// LOCK
+    Lock,
+    /// UNLOCK
+    Unlock,
+    /// `session-bind@openssh.com`, sent by OpenSSH clients on every connection
+    SessionBind,
+    /// Any other EXTENSION
+    Extension,
+}
+
+impl Operation {
+    /// 请求对应的操作；未知类型返回 `None`，总是被拒绝
+    pub fn of_request(payload: &[u8]) -> Option<Self> {
+        match payload[0] {
+            proto::SSH_AGENTC_REQUEST_IDENTITIES => Some(Operation::List),
+            proto::SSH_AGENTC_SIGN_REQUEST => Some(Operation::Sign),
+            proto::SSH_AGENTC_ADD_IDENTITY
+            | proto::SSH_AGENTC_ADD_ID_CONSTRAINED
+            | proto::SSH_AGENTC_ADD_SMARTCARD_KEY
+            | proto::SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED => Some(Operation::Add),
+            proto::SSH_AGENTC_REMOVE_IDENTITY | proto::SSH_AGENTC_REMOVE_SMARTCARD_KEY => {
+                Some(Operation::Remove)
+            }
+            proto::SSH_AGENTC_REMOVE_ALL_IDENTITIES => Some(Operation::RemoveAll),
+            proto::SSH_AGENTC_LOCK => Some(Operation::Lock),
+            proto::SSH_AGENTC_UNLOCK => Some(Operation::Unlock),
+            proto::SSH_AGENTC_EXTENSION => {
+                let name = proto::Reader::new(&payload[1..]).read_string().ok();
+                if name == Some(proto::SESSION_BIND_EXTENSION.as_bytes()) {
+                    Some(Operation::SessionBind)
+                } else {
+                    Some(Operation::Extension)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Selects keys; every field that is set must match
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyMatcher {
+    /// SHA256 fingerprint as printed by `ssh-add -l`
+    fingerprint: Option<String>,
+    /// Key type such as `ssh-ed25519`
+    key_type: Option<String>,
+    /// Glob over the key comment (`*` and `?`)
+    comment: Option<String>,
+}
+
+impl KeyMatcher {
+    /// 注释未知时（客户端未先列出密钥），带注释条件的匹配结果取 `unknown_comment`
+    fn matches(&self, key: &KeyInfo, unknown_comment: bool) -> bool {
+        if self
+            .fingerprint
+            .as_ref()
+            .is_some_and(|fingerprint| *fingerprint != key.fingerprint)
+        {
+            return false;
+        }
+        if self
+            .key_type
+            .as_ref()
+            .is_some_and(|key_type| *key_type != key.key_type)
+        {
+            return false;
+        }
+        if let Some(pattern) = &self.comment {
+            return match &key.comment {
+                Some(comment) => glob_match(pattern, comment),
+                None => unknown_comment,
+            };
+        }
+        true
+    }
+}
+
+impl std::fmt::Display for KeyMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields = [
+            ("fingerprint", &self.fingerprint),
+            ("key_type", &self.key_type),
+            ("comment", &self.comment),
+        ];
+        let mut first = true;
+        for (name, value) in fields {
+            if let Some(value) = value {
+                write!(f, "{}{}={}", if first { "" } else { " " }, name, value)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 用于匹配的密钥属性
+#[derive(Clone, Debug)]
+pub struct KeyInfo {
+    pub fingerprint: String,
+    pub key_type: String,
+    pub comment: Option<String>,
+}
+
+impl KeyInfo {
+    pub fn new(key_blob: &[u8], comment: Option<String>) -> Self {
+        Self {
+            fingerprint: proto::fingerprint(key_blob),
+            key_type: proto::key_type(key_blob).unwrap_or_default(),
+            comment,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    allow: Vec<KeyMatcher>,
+    #[serde(default)]
+    deny: Vec<KeyMatcher>,
+    #[serde(default)]
+    confirm: Vec<KeyMatcher>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read policy '{}': {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| anyhow!("Invalid policy '{}': {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let policy: Policy = toml::from_str(text)?;
+        for matcher in policy
+            .allow
+            .iter()
+            .chain(&policy.deny)
+            .chain(&policy.confirm)
+        {
+            if matcher.fingerprint.is_none()
+                && matcher.key_type.is_none()
+                && matcher.comment.is_none()
+            {
+                return Err(anyhow!(
+                    "every allow/deny/confirm entry needs fingerprint, key_type or comment"
+                ));
+            }
+        }
+        Ok(policy)
+    }
+
+    /// 判断密钥是否对 WSL 可见、可用于签名
+    ///
+    /// Fails closed when the comment is unknown: comment globs then match
+    /// for `deny` and do not match for `allow`.
+    pub fn is_allowed(&self, key: &KeyInfo) -> bool {
+        if self.deny.iter().any(|m| m.matches(key, true)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|m| m.matches(key, false))
+    }
+
+    /// 签名前是否需要用户确认；注释未知时按需要确认处理
+    pub fn needs_confirmation(&self, key: &KeyInfo) -> bool {
+        self.confirm.iter().any(|m| m.matches(key, true))
+    }
+
+    pub fn has_confirm_rules(&self) -> bool {
+        !self.confirm.is_empty()
+    }
+
+    /// 每条规则一行，例如 `deny comment=rsa-*`，用于重新加载时比较差异
+    pub fn rules(&self) -> Vec<String> {
+        let sections = [
+            ("allow", &self.allow),
+            ("deny", &self.deny),
+            ("confirm", &self.confirm),
+        ];
+        sections
+            .into_iter()
+            .flat_map(|(section, matchers)| {
+                matchers
+                    .iter()
+                    .map(move |matcher| format!("{} {}", section, matcher))
+            })
+            .collect()
+    }
+}
+
+/// 简单的 glob 匹配，支持 `*` 与 `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // 最近一次 `*` 的位置，以及它当前吞掉的文本位置
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::AgentMessage;
+
+    fn extension(name: &str) -> Vec<u8> {
+        AgentMessage::Extension {
+            name: name.to_string(),
+            contents: vec![0, 0, 0, 0],
+        }
+        .encode()
+    }
+
+    #[test]
+    fn session_bind_is_its_own_operation() {
+        assert_eq!(
+            Operation::of_request(&extension(proto::SESSION_BIND_EXTENSION)),
+            Some(Operation::SessionBind)
+        );
+        assert_eq!(
+            Operation::of_request(&extension("query")),
+            Some(Operation::Extension)
+        );
+        assert_eq!(
+            Operation::of_request(&[proto::SSH_AGENTC_EXTENSION]),
+            Some(Operation::Extension)
+        );
+        assert_eq!(Operation::of_request(&[99]), None);
+    }
+
+    fn key(seed: u8, comment: Option<&str>) -> KeyInfo {
+        KeyInfo::new(&crate::testing::key_blob(seed), comment.map(str::to_string))
+    }
+
+    fn allowed(policy: &str, keys: &[KeyInfo]) -> Vec<bool> {
+        let policy = Policy::parse(policy).unwrap();
+        keys.iter().map(|key| policy.is_allowed(key)).collect()
+    }
+
+    #[test]
+    fn globs_match_any_run_or_a_single_character() {
+        for (pattern, text) in [
+            ("work-*", "work-laptop"),
+            ("work-*", "work-"),
+            ("*", ""),
+            ("*@host", "me@host"),
+            ("a*b*c", "axxbyyc"),
+            ("a*bc", "abcbc"),
+            ("key?", "key1"),
+            ("密钥?", "密钥1"),
+            ("??-*-?", "ab-x-y-z"),
+        ] {
+            assert!(glob_match(pattern, text), "{pattern} ~ {text}");
+        }
+        for (pattern, text) in [
+            ("work-*", "home-work-laptop"),
+            ("key?", "key"),
+            ("key?", "key12"),
+            ("*@host", "me@host2"),
+            ("a*bc", "abcb"),
+            ("exact", "exact "),
+        ] {
+            assert!(!glob_match(pattern, text), "{pattern} !~ {text}");
+        }
+    }
+
+    #[test]
+    fn fingerprint_and_key_type_must_both_match() {
+        let keys = [key(1, Some("one")), key(2, Some("two"))];
+        let fingerprint = &keys[0].fingerprint;
+        let by_fingerprint = format!("[[allow]]\nfingerprint = \"{}\"\n", fingerprint);
+        assert_eq!(allowed(&by_fingerprint, &keys), [true, false]);
+        assert_eq!(
+            allowed("[[allow]]\nkey_type = \"ssh-ed25519\"\n", &keys),
+            [true, true]
+        );
+        assert_eq!(
+            allowed("[[allow]]\nkey_type = \"ssh-rsa\"\n", &keys),
+            [false, false]
+        );
+        let both = format!("{}key_type = \"ssh-rsa\"\n", by_fingerprint);
+        assert_eq!(allowed(&both, &keys), [false, false]);
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let keys = [key(1, Some("work-a")), key(2, Some("work-b"))];
+        let policy = format!(
+            "[[allow]]\ncomment = \"work-*\"\n[[deny]]\nfingerprint = \"{}\"\n",
+            keys[1].fingerprint
+        );
+        assert_eq!(allowed(&policy, &keys), [true, false]);
+        assert_eq!(allowed("", &keys), [true, true]);
+    }
+
+    #[test]
+    fn allow_list_matching_nothing_hides_every_key() {
+        let keys = [key(1, Some("one")), key(2, None)];
+        assert_eq!(
+            allowed("[[allow]]\ncomment = \"nothing-*\"\n", &keys),
+            [false, false]
+        );
+    }
+
+    #[test]
+    fn unknown_comments_fail_closed() {
+        let unknown = [key(1, None)];
+        assert_eq!(allowed("[[allow]]\ncomment = \"*\"\n", &unknown), [false]);
+        assert_eq!(
+            allowed("[[deny]]\ncomment = \"rsa-*\"\n", &unknown),
+            [false]
+        );
+        let confirm = Policy::parse("[[confirm]]\ncomment = \"work-*\"\n").unwrap();
+        assert!(confirm.needs_confirmation(&unknown[0]));
+        assert!(!confirm.needs_confirmation(&key(1, Some("home"))));
+        assert!(confirm.needs_confirmation(&key(1, Some("work-a"))));
+    }
+
+    #[test]
+    fn malformed_policies_are_rejected() {
+        for (text, message) in [
+            ("[[allow]]\n", "needs fingerprint, key_type or comment"),
+            ("[[confirm]]\n", "needs fingerprint, key_type or comment"),
+            ("[[allow]]\ncomments = \"x\"\n", "unknown field `comments`"),
+            ("[[allwo]]\ncomment = \"x\"\n", "unknown field `allwo`"),
+            ("[[deny]]\ncomment = 1\n", "invalid type"),
+            ("[allow]\ncomment = \"x\"\n", "invalid type"),
+            ("[[deny]\n", "TOML parse error"),
+        ] {
+            let error = Policy::parse(text).err().unwrap().to_string();
+            assert!(error.contains(message), "{text:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn rules_list_one_line_per_entry() {
+        let policy = Policy::parse(
+            "[[deny]]\ncomment = \"rsa-*\"\nkey_type = \"ssh-rsa\"\n[[allow]]\ncomment = \"*\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            policy.rules(),
+            ["allow comment=*", "deny key_type=ssh-rsa comment=rsa-*"]
+        );
+    }
+}
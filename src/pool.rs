@@ -0,0 +1,222 @@
+//! Bounded pool of upstream connections shared by every client of `serve`
+//!
+//! A client borrows a connection for exactly one request/response exchange
+//! and returns it afterwards, so a handful of pipe connections can serve many
+//! clients. Idle connections are checked before reuse: one the upstream has
+//! closed (or that carries unsolicited data) is discarded, as is one idle for
+//! longer than the idle timeout. A client whose session depends on
+//! per-connection state can pin its connection instead. A pinned connection
+//! gives its slot back and no longer counts toward the pool size, so clients
+//! pinned for their whole session cannot starve the others.
+
+use crate::codec::SshAgentMessage;
+use crate::proto::{self, AgentMessage};
+use crate::transport::UpstreamTransport;
+use crate::upstream::Connection;
+use log::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// When a client keeps its pooled connection for the rest of its session
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PinMode {
+    /// Always return the connection after each request
+    Never,
+    /// Pin after LOCK, UNLOCK or a session-bind@openssh.com extension
+    Stateful,
+    /// Pin on the first request
+    Always,
+}
+
+impl PinMode {
+    /// 该请求之后是否需要固定使用同一条连接
+    pub fn pins(self, msg: &SshAgentMessage) -> bool {
+        match self {
+            PinMode::Never => false,
+            PinMode::Always => true,
+            PinMode::Stateful => match msg.parse() {
+                Ok(AgentMessage::Lock { .. } | AgentMessage::Unlock { .. }) => true,
+                Ok(AgentMessage::Extension { name, .. }) => name == proto::SESSION_BIND_EXTENSION,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// 连接池的累计计数
+#[derive(Default)]
+struct Counters {
+    borrows: AtomicU64,
+    reused: AtomicU64,
+    opened: AtomicU64,
+    discarded: AtomicU64,
+    waits: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+/// A snapshot of the pool counters
+#[derive(Clone, Debug, Default)]
+pub struct PoolStats {
+    pub size: usize,
+    pub idle: usize,
+    pub in_use: usize,
+    pub borrows: u64,
+    pub reused: u64,
+    pub opened: u64,
+    pub discarded: u64,
+    pub waits: u64,
+    pub wait_time: Duration,
+    pub max_wait: Duration,
+}
+
+impl std::fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} in use, {} idle, {} borrows ({} reused), {} opened, {} discarded, \
+             {} waits (total {:?}, max {:?})",
+            self.in_use,
+            self.size,
+            self.idle,
+            self.borrows,
+            self.reused,
+            self.opened,
+            self.discarded,
+            self.waits,
+            self.wait_time,
+            self.max_wait
+        )
+    }
+}
+
+/// 到一个 upstream 的连接池
+pub struct ConnectionPool {
+    transport: Arc<dyn UpstreamTransport>,
+    size: usize,
+    idle_timeout: Option<Duration>,
+    // 每条借出的连接占用一个名额；空闲连接只来自归还，总数不超过池大小
+    slots: Arc<Semaphore>,
+    idle: Mutex<Vec<(Instant, Connection)>>,
+    counters: Counters,
+}
+
+impl ConnectionPool {
+    pub fn new(
+        transport: Arc<dyn UpstreamTransport>,
+        size: usize,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            transport,
+            size,
+            idle_timeout,
+            slots: Arc::new(Semaphore::new(size)),
+            idle: Mutex::new(Vec::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn transport(&self) -> &Arc<dyn UpstreamTransport> {
+        &self.transport
+    }
+
+    /// 等待一个空闲名额，并取出一条健康的空闲连接（如果有）
+    ///
+    /// 返回 `None` 时调用方需要自行建立新连接，并把名额交给它。
+    pub async fn acquire(&self) -> (OwnedSemaphorePermit, Option<Connection>) {
+        self.counters.borrows.fetch_add(1, Ordering::Relaxed);
+        let permit = match self.slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let started = Instant::now();
+                debug!("Pool for {} exhausted, waiting", self.transport);
+                let permit = self
+                    .slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("pool semaphore is never closed");
+                self.record_wait(started.elapsed());
+                permit
+            }
+        };
+        let connection = self.take_idle();
+        match &connection {
+            Some(_) => self.counters.reused.fetch_add(1, Ordering::Relaxed),
+            None => self.counters.opened.fetch_add(1, Ordering::Relaxed),
+        };
+        (permit, connection)
+    }
+
+    /// 为已固定连接的客户端取一条空闲连接（如果有），不占用名额
+    pub fn take_pinned(&self) -> Option<Connection> {
+        self.counters.borrows.fetch_add(1, Ordering::Relaxed);
+        let connection = self.take_idle();
+        match &connection {
+            Some(_) => self.counters.reused.fetch_add(1, Ordering::Relaxed),
+            None => self.counters.opened.fetch_add(1, Ordering::Relaxed),
+        };
+        connection
+    }
+
+    /// 取出最近归还的健康连接，丢弃已断开或空闲过久的连接
+    fn take_idle(&self) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some((returned, mut connection)) = idle.pop() {
+            let expired = self
+                .idle_timeout
+                .is_some_and(|timeout| returned.elapsed() >= timeout);
+            if expired || !connection.is_healthy() {
+                debug!(
+                    "Discarding {} pooled connection to {}",
+                    if expired { "expired" } else { "broken" },
+                    self.transport
+                );
+                self.counters.discarded.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            return Some(connection);
+        }
+        None
+    }
+
+    /// 归还连接；先放回空闲列表再释放名额，等待者总能取到它
+    pub fn release(&self, connection: Connection, permit: OwnedSemaphorePermit) {
+        self.idle.lock().unwrap().push((Instant::now(), connection));
+        drop(permit);
+    }
+
+    fn record_wait(&self, waited: Duration) {
+        let micros = u64::try_from(waited.as_micros()).unwrap_or(u64::MAX);
+        self.counters.waits.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .wait_micros
+            .fetch_add(micros, Ordering::Relaxed);
+        self.counters
+            .max_wait_micros
+            .fetch_max(micros, Ordering::Relaxed);
+        debug!(
+            "Waited {:?} for a pooled connection to {}",
+            waited, self.transport
+        );
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let counters = &self.counters;
+        PoolStats {
+            size: self.size,
+            idle: self.idle.lock().unwrap().len(),
+            in_use: self.size - self.slots.available_permits(),
+            borrows: counters.borrows.load(Ordering::Relaxed),
+            reused: counters.reused.load(Ordering::Relaxed),
+            opened: counters.opened.load(Ordering::Relaxed),
+            discarded: counters.discarded.load(Ordering::Relaxed),
+            waits: counters.waits.load(Ordering::Relaxed),
+            wait_time: Duration::from_micros(counters.wait_micros.load(Ordering::Relaxed)),
+            max_wait: Duration::from_micros(counters.max_wait_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -0,0 +1,872 @@
+//! SSH agent protocol messages (draft-miller-ssh-agent)
+//!
+//! [`SshAgentMessage`](crate::codec::SshAgentMessage) only carries raw frames;
+//! this module gives their payloads a typed shape and encodes them back
+//! byte-for-byte.
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+pub const SSH_AGENT_FAILURE: u8 = 5;
+pub const SSH_AGENT_SUCCESS: u8 = 6;
+pub const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+pub const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+pub const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+pub const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+pub const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+pub const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+pub const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
+pub const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
+pub const SSH_AGENTC_LOCK: u8 = 22;
+pub const SSH_AGENTC_UNLOCK: u8 = 23;
+pub const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED: u8 = 26;
+pub const SSH_AGENTC_EXTENSION: u8 = 27;
+pub const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
+/// OpenSSH 客户端连接 agent 后发送的扩展，把连接绑定到服务器 host key
+pub const SESSION_BIND_EXTENSION: &str = "session-bind@openssh.com";
+
+pub const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+pub const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+pub const SSH_AGENT_CONSTRAIN_EXTENSION: u8 = 255;
+
+/// 消息类型的协议名称，用于日志
+pub fn message_name(msg_type: u8) -> &'static str {
+    match msg_type {
+        SSH_AGENT_FAILURE => "FAILURE",
+        SSH_AGENT_SUCCESS => "SUCCESS",
+        SSH_AGENTC_REQUEST_IDENTITIES => "REQUEST_IDENTITIES",
+        SSH_AGENT_IDENTITIES_ANSWER => "IDENTITIES_ANSWER",
+        SSH_AGENTC_SIGN_REQUEST => "SIGN_REQUEST",
+        SSH_AGENT_SIGN_RESPONSE => "SIGN_RESPONSE",
+        SSH_AGENTC_ADD_IDENTITY => "ADD_IDENTITY",
+        SSH_AGENTC_REMOVE_IDENTITY => "REMOVE_IDENTITY",
+        SSH_AGENTC_REMOVE_ALL_IDENTITIES => "REMOVE_ALL_IDENTITIES",
+        SSH_AGENTC_ADD_SMARTCARD_KEY => "ADD_SMARTCARD_KEY",
+        SSH_AGENTC_REMOVE_SMARTCARD_KEY => "REMOVE_SMARTCARD_KEY",
+        SSH_AGENTC_LOCK => "LOCK",
+        SSH_AGENTC_UNLOCK => "UNLOCK",
+        SSH_AGENTC_ADD_ID_CONSTRAINED => "ADD_ID_CONSTRAINED",
+        SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED => "ADD_SMARTCARD_KEY_CONSTRAINED",
+        SSH_AGENTC_EXTENSION => "EXTENSION",
+        SSH_AGENT_EXTENSION_FAILURE => "EXTENSION_FAILURE",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Cursor over SSH wire encoded data (RFC 4251 section 5)
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// 剩余未读取的字节
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(anyhow!(
+                "Truncated message: need {} bytes, {} left",
+                n,
+                self.buf.len()
+            ));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn read_string(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn read_utf8(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.read_string()?.to_vec())?)
+    }
+
+    /// 非 UTF-8 的字节替换为 U+FFFD，用于仅供显示的文本（如密钥注释）
+    pub fn read_utf8_lossy(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(self.read_string()?).into_owned())
+    }
+
+    /// mpint 在线上与 string 编码相同，这里保留原始的二进制补码表示
+    pub fn read_mpint(&mut self) -> Result<&'a [u8]> {
+        self.read_string()
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("{} trailing bytes in message", self.buf.len()))
+        }
+    }
+}
+
+/// Builder for SSH wire encoded data
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn write_string(&mut self, value: &[u8]) -> &mut Self {
+        self.write_u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn write_raw(&mut self, value: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// 公钥 blob 中编码的密钥类型，例如 `ssh-ed25519`
+pub fn key_type(key_blob: &[u8]) -> Result<String> {
+    Reader::new(key_blob).read_utf8()
+}
+
+/// OpenSSH 风格的公钥指纹，例如 `SHA256:KC/yx...`
+pub fn fingerprint(key_blob: &[u8]) -> String {
+    use base64::Engine;
+    use sha2::Digest;
+    let digest = sha2::Sha256::digest(key_blob);
+    format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+    )
+}
+
+const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+const SSHSIG_MAGIC: &[u8] = b"SSHSIG";
+
+/// What the data of a SIGN_REQUEST is about to authorize
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "purpose", rename_all = "lowercase")]
+pub enum SignedData {
+    /// SSH_MSG_USERAUTH_REQUEST for public key authentication (RFC 4252 section 7)
+    #[serde(rename = "userauth")]
+    UserAuth {
+        user: String,
+        service: String,
+        /// `publickey` or `publickey-hostbound-v00@openssh.com`
+        method: String,
+        algorithm: String,
+        #[serde(skip)]
+        key_blob: Vec<u8>,
+        /// 仅 hostbound 方式携带服务器 host key
+        #[serde(skip)]
+        host_key: Option<Vec<u8>>,
+    },
+    /// `ssh-keygen -Y sign` / git commit signing (OpenSSH PROTOCOL.sshsig)
+    #[serde(rename = "sshsig")]
+    SshSig {
+        namespace: String,
+        hash_algorithm: String,
+    },
+    Unknown,
+}
+
+impl SignedData {
+    pub fn parse(data: &[u8]) -> Self {
+        if let Some(rest) = data.strip_prefix(SSHSIG_MAGIC) {
+            return Self::parse_sshsig(rest).unwrap_or(SignedData::Unknown);
+        }
+        Self::parse_userauth(data).unwrap_or(SignedData::Unknown)
+    }
+
+    fn parse_userauth(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+        // session identifier
+        reader.read_string()?;
+        if reader.read_u8()? != SSH_MSG_USERAUTH_REQUEST {
+            return Err(anyhow!("Not a USERAUTH_REQUEST"));
+        }
+        let user = reader.read_utf8()?;
+        let service = reader.read_utf8()?;
+        let method = reader.read_utf8()?;
+        if !method.starts_with("publickey") {
+            return Err(anyhow!("Unexpected userauth method '{}'", method));
+        }
+        // has signature
+        reader.read_u8()?;
+        let algorithm = reader.read_utf8()?;
+        let key_blob = reader.read_string()?.to_vec();
+        let host_key = if reader.is_empty() {
+            None
+        } else {
+            Some(reader.read_string()?.to_vec())
+        };
+        reader.finish()?;
+        Ok(SignedData::UserAuth {
+            user,
+            service,
+            method,
+            algorithm,
+            key_blob,
+            host_key,
+        })
+    }
+
+    fn parse_sshsig(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+        let namespace = reader.read_utf8()?;
+        // reserved
+        reader.read_string()?;
+        let hash_algorithm = reader.read_utf8()?;
+        // H(message)
+        reader.read_string()?;
+        reader.finish()?;
+        Ok(SignedData::SshSig {
+            namespace,
+            hash_algorithm,
+        })
+    }
+}
+
+impl std::fmt::Display for SignedData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignedData::UserAuth {
+                user,
+                service,
+                algorithm,
+                host_key,
+                ..
+            } => {
+                write!(f, "login as {} ({}) using {}", user, service, algorithm)?;
+                if let Some(host_key) = host_key {
+                    write!(f, " to host {}", fingerprint(host_key))?;
+                }
+                Ok(())
+            }
+            SignedData::SshSig {
+                namespace,
+                hash_algorithm,
+            } => write!(
+                f,
+                "sshsig signature in namespace '{}' ({})",
+                namespace, hash_algorithm
+            ),
+            SignedData::Unknown => write!(f, "unrecognized data"),
+        }
+    }
+}
+
+/// One entry of an IDENTITIES_ANSWER
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub key_blob: Vec<u8>,
+    pub comment: String,
+}
+
+/// Private key material carried by ADD_IDENTITY
+///
+/// Only the key type is interpreted; the type-specific fields are kept as
+/// raw wire bytes so the message re-encodes exactly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivateKey {
+    pub key_type: String,
+    pub fields: Vec<u8>,
+}
+
+impl PrivateKey {
+    fn read(reader: &mut Reader) -> Result<Self> {
+        let key_type = reader.read_utf8()?;
+        let start = reader.buf;
+        skip_private_key_fields(reader, &key_type)?;
+        let consumed = start.len() - reader.buf.len();
+        Ok(Self {
+            key_type,
+            fields: start[..consumed].to_vec(),
+        })
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer
+            .write_string(self.key_type.as_bytes())
+            .write_raw(&self.fields);
+    }
+}
+
+/// 按密钥类型跳过私钥字段（参见 draft-miller-ssh-agent 4.2.x 各节）
+fn skip_private_key_fields(reader: &mut Reader, key_type: &str) -> Result<()> {
+    let (strings, mpints) = match key_type {
+        // n, e, d, iqmp, p, q
+        "ssh-rsa" => (0, 6),
+        // p, q, g, y, x
+        "ssh-dss" => (0, 5),
+        // certificate, d
+        t if t.starts_with("ecdsa-sha2-") && t.ends_with("-cert-v01@openssh.com") => (1, 1),
+        // curve name, Q, d
+        t if t.starts_with("ecdsa-sha2-") => (2, 1),
+        // ENC(A), k || ENC(A)
+        "ssh-ed25519" => (2, 0),
+        // certificate, d, iqmp, p, q
+        "ssh-rsa-cert-v01@openssh.com" => (1, 4),
+        // certificate, x
+        "ssh-dss-cert-v01@openssh.com" => (1, 1),
+        // certificate, ENC(A), k || ENC(A)
+        "ssh-ed25519-cert-v01@openssh.com" => (3, 0),
+        // curve name, Q, application, flags, key handle, reserved
+        "sk-ecdsa-sha2-nistp256@openssh.com" => {
+            reader.read_string()?;
+            reader.read_string()?;
+            return skip_security_key_fields(reader);
+        }
+        // ENC(A), application, flags, key handle, reserved
+        "sk-ssh-ed25519@openssh.com" => {
+            reader.read_string()?;
+            return skip_security_key_fields(reader);
+        }
+        _ => return Err(anyhow!("Unsupported private key type '{}'", key_type)),
+    };
+    for _ in 0..strings {
+        reader.read_string()?;
+    }
+    for _ in 0..mpints {
+        reader.read_mpint()?;
+    }
+    Ok(())
+}
+
+fn skip_security_key_fields(reader: &mut Reader) -> Result<()> {
+    reader.read_string()?; // application
+    reader.read_u8()?; // flags
+    reader.read_string()?; // key handle
+    reader.read_string()?; // reserved
+    Ok(())
+}
+
+/// Key constraint attached by ADD_ID_CONSTRAINED
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constraint {
+    Lifetime(u32),
+    Confirm,
+    /// Extension details are opaque and run to the end of the message
+    Extension {
+        name: String,
+        details: Vec<u8>,
+    },
+}
+
+impl Constraint {
+    fn read_all(reader: &mut Reader) -> Result<Vec<Self>> {
+        let mut constraints = Vec::new();
+        while !reader.is_empty() {
+            let constraint = match reader.read_u8()? {
+                SSH_AGENT_CONSTRAIN_LIFETIME => Constraint::Lifetime(reader.read_u32()?),
+                SSH_AGENT_CONSTRAIN_CONFIRM => Constraint::Confirm,
+                SSH_AGENT_CONSTRAIN_EXTENSION => Constraint::Extension {
+                    name: reader.read_utf8()?,
+                    details: reader.rest().to_vec(),
+                },
+                other => return Err(anyhow!("Unknown key constraint {}", other)),
+            };
+            constraints.push(constraint);
+        }
+        Ok(constraints)
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        match self {
+            Constraint::Lifetime(seconds) => {
+                writer
+                    .write_u8(SSH_AGENT_CONSTRAIN_LIFETIME)
+                    .write_u32(*seconds);
+            }
+            Constraint::Confirm => {
+                writer.write_u8(SSH_AGENT_CONSTRAIN_CONFIRM);
+            }
+            Constraint::Extension { name, details } => {
+                writer
+                    .write_u8(SSH_AGENT_CONSTRAIN_EXTENSION)
+                    .write_string(name.as_bytes())
+                    .write_raw(details);
+            }
+        }
+    }
+}
+
+/// A decoded SSH agent message payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentMessage {
+    Failure,
+    Success,
+    RequestIdentities,
+    IdentitiesAnswer(Vec<Identity>),
+    SignRequest {
+        key_blob: Vec<u8>,
+        data: Vec<u8>,
+        flags: u32,
+    },
+    SignResponse {
+        signature: Vec<u8>,
+    },
+    AddIdentity {
+        key: PrivateKey,
+        comment: String,
+    },
+    AddIdConstrained {
+        key: PrivateKey,
+        comment: String,
+        constraints: Vec<Constraint>,
+    },
+    RemoveIdentity {
+        key_blob: Vec<u8>,
+    },
+    RemoveAllIdentities,
+    Lock {
+        passphrase: Vec<u8>,
+    },
+    Unlock {
+        passphrase: Vec<u8>,
+    },
+    Extension {
+        name: String,
+        contents: Vec<u8>,
+    },
+    ExtensionFailure,
+    /// Message types this module does not model (e.g. smartcard operations)
+    Unknown {
+        msg_type: u8,
+        contents: Vec<u8>,
+    },
+}
+
+impl AgentMessage {
+    /// 解析一个完整的消息负载（不含长度头）
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(payload);
+        let msg_type = reader.read_u8()?;
+        let message = match msg_type {
+            SSH_AGENT_FAILURE => AgentMessage::Failure,
+            SSH_AGENT_SUCCESS => AgentMessage::Success,
+            SSH_AGENTC_REQUEST_IDENTITIES => AgentMessage::RequestIdentities,
+            SSH_AGENT_IDENTITIES_ANSWER => {
+                let count = reader.read_u32()?;
+                let mut identities = Vec::new();
+                for _ in 0..count {
+                    identities.push(Identity {
+                        key_blob: reader.read_string()?.to_vec(),
+                        // 一个注释编码有误不应让整个列表失效
+                        comment: reader.read_utf8_lossy()?,
+                    });
+                }
+                AgentMessage::IdentitiesAnswer(identities)
+            }
+            SSH_AGENTC_SIGN_REQUEST => AgentMessage::SignRequest {
+                key_blob: reader.read_string()?.to_vec(),
+                data: reader.read_string()?.to_vec(),
+                flags: reader.read_u32()?,
+            },
+            SSH_AGENT_SIGN_RESPONSE => AgentMessage::SignResponse {
+                signature: reader.read_string()?.to_vec(),
+            },
+            SSH_AGENTC_ADD_IDENTITY => AgentMessage::AddIdentity {
+                key: PrivateKey::read(&mut reader)?,
+                comment: reader.read_utf8()?,
+            },
+            SSH_AGENTC_ADD_ID_CONSTRAINED => AgentMessage::AddIdConstrained {
+                key: PrivateKey::read(&mut reader)?,
+                comment: reader.read_utf8()?,
+                constraints: Constraint::read_all(&mut reader)?,
+            },
+            SSH_AGENTC_REMOVE_IDENTITY => AgentMessage::RemoveIdentity {
+                key_blob: reader.read_string()?.to_vec(),
+            },
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => AgentMessage::RemoveAllIdentities,
+            SSH_AGENTC_LOCK => AgentMessage::Lock {
+                passphrase: reader.read_string()?.to_vec(),
+            },
+            SSH_AGENTC_UNLOCK => AgentMessage::Unlock {
+                passphrase: reader.read_string()?.to_vec(),
+            },
+            SSH_AGENTC_EXTENSION => AgentMessage::Extension {
+                name: reader.read_utf8()?,
+                contents: reader.rest().to_vec(),
+            },
+            SSH_AGENT_EXTENSION_FAILURE => AgentMessage::ExtensionFailure,
+            _ => AgentMessage::Unknown {
+                msg_type,
+                contents: reader.rest().to_vec(),
+            },
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            AgentMessage::Failure => SSH_AGENT_FAILURE,
+            AgentMessage::Success => SSH_AGENT_SUCCESS,
+            AgentMessage::RequestIdentities => SSH_AGENTC_REQUEST_IDENTITIES,
+            AgentMessage::IdentitiesAnswer(_) => SSH_AGENT_IDENTITIES_ANSWER,
+            AgentMessage::SignRequest { .. } => SSH_AGENTC_SIGN_REQUEST,
+            AgentMessage::SignResponse { .. } => SSH_AGENT_SIGN_RESPONSE,
+            AgentMessage::AddIdentity { .. } => SSH_AGENTC_ADD_IDENTITY,
+            AgentMessage::AddIdConstrained { .. } => SSH_AGENTC_ADD_ID_CONSTRAINED,
+            AgentMessage::RemoveIdentity { .. } => SSH_AGENTC_REMOVE_IDENTITY,
+            AgentMessage::RemoveAllIdentities => SSH_AGENTC_REMOVE_ALL_IDENTITIES,
+            AgentMessage::Lock { .. } => SSH_AGENTC_LOCK,
+            AgentMessage::Unlock { .. } => SSH_AGENTC_UNLOCK,
+            AgentMessage::Extension { .. } => SSH_AGENTC_EXTENSION,
+            AgentMessage::ExtensionFailure => SSH_AGENT_EXTENSION_FAILURE,
+            AgentMessage::Unknown { msg_type, .. } => *msg_type,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        message_name(self.msg_type())
+    }
+
+    /// 编码为消息负载（不含长度头）
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_u8(self.msg_type());
+        match self {
+            AgentMessage::Failure
+            | AgentMessage::Success
+            | AgentMessage::RequestIdentities
+            | AgentMessage::RemoveAllIdentities
+            | AgentMessage::ExtensionFailure => {}
+            AgentMessage::IdentitiesAnswer(identities) => {
+                writer.write_u32(identities.len() as u32);
+                for identity in identities {
+                    writer
+                        .write_string(&identity.key_blob)
+                        .write_string(identity.comment.as_bytes());
+                }
+            }
+            AgentMessage::SignRequest {
+                key_blob,
+                data,
+                flags,
+            } => {
+                writer
+                    .write_string(key_blob)
+                    .write_string(data)
+                    .write_u32(*flags);
+            }
+            AgentMessage::SignResponse { signature } => {
+                writer.write_string(signature);
+            }
+            AgentMessage::AddIdentity { key, comment } => {
+                key.write(&mut writer);
+                writer.write_string(comment.as_bytes());
+            }
+            AgentMessage::AddIdConstrained {
+                key,
+                comment,
+                constraints,
+            } => {
+                key.write(&mut writer);
+                writer.write_string(comment.as_bytes());
+                for constraint in constraints {
+                    constraint.write(&mut writer);
+                }
+            }
+            AgentMessage::RemoveIdentity { key_blob } => {
+                writer.write_string(key_blob);
+            }
+            AgentMessage::Lock { passphrase } | AgentMessage::Unlock { passphrase } => {
+                writer.write_string(passphrase);
+            }
+            AgentMessage::Extension { name, contents } => {
+                writer.write_string(name.as_bytes()).write_raw(contents);
+            }
+            AgentMessage::Unknown { contents, .. } => {
+                writer.write_raw(contents);
+            }
+        }
+        writer.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_blob() -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_string(b"ssh-ed25519").write_string(&[7; 32]);
+        writer.into_bytes()
+    }
+
+    fn private_key() -> PrivateKey {
+        let mut fields = Writer::new();
+        fields.write_string(&[7; 32]).write_string(&[9; 64]);
+        PrivateKey {
+            key_type: "ssh-ed25519".to_string(),
+            fields: fields.into_bytes(),
+        }
+    }
+
+    /// 每种消息各一个，包括所有字段都有内容的情况
+    fn messages() -> Vec<AgentMessage> {
+        vec![
+            AgentMessage::Failure,
+            AgentMessage::Success,
+            AgentMessage::RequestIdentities,
+            AgentMessage::IdentitiesAnswer(vec![
+                Identity {
+                    key_blob: key_blob(),
+                    comment: "test-key".to_string(),
+                },
+                Identity {
+                    key_blob: key_blob(),
+                    comment: String::new(),
+                },
+            ]),
+            AgentMessage::SignRequest {
+                key_blob: key_blob(),
+                data: b"data".to_vec(),
+                flags: 4,
+            },
+            AgentMessage::SignResponse {
+                signature: b"signature".to_vec(),
+            },
+            AgentMessage::AddIdentity {
+                key: private_key(),
+                comment: "test-key".to_string(),
+            },
+            AgentMessage::AddIdConstrained {
+                key: private_key(),
+                comment: "test-key".to_string(),
+                constraints: vec![
+                    Constraint::Lifetime(60),
+                    Constraint::Confirm,
+                    Constraint::Extension {
+                        name: "restrict-destination-v00@openssh.com".to_string(),
+                        details: b"details".to_vec(),
+                    },
+                ],
+            },
+            AgentMessage::RemoveIdentity {
+                key_blob: key_blob(),
+            },
+            AgentMessage::RemoveAllIdentities,
+            AgentMessage::Lock {
+                passphrase: b"secret".to_vec(),
+            },
+            AgentMessage::Unlock {
+                passphrase: b"secret".to_vec(),
+            },
+            AgentMessage::Extension {
+                name: SESSION_BIND_EXTENSION.to_string(),
+                contents: b"contents".to_vec(),
+            },
+            AgentMessage::ExtensionFailure,
+            AgentMessage::Unknown {
+                msg_type: SSH_AGENTC_ADD_SMARTCARD_KEY,
+                contents: b"contents".to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in messages() {
+            let payload = message.encode();
+            assert_eq!(payload[0], message.msg_type());
+            assert_eq!(AgentMessage::parse(&payload).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        assert!(AgentMessage::parse(&[]).is_err());
+        for message in messages() {
+            let payload = message.encode();
+            if payload.len() == 1 {
+                continue;
+            }
+            let truncated = AgentMessage::parse(&payload[..payload.len() - 1]);
+            match message {
+                // 最后一个字段一直延续到消息末尾，截断后仍可解析，但不能与原消息相同
+                AgentMessage::Extension { .. }
+                | AgentMessage::AddIdConstrained { .. }
+                | AgentMessage::Unknown { .. } => assert_ne!(truncated.ok(), Some(message)),
+                _ => assert!(truncated.is_err(), "{} accepted truncated", message.name()),
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for message in messages() {
+            // 这些消息的最后一个字段一直延续到消息末尾
+            if matches!(
+                message,
+                AgentMessage::Extension { .. }
+                    | AgentMessage::AddIdConstrained { .. }
+                    | AgentMessage::Unknown { .. }
+            ) {
+                continue;
+            }
+            let mut payload = message.encode();
+            payload.push(0);
+            assert!(
+                AgentMessage::parse(&payload).is_err(),
+                "{} accepted trailing bytes",
+                message.name()
+            );
+        }
+    }
+
+    fn userauth(method: &str, host_key: Option<&[u8]>) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer
+            .write_string(&[1; 32])
+            .write_u8(SSH_MSG_USERAUTH_REQUEST)
+            .write_string(b"git")
+            .write_string(b"ssh-connection")
+            .write_string(method.as_bytes())
+            .write_u8(1)
+            .write_string(b"ssh-ed25519")
+            .write_string(&key_blob());
+        if let Some(host_key) = host_key {
+            writer.write_string(host_key);
+        }
+        writer.into_bytes()
+    }
+
+    fn sshsig() -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer
+            .write_raw(SSHSIG_MAGIC)
+            .write_string(b"git")
+            .write_string(b"")
+            .write_string(b"sha512")
+            .write_string(&[3; 64]);
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn parses_userauth() {
+        assert_eq!(
+            SignedData::parse(&userauth("publickey", None)),
+            SignedData::UserAuth {
+                user: "git".to_string(),
+                service: "ssh-connection".to_string(),
+                method: "publickey".to_string(),
+                algorithm: "ssh-ed25519".to_string(),
+                key_blob: key_blob(),
+                host_key: None,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_comment_keeps_the_identity() {
+        let mut writer = Writer::new();
+        writer
+            .write_u8(SSH_AGENT_IDENTITIES_ANSWER)
+            .write_u32(1)
+            .write_string(&key_blob())
+            .write_string(b"work\xff");
+        let AgentMessage::IdentitiesAnswer(identities) =
+            AgentMessage::parse(&writer.into_bytes()).unwrap()
+        else {
+            panic!("not parsed as IDENTITIES_ANSWER");
+        };
+        assert_eq!(identities[0].key_blob, key_blob());
+        assert_eq!(identities[0].comment, "work\u{fffd}");
+    }
+
+    #[test]
+    fn describes_userauth() {
+        let data = SignedData::parse(&userauth("publickey", None));
+        assert_eq!(
+            data.to_string(),
+            "login as git (ssh-connection) using ssh-ed25519"
+        );
+    }
+
+    #[test]
+    fn parses_hostbound_userauth() {
+        let method = "publickey-hostbound-v00@openssh.com";
+        let data = userauth(method, Some(b"host-key"));
+        let SignedData::UserAuth {
+            method: parsed,
+            host_key,
+            ..
+        } = SignedData::parse(&data)
+        else {
+            panic!("not parsed as userauth");
+        };
+        assert_eq!(parsed, method);
+        assert_eq!(host_key.as_deref(), Some(&b"host-key"[..]));
+    }
+
+    #[test]
+    fn parses_sshsig() {
+        assert_eq!(
+            SignedData::parse(&sshsig()),
+            SignedData::SshSig {
+                namespace: "git".to_string(),
+                hash_algorithm: "sha512".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_signed_data_is_unknown() {
+        let hostbound = userauth("publickey-hostbound-v00@openssh.com", Some(b"host-key"));
+        let mut trailing = hostbound.clone();
+        trailing.push(0);
+        let mut sshsig_trailing = sshsig();
+        sshsig_trailing.push(0);
+        let sshsig = sshsig();
+        for data in [
+            &userauth("password", None)[..],
+            &hostbound[..hostbound.len() - 1],
+            &trailing,
+            &sshsig[..sshsig.len() - 1],
+            &sshsig_trailing,
+            b"",
+        ] {
+            assert_eq!(SignedData::parse(data), SignedData::Unknown);
+        }
+    }
+}
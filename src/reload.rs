@@ -0,0 +1,224 @@
+//! Reloading the bridge settings of a running `serve` process
+//!
+//! A reload is triggered by SIGHUP, by a change to one of the watched files
+//! (the config file and the policy file) or through a [`ReloadHandle`]. The new settings are built and
+//! validated completely before they replace the old ones, so an invalid file
+//! is rejected and the previous settings stay in force. Clients keep their
+//! connections; each request uses the settings current when it arrives.
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+
+/// 检查被监视文件是否变化的间隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A value that can be replaced while readers hold on to older snapshots
+pub struct Live<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> Live<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(value)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// The outcome of rebuilding the settings from disk
+pub struct Reloaded<T> {
+    pub value: T,
+    /// 人类可读的变更说明，为空表示没有变化
+    pub changes: Vec<String>,
+    /// 下一次需要监视的文件
+    pub watched: Vec<PathBuf>,
+}
+
+/// 重新加载的结果：变更说明或拒绝的原因
+type Outcome = Result<Vec<String>, String>;
+
+/// Requests a reload from outside, e.g. from the control socket
+#[derive(Clone)]
+pub struct ReloadHandle {
+    requests: mpsc::Sender<oneshot::Sender<Outcome>>,
+}
+
+impl ReloadHandle {
+    /// 立即重新加载，返回变更说明
+    pub async fn reload(&self) -> anyhow::Result<Vec<String>> {
+        let (reply, outcome) = oneshot::channel();
+        let stopped = || anyhow!("The reload task has stopped");
+        self.requests.send(reply).await.map_err(|_| stopped())?;
+        outcome
+            .await
+            .map_err(|_| stopped())?
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// 文件的修改时间与大小；文件不存在时为 `None`
+fn signature(path: &PathBuf) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn signatures(paths: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    paths.iter().map(signature).collect()
+}
+
+/// 在后台监视文件与 SIGHUP，重新加载成功后替换 `live` 中的值
+///
+/// `load` rebuilds the settings from the current files and compares them
+/// with the running ones; it must not have side effects when it fails.
+pub fn spawn<T, F>(live: Arc<Live<T>>, watched: Vec<PathBuf>, mut load: F) -> ReloadHandle
+where
+    T: Send + Sync + 'static,
+    F: FnMut(&T) -> anyhow::Result<Reloaded<T>> + Send + 'static,
+{
+    let (requests, mut requested) = mpsc::channel::<oneshot::Sender<Outcome>>(4);
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(signal) => Some(signal),
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP, relying on file changes: {}", e);
+                None
+            }
+        };
+        let mut watched = watched;
+        let mut known = signatures(&watched);
+        for path in &watched {
+            debug!("Watching {} for changes", path.display());
+        }
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            #[cfg(unix)]
+            let hangup_received = async {
+                match hangup.as_mut() {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup_received = std::future::pending::<Option<()>>();
+
+            let (reason, reply) = tokio::select! {
+                _ = hangup_received => ("SIGHUP".to_string(), None),
+                Some(reply) = requested.recv() => ("control request".to_string(), Some(reply)),
+                _ = interval.tick() => {
+                    let current = signatures(&watched);
+                    let Some(changed) = (0..watched.len()).find(|&i| current[i] != known[i]) else {
+                        continue;
+                    };
+                    (format!("change to {}", watched[changed].display()), None)
+                }
+            };
+
+            let running = live.load();
+            let outcome = match load(&running) {
+                Ok(reloaded) => {
+                    watched = reloaded.watched;
+                    known = signatures(&watched);
+                    if reloaded.changes.is_empty() {
+                        info!("Reloaded configuration after {}: no changes", reason);
+                    } else {
+                        info!("Reloaded configuration after {}:", reason);
+                        for change in &reloaded.changes {
+                            info!("  {}", change);
+                        }
+                    }
+                    live.store(reloaded.value);
+                    Ok(reloaded.changes)
+                }
+                Err(e) => {
+                    // 记下当前的文件状态，避免对同一个错误反复报错
+                    known = signatures(&watched);
+                    warn!(
+                        "Rejected configuration reload after {}, keeping the previous settings: {}",
+                        reason, e
+                    );
+                    Err(e.to_string())
+                }
+            };
+            if let Some(reply) = reply {
+                let _ = reply.send(outcome);
+            }
+        }
+    });
+    ReloadHandle { requests }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 第一次加载失败，之后每次加载把值加一
+    fn flaky(
+        loads: Arc<AtomicUsize>,
+        watched: Vec<PathBuf>,
+    ) -> impl FnMut(&u32) -> anyhow::Result<Reloaded<u32>> {
+        move |running| {
+            if loads.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(anyhow!("invalid config"));
+            }
+            Ok(Reloaded {
+                value: running + 1,
+                changes: vec![format!("value: {} -> {}", running, running + 1)],
+                watched: watched.clone(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_reload_keeps_the_running_value() {
+        let live = Arc::new(Live::new(1));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let handle = spawn(live.clone(), Vec::new(), flaky(loads, Vec::new()));
+        let error = handle.reload().await.unwrap_err();
+        assert_eq!(error.to_string(), "invalid config");
+        assert_eq!(*live.load(), 1);
+        assert_eq!(handle.reload().await.unwrap(), ["value: 1 -> 2"]);
+        assert_eq!(*live.load(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watched_file_changes_trigger_a_reload() {
+        let path = std::env::temp_dir().join(format!("watched-{}.toml", std::process::id()));
+        std::fs::write(&path, "a").unwrap();
+        let live = Arc::new(Live::new(1));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let watched = vec![path.clone()];
+        spawn(live.clone(), watched.clone(), flaky(loads.clone(), watched));
+        // 每次等待都跨过一次检查
+        let check = || tokio::time::sleep(WATCH_INTERVAL + Duration::from_millis(500));
+
+        check().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 0);
+        // 被拒绝的文件不会在每次检查时重复加载
+        std::fs::write(&path, "ab").unwrap();
+        check().await;
+        check().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(*live.load(), 1);
+        std::fs::write(&path, "abc").unwrap();
+        check().await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(*live.load(), 2);
+    }
+}
@@ -0,0 +1,122 @@
+use std::hash::{BuildHasher, RandomState};
+use std::time::Duration;
+
+/// How the delay between connection attempts grows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backoff {
+    /// Wait the same delay before every attempt
+    Constant,
+    /// Add the base delay after every failed attempt
+    Linear,
+    /// Double the delay after every failed attempt
+    Exponential,
+}
+
+/// 连接 upstream 时的重试策略
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Backoff,
+    pub delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+    // 超过该总时长后不再重试
+    pub max_elapsed: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// 第 `attempt` 次失败（从 0 开始）后等待的时间
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Constant => self.delay,
+            Backoff::Linear => self.delay.saturating_mul(attempt.saturating_add(1)),
+            Backoff::Exponential => self
+                .delay
+                .saturating_mul(2u32.saturating_pow(attempt.min(31))),
+        };
+        let delay = delay.min(self.max_delay.max(self.delay));
+        if !self.jitter {
+            return delay;
+        }
+        // equal jitter：保留一半延迟，另一半随机，避免多个客户端同时重连
+        let half = delay / 2;
+        let random = RandomState::new().hash_one(attempt);
+        half + half.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Duration = Duration::from_millis(100);
+
+    fn policy(backoff: Backoff) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 10,
+            backoff,
+            delay: BASE,
+            max_delay: Duration::from_secs(60),
+            jitter: false,
+            max_elapsed: None,
+        }
+    }
+
+    fn delays(policy: &RetryPolicy) -> Vec<Duration> {
+        (0..4).map(|attempt| policy.delay_for(attempt)).collect()
+    }
+
+    #[test]
+    fn delay_grows_with_the_backoff() {
+        let ms = |values: [u64; 4]| values.map(Duration::from_millis).to_vec();
+        assert_eq!(delays(&policy(Backoff::Constant)), ms([100; 4]));
+        assert_eq!(delays(&policy(Backoff::Linear)), ms([100, 200, 300, 400]));
+        assert_eq!(
+            delays(&policy(Backoff::Exponential)),
+            ms([100, 200, 400, 800])
+        );
+    }
+
+    #[test]
+    fn delay_is_capped_by_the_max_delay() {
+        let capped = RetryPolicy {
+            max_delay: Duration::from_millis(250),
+            ..policy(Backoff::Exponential)
+        };
+        assert_eq!(capped.delay_for(1), Duration::from_millis(200));
+        assert_eq!(capped.delay_for(2), Duration::from_millis(250));
+        // 上限小于基础延迟时以基础延迟为准
+        let below = RetryPolicy {
+            max_delay: Duration::from_millis(10),
+            ..policy(Backoff::Linear)
+        };
+        assert_eq!(below.delay_for(5), BASE);
+    }
+
+    #[test]
+    fn high_attempts_do_not_overflow() {
+        for backoff in [Backoff::Constant, Backoff::Linear, Backoff::Exponential] {
+            let huge = RetryPolicy {
+                delay: Duration::from_secs(u64::MAX / 2),
+                max_delay: Duration::MAX,
+                ..policy(backoff)
+            };
+            assert!(huge.delay_for(u32::MAX) >= huge.delay);
+            let normal = policy(backoff);
+            assert!(normal.delay_for(u32::MAX) <= normal.max_delay);
+        }
+    }
+
+    #[test]
+    fn jitter_stays_between_half_and_full_delay() {
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy(Backoff::Exponential)
+        };
+        for attempt in 0..200 {
+            let full = policy(Backoff::Exponential).delay_for(attempt);
+            let delay = jittered.delay_for(attempt);
+            assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+        }
+    }
+}
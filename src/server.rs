@@ -0,0 +1,275 @@
+use crate::bridge::{self, BridgeOptions, ClientInfo};
+use crate::events::Event;
+use crate::reload::Live;
+use crate::upstream::UpstreamOptions;
+use anyhow::{Result, anyhow};
+use log::debug;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+/// 解析监听地址，目前只支持 `unix:/path/to/agent.sock`
+pub fn parse_listen(spec: &str) -> Result<String> {
+    match spec.strip_prefix("unix:") {
+        Some(path) if !path.is_empty() => Ok(path.to_string()),
+        _ => Err(anyhow!(
+            "Unsupported listen address '{}', expected unix:/path/to/agent.sock",
+            spec
+        )),
+    }
+}
+
+/// 绑定只有当前用户可以访问的 Unix socket
+///
+/// socket 先在新建的 0700 临时目录中绑定并改为 0600，再改名到目标路径，
+/// 权限收紧之前其他用户无法连接。
+#[cfg(unix)]
+pub fn bind_private(path: &str) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::sync::atomic::AtomicU32;
+
+    static STAGED: AtomicU32 = AtomicU32::new(0);
+    let target = std::path::Path::new(path);
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let staging = parent.join(format!(
+        ".{}-{}",
+        std::process::id(),
+        STAGED.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("s");
+    let result = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, target)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+/// A client currently connected to `serve`
+pub struct ClientEntry {
+    pub id: u64,
+    pub info: Arc<ClientInfo>,
+    pub connected: SystemTime,
+    disconnect: Arc<Notify>,
+}
+
+/// Connected clients, shared with the control socket
+#[derive(Default)]
+pub struct Clients {
+    next_id: AtomicU64,
+    entries: Mutex<BTreeMap<u64, ClientEntry>>,
+}
+
+/// 客户端连接期间的登记，释放时自动注销
+pub struct Registration {
+    clients: Arc<Clients>,
+    id: u64,
+    disconnect: Arc<Notify>,
+}
+
+impl Registration {
+    /// 等待控制接口要求断开该客户端
+    pub async fn disconnect_requested(&self) {
+        self.disconnect.notified().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.clients.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Clients {
+    /// 分配下一个客户端编号，从 1 开始
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// 至今接受的客户端总数
+    pub fn total(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    pub fn register(self: &Arc<Self>, id: u64, info: Arc<ClientInfo>) -> Registration {
+        let disconnect = Arc::new(Notify::new());
+        let entry = ClientEntry {
+            id,
+            info,
+            connected: SystemTime::now(),
+            disconnect: disconnect.clone(),
+        };
+        self.entries.lock().unwrap().insert(id, entry);
+        Registration {
+            clients: self.clone(),
+            id,
+            disconnect,
+        }
+    }
+
+    /// 要求断开客户端；客户端不存在时返回 `false`
+    pub fn disconnect(&self, id: u64) -> bool {
+        match self.entries.lock().unwrap().get(&id) {
+            // notify_one 会保留通知，客户端正在处理请求时也不会错过
+            Some(entry) => {
+                entry.disconnect.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 对每个已连接的客户端调用 `f`，按编号排序
+    pub fn for_each(&self, mut f: impl FnMut(&ClientEntry)) {
+        for entry in self.entries.lock().unwrap().values() {
+            f(entry);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+/// 通过 SO_PEERCRED 识别连接的进程
+#[cfg(unix)]
+fn peer_info(stream: &tokio::net::UnixStream, client_id: u64) -> ClientInfo {
+    let mut client = ClientInfo::new(format!("client #{}", client_id));
+    if let Ok(cred) = stream.peer_cred() {
+        client.pid = cred.pid();
+        client.uid = Some(cred.uid());
+    }
+    client.process = client.pid.and_then(|pid| {
+        std::fs::read_to_string(format!("/proc/{}/comm", pid))
+            .ok()
+            .map(|comm| comm.trim_end().to_string())
+    });
+    client
+}
+
+/// 监听 Unix socket，并为每个客户端建立独立的 upstream 连接
+///
+/// 每个请求都读取 `options` 的当前值，重新加载的设置对已连接的客户端同样生效。
+#[cfg(unix)]
+pub async fn serve(
+    listen: &str,
+    upstream: UpstreamOptions,
+    options: Arc<Live<BridgeOptions>>,
+    clients: Arc<Clients>,
+) -> Result<()> {
+    let path = parse_listen(listen)?;
+    // 清理上次运行残留的 socket 文件
+    match std::fs::remove_file(&path) {
+        Ok(()) => debug!("Removed stale socket {}", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(anyhow!("Failed to remove stale socket '{}': {}", path, e)),
+    }
+    // 只允许当前用户访问 agent socket
+    let listener =
+        bind_private(&path).map_err(|e| anyhow!("Failed to listen on '{}': {}", path, e))?;
+    debug!("Listening on unix:{}", path);
+
+    let result = loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tokio::signal::ctrl_c() => {
+                debug!("Received interrupt, shutting down");
+                break Ok(());
+            }
+        };
+        let (stream, _) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => break Err(anyhow!("Failed to accept client: {}", e)),
+        };
+        let client_id = clients.next_id();
+        let client = Arc::new(peer_info(&stream, client_id));
+        let registration = clients.register(client_id, client.clone());
+        let upstream = upstream.clone();
+        let options = options.clone();
+        debug!("{} connected", client);
+        let events = options.load().events.clone();
+        if let Some(events) = &events {
+            events.publish(Event::ClientConnected {
+                client: client.as_ref().into(),
+            });
+        }
+        tokio::spawn(async move {
+            let (client_read, client_write) = stream.into_split();
+            let result = tokio::select! {
+                result = bridge::bridge_connection(
+                    client_read,
+                    client_write,
+                    &upstream,
+                    &client,
+                    &options,
+                ) => result,
+                // 丢弃转发任务即关闭客户端连接
+                _ = registration.disconnect_requested() => {
+                    debug!("Disconnecting {} on control request", client);
+                    Ok(())
+                }
+            };
+            drop(registration);
+            if let Some(events) = &events {
+                events.publish(Event::ClientDisconnected {
+                    client: client.as_ref().into(),
+                });
+            }
+            match result {
+                Ok(()) => debug!("{} disconnected", client),
+                Err(e) => log::error!("{} terminated with error: {}", client, e),
+            }
+        });
+    };
+
+    let _ = std::fs::remove_file(&path);
+    for pool in upstream.pools.iter().flat_map(|pools| pools.iter()) {
+        debug!("Connection pool for {}: {}", pool.transport(), pool.stats());
+    }
+    result
+}
+
+#[cfg(not(unix))]
+pub async fn serve(
+    listen: &str,
+    _upstream: UpstreamOptions,
+    _options: Arc<Live<BridgeOptions>>,
+    _clients: Arc<Clients>,
+) -> Result<()> {
+    parse_listen(listen)?;
+    Err(anyhow!(
+        "Listening on Unix sockets is only supported on Unix"
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn sockets_are_private_from_the_start() {
+        let dir = std::env::temp_dir().join(format!("bind-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.sock");
+        // 残留的旧 socket 被直接替换
+        std::fs::write(&path, b"stale").unwrap();
+        let listener = bind_private(path.to_str().unwrap()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let connect = tokio::net::UnixStream::connect(&path);
+        let (connected, accepted) = tokio::join!(connect, listener.accept());
+        assert!(connected.is_ok() && accepted.is_ok());
+        // 临时目录已清理
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+//! In-memory upstream agents and option builders shared by the unit tests
+
+use crate::bridge::{BridgeOptions, InvalidFramePolicy, RequestTimeouts};
+use crate::codec::{SshAgentCodec, SshAgentMessage};
+use crate::policy::Operation;
+use crate::pool::PinMode;
+use crate::proto::{self, AgentMessage, Identity, Writer};
+use crate::retry::{Backoff, RetryPolicy};
+use crate::transport::{BoxedStream, UpstreamTransport};
+use crate::upstream::{UpstreamMode, UpstreamOptions};
+use futures::SinkExt;
+use futures::future::BoxFuture;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+impl RetryPolicy {
+    /// 不重试、不等待
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: Backoff::Constant,
+            delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: false,
+            max_elapsed: None,
+        }
+    }
+}
+
+/// 不含策略、超时等附加功能的 bridge 设置
+pub fn bridge_options() -> BridgeOptions {
+    BridgeOptions {
+        max_message_size: crate::codec::DEFAULT_MAX_MESSAGE_SIZE,
+        invalid_frame: InvalidFramePolicy::Failure,
+        policy: None,
+        allowed_operations: vec![Operation::List, Operation::Sign, Operation::SessionBind],
+        audit: None,
+        confirm: None,
+        timeouts: RequestTimeouts {
+            list: None,
+            sign: None,
+            other: None,
+        },
+        cache: None,
+        metrics: None,
+        events: None,
+    }
+}
+
+/// 按给定的 upstream 合并、不重试、不等待的 upstream 设置
+pub fn upstream_options(agents: &[Arc<FakeAgent>]) -> UpstreamOptions {
+    UpstreamOptions {
+        transports: agents
+            .iter()
+            .map(|agent| agent.clone() as Arc<dyn UpstreamTransport>)
+            .collect(),
+        mode: UpstreamMode::Merge,
+        retry: RetryPolicy::none(),
+        wait: None,
+        max_message_size: crate::codec::DEFAULT_MAX_MESSAGE_SIZE,
+        pools: None,
+        pin: PinMode::Never,
+        metrics: None,
+        events: None,
+    }
+}
+
+/// 以 `seed` 区分的 ed25519 公钥 blob
+pub fn key_blob(seed: u8) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer
+        .write_string(b"ssh-ed25519")
+        .write_string(&[seed; 32]);
+    writer.into_bytes()
+}
+
+pub fn identity(seed: u8, comment: &str) -> Identity {
+    Identity {
+        key_blob: key_blob(seed),
+        comment: comment.to_string(),
+    }
+}
+
+/// How a [`FakeAgent`] answers connection attempts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connect {
+    /// Accept and answer requests
+    Accept,
+    /// Refuse the connection
+    Refuse,
+    /// Never finish connecting
+    Hang,
+    /// Accept and close the connection straight away
+    Close,
+}
+
+/// An in-memory upstream agent
+///
+/// It lists its identities, answers SIGN_REQUEST with its own name as the
+/// signature and everything else with SUCCESS, and records the type of every
+/// request it receives.
+pub struct FakeAgent {
+    name: String,
+    // 每条连接由一个后台任务应答，任务需要持有自身
+    me: Weak<FakeAgent>,
+    connect: Mutex<Connect>,
+    identities: Mutex<Vec<Identity>>,
+    // 接下来这么多个请求收到后直接断开连接而不应答
+    drops: AtomicUsize,
+    // 应答前等待的时间
+    delay: Mutex<Option<Duration>>,
+    // 每个应答之后再多发一个 SSH_AGENT_SUCCESS
+    chatty: AtomicBool,
+    // 连接尝试次数，包括被拒绝的
+    attempts: AtomicUsize,
+    requests: Mutex<Vec<u8>>,
+}
+
+impl FakeAgent {
+    pub fn new(name: &str) -> Arc<Self> {
+        Self::with_identities(name, Vec::new())
+    }
+
+    pub fn with_identities(name: &str, identities: Vec<Identity>) -> Arc<Self> {
+        Arc::new_cyclic(|me| Self {
+            name: name.to_string(),
+            me: me.clone(),
+            connect: Mutex::new(Connect::Accept),
+            identities: Mutex::new(identities),
+            drops: AtomicUsize::new(0),
+            delay: Mutex::new(None),
+            chatty: AtomicBool::new(false),
+            attempts: AtomicUsize::new(0),
+            requests: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn set_connect(&self, connect: Connect) {
+        *self.connect.lock().unwrap() = connect;
+    }
+
+    pub fn drop_requests(&self, count: usize) {
+        self.drops.store(count, Ordering::SeqCst);
+    }
+
+    pub fn attempts(&self) -> usize {
+        self.attempts.load(Ordering::SeqCst)
+    }
+
+    /// 收到的请求类型，按到达顺序
+    pub fn requests(&self) -> Vec<u8> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        *self.delay.lock().unwrap() = Some(delay);
+    }
+
+    pub fn set_chatty(&self, chatty: bool) {
+        self.chatty.store(chatty, Ordering::SeqCst);
+    }
+
+    fn reply(&self, request: &SshAgentMessage) -> AgentMessage {
+        match request.payload[0] {
+            proto::SSH_AGENTC_REQUEST_IDENTITIES => {
+                AgentMessage::IdentitiesAnswer(self.identities.lock().unwrap().clone())
+            }
+            proto::SSH_AGENTC_SIGN_REQUEST => AgentMessage::SignResponse {
+                signature: self.name.as_bytes().to_vec(),
+            },
+            _ => AgentMessage::Success,
+        }
+    }
+
+    async fn serve(self: Arc<Self>, stream: tokio::io::DuplexStream) {
+        let (read, write) = tokio::io::split(stream);
+        let mut reader = FramedRead::new(read, SshAgentCodec::default());
+        let mut writer = FramedWrite::new(write, SshAgentCodec::default());
+        while let Some(Ok(Ok(request))) = reader.next().await {
+            self.requests.lock().unwrap().push(request.payload[0]);
+            let dropped = self
+                .drops
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if dropped.is_ok() {
+                return;
+            }
+            let delay = *self.delay.lock().unwrap();
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            let mut replies = vec![self.reply(&request)];
+            if self.chatty.load(Ordering::SeqCst) {
+                replies.push(AgentMessage::Success);
+            }
+            for reply in replies {
+                if writer.send(SshAgentMessage::from(&reply)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for FakeAgent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fake:{}", self.name)
+    }
+}
+
+impl UpstreamTransport for FakeAgent {
+    fn address(&self) -> Option<&str> {
+        None
+    }
+
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<BoxedStream>> {
+        let connect = *self.connect.lock().unwrap();
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            match connect {
+                Connect::Refuse => Err(std::io::ErrorKind::ConnectionRefused.into()),
+                Connect::Hang => std::future::pending().await,
+                Connect::Close => Ok(Box::new(tokio::io::duplex(64).0) as BoxedStream),
+                Connect::Accept => {
+                    let (bridge, agent) = tokio::io::duplex(64 * 1024);
+                    let this = self.me.upgrade().expect("fake agent is alive");
+                    tokio::spawn(this.serve(agent));
+                    Ok(Box::new(bridge) as BoxedStream)
+                }
+            }
+        })
+    }
+}
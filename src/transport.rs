@@ -0,0 +1,252 @@
+use crate::metrics::Metrics;
+use crate::retry::RetryPolicy;
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+use log::debug;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+
+/// Upstream 连接的字节流
+pub trait UpstreamStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UpstreamStream for T {}
+
+pub type BoxedStream = Box<dyn UpstreamStream>;
+
+/// A way of reaching the real SSH agent that the bridge forwards to
+pub trait UpstreamTransport: std::fmt::Display + Send + Sync {
+    /// Filesystem address of the agent endpoint (pipe name or socket path),
+    /// if it can be checked locally
+    fn address(&self) -> Option<&str>;
+
+    /// Open a new connection to the agent
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<BoxedStream>>;
+}
+
+/// Windows 命名管道
+pub struct NamedPipeTransport {
+    name: String,
+}
+
+impl NamedPipeTransport {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl std::fmt::Display for NamedPipeTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pipe:{}", self.name)
+    }
+}
+
+impl UpstreamTransport for NamedPipeTransport {
+    fn address(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    #[cfg(windows)]
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<BoxedStream>> {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_FLAG_OVERLAPPED: u32 = 0x40000000; // from winapi::um::winbase::FILE_FLAG_OVERLAPPED
+        let name = self.name.clone();
+        Box::pin(async move {
+            let pipe = tokio::task::spawn_blocking(move || {
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    // 使用 OVERLAPPED I/O 以便与异步代码兼容
+                    .custom_flags(FILE_FLAG_OVERLAPPED)
+                    .open(name)
+            })
+            .await??;
+            // 转换为异步文件
+            Ok(Box::new(tokio::fs::File::from_std(pipe)) as BoxedStream)
+        })
+    }
+
+    #[cfg(not(windows))]
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<BoxedStream>> {
+        Box::pin(async {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Named pipes are only available on Windows",
+            ))
+        })
+    }
+}
+
+/// Unix domain socket，例如本地 `ssh-agent` 的 `SSH_AUTH_SOCK`
+pub struct UnixSocketTransport {
+    path: String,
+}
+
+impl UnixSocketTransport {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl std::fmt::Display for UnixSocketTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unix:{}", self.path)
+    }
+}
+
+impl UpstreamTransport for UnixSocketTransport {
+    fn address(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+
+    #[cfg(unix)]
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<BoxedStream>> {
+        Box::pin(async {
+            let stream = tokio::net::UnixStream::connect(&self.path).await?;
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<BoxedStream>> {
+        Box::pin(async {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix domain sockets are only available on Unix",
+            ))
+        })
+    }
+}
+
+/// 解析 upstream 地址：`unix:/path/to/agent.sock` 或 `pipe:\\.\pipe\name`，
+/// 不带前缀时视为命名管道
+pub fn parse_upstream(spec: &str) -> Result<Arc<dyn UpstreamTransport>> {
+    if let Some(path) = spec.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err(anyhow!("Empty unix socket path in upstream '{}'", spec));
+        }
+        return Ok(Arc::new(UnixSocketTransport::new(path)));
+    }
+    let name = spec.strip_prefix("pipe:").unwrap_or(spec);
+    if name.is_empty() {
+        return Err(anyhow!("Empty pipe name in upstream '{}'", spec));
+    }
+    Ok(Arc::new(NamedPipeTransport::new(name)))
+}
+
+/// 连接到 upstream agent，按重试策略等待后重试
+pub async fn connect_upstream(
+    transport: &dyn UpstreamTransport,
+    retry: &RetryPolicy,
+    metrics: Option<&Metrics>,
+) -> Result<BoxedStream> {
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let error = match transport.connect().await {
+            Ok(stream) => {
+                if let Some(metrics) = metrics {
+                    metrics.record_connect(&transport.to_string(), true, false);
+                }
+                debug!(
+                    "Successfully connected to {} on attempt {}",
+                    transport,
+                    attempt + 1
+                );
+                return Ok(stream);
+            }
+            Err(e) => e,
+        };
+        let delay = retry.delay_for(attempt);
+        let out_of_time = retry
+            .max_elapsed
+            .is_some_and(|max| started.elapsed() + delay > max);
+        let giving_up = attempt >= retry.max_retries || out_of_time;
+        if let Some(metrics) = metrics {
+            metrics.record_connect(&transport.to_string(), false, !giving_up);
+        }
+        if giving_up {
+            return Err(anyhow!(
+                "Failed to connect to '{}' after {} attempts in {:?}: {}",
+                transport,
+                attempt + 1,
+                started.elapsed(),
+                error
+            ));
+        }
+        debug!(
+            "Connection attempt {} failed: {}, retrying in {:?}",
+            attempt + 1,
+            error,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::Backoff;
+    use crate::testing::{Connect, FakeAgent};
+    use std::time::Duration;
+
+    fn refused() -> Arc<FakeAgent> {
+        let agent = FakeAgent::new("down");
+        agent.set_connect(Connect::Refuse);
+        agent
+    }
+
+    fn retry(max_retries: u32, max_elapsed: Option<Duration>) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            backoff: Backoff::Constant,
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(100),
+            jitter: false,
+            max_elapsed,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_retries() {
+        let agent = refused();
+        let started = Instant::now();
+        assert!(
+            connect_upstream(agent.as_ref(), &retry(2, None), None)
+                .await
+                .is_err()
+        );
+        assert_eq!(agent.attempts(), 3);
+        assert_eq!(started.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_before_the_next_wait_passes_max_elapsed() {
+        let agent = refused();
+        let started = Instant::now();
+        let policy = retry(100, Some(Duration::from_millis(350)));
+        let error = connect_upstream(agent.as_ref(), &policy, None)
+            .await
+            .err()
+            .unwrap();
+        // 第四次失败后再等 100ms 会超过 350ms，不再等待
+        assert_eq!(agent.attempts(), 4);
+        assert_eq!(started.elapsed(), Duration::from_millis(300));
+        assert!(error.to_string().contains("after 4 attempts"), "{error}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connects_once_the_upstream_accepts() {
+        let agent = refused();
+        let policy = retry(5, None);
+        let connecting = connect_upstream(agent.as_ref(), &policy, None);
+        let accept = async {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            agent.set_connect(Connect::Accept);
+        };
+        let (stream, ()) = tokio::join!(connecting, accept);
+        assert!(stream.is_ok());
+        assert_eq!(agent.attempts(), 3);
+    }
+}
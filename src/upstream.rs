@@ -0,0 +1,1003 @@
+//! Request/response sessions with one or more upstream agents
+//!
+//! With several upstreams the bridge presents them as a single agent:
+//! IDENTITIES_ANSWER lists are merged (the first upstream holding a key blob
+//! owns it), sign and remove requests go to the owner of the key, LOCK,
+//! UNLOCK, REMOVE_ALL and extensions are sent to every upstream, and
+//! everything else goes to the first one.
+//!
+//! In failover mode only one upstream is used at a time: the first one in
+//! the list that accepts a connection. When it stops answering the bridge
+//! moves on to the next one and re-sends the request there.
+//!
+//! A connection that drops is re-established on the next request. If it
+//! drops while a request is in flight, REQUEST_IDENTITIES and SIGN_REQUEST
+//! are re-issued on the new connection; other requests fail.
+//!
+//! With a connection pool (see [`crate::pool`]) an upstream borrows a
+//! connection for each request instead of keeping its own, until a stateful
+//! request pins it.
+
+use crate::codec::{FrameError, SshAgentCodec, SshAgentMessage};
+use crate::events::Events;
+use crate::metrics::Metrics;
+use crate::pool::{ConnectionPool, PinMode};
+use crate::proto::{self, AgentMessage, Identity};
+use crate::retry::RetryPolicy;
+use crate::transport::{self, BoxedStream, UpstreamTransport};
+use anyhow::{Result, anyhow};
+use futures::{FutureExt, SinkExt};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// How several upstream agents are combined
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum UpstreamMode {
+    /// Expose the keys of every upstream at once
+    Merge,
+    /// Use the first available upstream, falling back to the next one on failure
+    Failover,
+}
+
+/// What to do with client requests while no upstream is reachable
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum WaitMode {
+    /// Hold requests until an upstream appears
+    Queue,
+    /// Answer SSH_AGENT_FAILURE until an upstream appears
+    FailFast,
+}
+
+/// Where and how to reach the upstream agents
+#[derive(Clone)]
+pub struct UpstreamOptions {
+    pub transports: Arc<[Arc<dyn UpstreamTransport>]>,
+    pub mode: UpstreamMode,
+    pub retry: RetryPolicy,
+    // 设置后 upstream 不可用时一直等待，而不是断开客户端
+    pub wait: Option<WaitMode>,
+    pub max_message_size: u32,
+    // 与 transports 一一对应；未设置时每个客户端使用自己的连接
+    pub pools: Option<Arc<[Arc<ConnectionPool>]>>,
+    pub pin: PinMode,
+    pub metrics: Option<Arc<Metrics>>,
+    pub events: Option<Arc<Events>>,
+}
+
+/// 与 upstream agent 的一条连接
+pub struct Connection {
+    reader: FramedRead<ReadHalf<BoxedStream>, SshAgentCodec>,
+    writer: FramedWrite<WriteHalf<BoxedStream>, SshAgentCodec>,
+}
+
+impl Connection {
+    fn new(stream: BoxedStream, max_message_size: u32) -> Self {
+        let (read, write) = tokio::io::split(stream);
+        Self {
+            reader: FramedRead::new(read, SshAgentCodec::new(max_message_size)),
+            writer: FramedWrite::new(write, SshAgentCodec::new(max_message_size)),
+        }
+    }
+
+    /// 空闲连接上不应有任何可读内容；已关闭或收到多余数据的连接不再可用
+    pub fn is_healthy(&mut self) -> bool {
+        self.reader.next().now_or_never().is_none()
+    }
+
+    /// 丢弃空闲时收到的帧，返回连接是否仍然打开
+    fn drain(&mut self, upstream: &dyn UpstreamTransport) -> bool {
+        while let Some(frame) = self.reader.next().now_or_never() {
+            match frame {
+                Some(Ok(frame)) => discard(upstream, frame),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// 发送一个请求并等待对应的响应
+    async fn request(
+        &mut self,
+        msg: SshAgentMessage,
+        metrics: Option<&Metrics>,
+    ) -> Result<SshAgentMessage> {
+        let sent = 4 + msg.payload.len();
+        self.writer.send(msg).await?;
+        if let Some(metrics) = metrics {
+            metrics.record_forwarded(true, sent);
+        }
+        let reply = match self.reader.next().await {
+            Some(frame) => frame?.map_err(|e| anyhow!("invalid frame: {}", e))?,
+            None => return Err(anyhow!("connection closed")),
+        };
+        if let Some(metrics) = metrics {
+            metrics.record_forwarded(false, 4 + reply.payload.len());
+        }
+        Ok(reply)
+    }
+}
+
+/// agent 不会主动发送消息，请求之间收到的帧不能当作下一个请求的响应
+fn discard(upstream: &dyn UpstreamTransport, frame: Result<SshAgentMessage, FrameError>) {
+    match frame {
+        Ok(msg) => warn!(
+            "Discarding unsolicited {} from {}",
+            proto::message_name(msg.payload[0]),
+            upstream
+        ),
+        Err(e) => warn!("Discarding invalid frame from {}: {}", upstream, e),
+    }
+}
+
+/// REQUEST_IDENTITIES 与 SIGN_REQUEST 重复执行没有副作用，连接中断后可以重发
+fn is_idempotent(msg_type: u8) -> bool {
+    matches!(
+        msg_type,
+        proto::SSH_AGENTC_REQUEST_IDENTITIES | proto::SSH_AGENTC_SIGN_REQUEST
+    )
+}
+
+/// 故障转移模式下切换到后备 upstream 之后，隔多久再尝试回到优先级更高的 upstream
+const FAILBACK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Connecting to an upstream failed, so the request was never sent
+#[derive(Debug)]
+struct Unreachable(String);
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+/// 一个 upstream agent；连接中断后在下一次请求时重新连接
+pub struct Upstream {
+    transport: Arc<dyn UpstreamTransport>,
+    pool: Option<Arc<ConnectionPool>>,
+    connection: Option<Connection>,
+    // 从连接池借出连接时占用的名额
+    permit: Option<OwnedSemaphorePermit>,
+    // 固定后连接不再还给连接池，也不占用名额
+    pinned: bool,
+}
+
+impl Upstream {
+    async fn connect(options: &UpstreamOptions, index: usize, pinned: bool) -> Result<Self> {
+        let mut upstream = Self {
+            transport: options.transports[index].clone(),
+            pool: options.pools.as_ref().map(|pools| pools[index].clone()),
+            connection: None,
+            permit: None,
+            pinned,
+        };
+        upstream.open(options).await?;
+        upstream.release();
+        Ok(upstream)
+    }
+
+    /// 确保有可用连接（借用或按重试策略新建），返回连接是否刚刚建立
+    async fn open(&mut self, options: &UpstreamOptions) -> Result<bool> {
+        if let Some(connection) = &mut self.connection {
+            if connection.drain(self.transport.as_ref()) {
+                return Ok(false);
+            }
+            debug!(
+                "{} closed the idle connection, reconnecting",
+                self.transport
+            );
+            self.disconnect();
+        }
+        if let Some(pool) = &self.pool {
+            // 固定的连接不占用名额，否则固定的客户端多于池大小时其他客户端会一直等待
+            let idle = if self.pinned {
+                pool.take_pinned()
+            } else {
+                let (permit, idle) = pool.acquire().await;
+                self.permit = Some(permit);
+                idle
+            };
+            if idle.is_some() {
+                self.connection = idle;
+                return Ok(false);
+            }
+        }
+        // 重试次数用完即返回错误；等待模式下由 Upstreams::connect 在所有 upstream 都失败后等待
+        let result = transport::connect_upstream(
+            self.transport.as_ref(),
+            &options.retry,
+            options.metrics.as_deref(),
+        )
+        .await;
+        if let Some(events) = &options.events {
+            let upstream = self.transport.to_string();
+            events.upstream_connected(&upstream, result.as_ref().err());
+        }
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.permit = None;
+                return Err(Unreachable(e.to_string()).into());
+            }
+        };
+        debug!("Connected to {} successfully", self.transport);
+        self.connection = Some(Connection::new(stream, options.max_message_size));
+        Ok(true)
+    }
+
+    /// 请求完成后把连接还给连接池；已固定的连接只归还名额
+    fn release(&mut self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        if self.pinned {
+            self.permit = None;
+            return;
+        }
+        if let (Some(connection), Some(permit)) = (self.connection.take(), self.permit.take()) {
+            pool.release(connection, permit);
+        }
+    }
+
+    /// 丢弃当前连接，借用的名额一并归还
+    fn disconnect(&mut self) {
+        self.connection = None;
+        self.permit = None;
+    }
+
+    /// 发送请求；连接已断开时重新连接，幂等请求在重连后重发一次
+    pub async fn request(
+        &mut self,
+        options: &UpstreamOptions,
+        msg: SshAgentMessage,
+    ) -> Result<SshAgentMessage> {
+        let fresh = self.open(options).await?;
+        let msg_type = msg.payload[0];
+        let connection = self.connection.as_mut().unwrap();
+        let error = match connection
+            .request(msg.clone(), options.metrics.as_deref())
+            .await
+        {
+            Ok(reply) => {
+                self.release();
+                return Ok(reply);
+            }
+            Err(e) => e,
+        };
+        self.disconnect();
+        if fresh || !is_idempotent(msg_type) {
+            return Err(anyhow!("Request to {} failed: {}", self.transport, error));
+        }
+        warn!(
+            "Lost connection to {} ({}), reconnecting to re-issue {}",
+            self.transport,
+            error,
+            proto::message_name(msg_type)
+        );
+        self.open(options).await?;
+        let connection = self.connection.as_mut().unwrap();
+        match connection.request(msg, options.metrics.as_deref()).await {
+            Ok(reply) => {
+                self.release();
+                Ok(reply)
+            }
+            Err(e) => {
+                self.disconnect();
+                Err(anyhow!(
+                    "Request to {} failed after reconnecting: {}",
+                    self.transport,
+                    e
+                ))
+            }
+        }
+    }
+}
+
+/// The upstream agents serving one client connection
+pub struct Upstreams {
+    options: UpstreamOptions,
+    upstreams: Vec<Upstream>,
+    // 故障转移模式下当前使用的 upstream 在列表中的位置
+    active: usize,
+    // 切换到后备 upstream 之后，下一次尝试回到更高优先级 upstream 的时间
+    failback_at: Option<Instant>,
+    // 密钥 blob 到持有它的 upstream 下标，来自最近一次合并的 IDENTITIES_ANSWER
+    owners: HashMap<Vec<u8>, usize>,
+    // 使用连接池时，是否已为该客户端固定连接
+    pinned: bool,
+}
+
+impl Upstreams {
+    /// 连接 upstream；等待模式下所有 upstream 都不可用时等待后整体重试
+    pub async fn connect(options: &UpstreamOptions) -> Result<Self> {
+        let mut round = 0;
+        loop {
+            match Self::connect_once(options).await {
+                Ok(upstreams) => return Ok(upstreams),
+                Err(e) if options.wait.is_some() => {
+                    let delay = options.retry.delay_for(round);
+                    debug!("Waiting {:?} for an upstream to appear: {}", delay, e);
+                    tokio::time::sleep(delay).await;
+                    round = round.saturating_add(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 连接 upstream；合并模式下部分不可用时继续使用其余的
+    async fn connect_once(options: &UpstreamOptions) -> Result<Self> {
+        let mut upstreams = Self {
+            options: options.clone(),
+            upstreams: Vec::new(),
+            active: 0,
+            failback_at: None,
+            owners: HashMap::new(),
+            pinned: false,
+        };
+        match options.mode {
+            UpstreamMode::Merge => upstreams.connect_all().await?,
+            UpstreamMode::Failover => upstreams.connect_from(0).await?,
+        }
+        Ok(upstreams)
+    }
+
+    async fn connect_all(&mut self) -> Result<()> {
+        let transports = self.options.transports.clone();
+        let mut last_error = None;
+        for index in 0..transports.len() {
+            match Upstream::connect(&self.options, index, self.pinned).await {
+                Ok(upstream) => self.upstreams.push(upstream),
+                Err(e) if transports.len() > 1 => {
+                    warn!("Skipping unavailable upstream: {}", e);
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if self.upstreams.is_empty() {
+            return Err(last_error.unwrap_or_else(|| anyhow!("No upstream agent configured")));
+        }
+        Ok(())
+    }
+
+    /// 按顺序连接第一个可用的 upstream
+    ///
+    /// The current upstream is only replaced once another one connects, so
+    /// a failed or cancelled fallback leaves the session usable.
+    async fn connect_from(&mut self, start: usize) -> Result<()> {
+        let transports = self.options.transports.clone();
+        let mut last_error = None;
+        for (index, transport) in transports.iter().enumerate().skip(start) {
+            match Upstream::connect(&self.options, index, self.pinned).await {
+                Ok(upstream) => {
+                    if index > 0 {
+                        warn!("Falling back to upstream {}", transport);
+                    }
+                    self.upstreams = vec![upstream];
+                    self.active = index;
+                    self.failback_at = (index > 0).then(|| Instant::now() + FAILBACK_INTERVAL);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Upstream unavailable: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No upstream agent left to fall back to")))
+    }
+
+    /// 故障转移：当前 upstream 失败时依次尝试后面的 upstream
+    ///
+    /// A request that may already have run on the failed upstream (anything
+    /// but REQUEST_IDENTITIES and SIGN_REQUEST) is not sent to the next one
+    /// unless connecting itself failed.
+    async fn request_with_failover(&mut self, msg: SshAgentMessage) -> Result<SshAgentMessage> {
+        if self.upstreams.is_empty() {
+            self.connect_from(0).await?;
+        } else {
+            self.fail_back().await;
+        }
+        let msg_type = msg.payload[0];
+        loop {
+            let error = match self.upstreams[0].request(&self.options, msg.clone()).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => e,
+            };
+            warn!("{}", error);
+            if !is_idempotent(msg_type) && !error.is::<Unreachable>() {
+                return Err(error);
+            }
+            self.connect_from(self.active + 1)
+                .await
+                .map_err(|e| anyhow!("{}; {}", error, e))?;
+        }
+    }
+
+    /// 使用后备 upstream 一段时间后，尝试回到优先级更高的 upstream（不重试）
+    async fn fail_back(&mut self) {
+        let Some(at) = self.failback_at else {
+            return;
+        };
+        if Instant::now() < at {
+            return;
+        }
+        let options = UpstreamOptions {
+            retry: RetryPolicy {
+                max_retries: 0,
+                ..self.options.retry.clone()
+            },
+            ..self.options.clone()
+        };
+        for index in 0..self.active {
+            if let Ok(upstream) = Upstream::connect(&options, index, self.pinned).await {
+                warn!("Returning to upstream {}", upstream.transport);
+                self.upstreams = vec![upstream];
+                self.active = index;
+                self.failback_at = (index > 0).then(|| Instant::now() + FAILBACK_INTERVAL);
+                return;
+            }
+        }
+        self.failback_at = Some(Instant::now() + FAILBACK_INTERVAL);
+    }
+
+    /// 将请求路由到合适的 upstream 并返回给客户端的响应
+    pub async fn request(&mut self, msg: SshAgentMessage) -> Result<SshAgentMessage> {
+        if self.options.pools.is_some() && !self.pinned && self.options.pin.pins(&msg) {
+            debug!("Pinning pooled connections for the rest of the session");
+            self.pinned = true;
+            for upstream in &mut self.upstreams {
+                upstream.pinned = true;
+            }
+        }
+        if self.options.mode == UpstreamMode::Failover {
+            return self.request_with_failover(msg).await;
+        }
+        if self.upstreams.len() == 1 {
+            return self.upstreams[0].request(&self.options, msg).await;
+        }
+        match msg.payload[0] {
+            proto::SSH_AGENTC_REQUEST_IDENTITIES => Ok(self.list_identities().await),
+            proto::SSH_AGENTC_SIGN_REQUEST | proto::SSH_AGENTC_REMOVE_IDENTITY => {
+                let key_blob = match msg.parse() {
+                    Ok(
+                        AgentMessage::SignRequest { key_blob, .. }
+                        | AgentMessage::RemoveIdentity { key_blob },
+                    ) => key_blob,
+                    _ => return self.upstreams[0].request(&self.options, msg).await,
+                };
+                match self.owner_of(&key_blob).await {
+                    Some(index) => {
+                        debug!(
+                            "Routing request for key {} to {}",
+                            proto::fingerprint(&key_blob),
+                            self.upstreams[index].transport
+                        );
+                        self.upstreams[index].request(&self.options, msg).await
+                    }
+                    None => {
+                        debug!("No upstream holds key {}", proto::fingerprint(&key_blob));
+                        Ok(SshAgentMessage::from(&AgentMessage::Failure))
+                    }
+                }
+            }
+            proto::SSH_AGENTC_LOCK
+            | proto::SSH_AGENTC_UNLOCK
+            | proto::SSH_AGENTC_REMOVE_ALL_IDENTITIES => {
+                let replies = self.broadcast(msg).await;
+                let success = replies
+                    .iter()
+                    .all(|reply| reply.payload[0] == proto::SSH_AGENT_SUCCESS);
+                Ok(SshAgentMessage::from(if success {
+                    &AgentMessage::Success
+                } else {
+                    &AgentMessage::Failure
+                }))
+            }
+            proto::SSH_AGENTC_EXTENSION => {
+                // 返回第一个支持该扩展的 upstream 的响应
+                let mut replies = self.broadcast(msg).await;
+                let index = replies
+                    .iter()
+                    .position(|reply| {
+                        !matches!(
+                            reply.payload[0],
+                            proto::SSH_AGENT_FAILURE | proto::SSH_AGENT_EXTENSION_FAILURE
+                        )
+                    })
+                    .unwrap_or(0);
+                Ok(replies.swap_remove(index))
+            }
+            _ => self.upstreams[0].request(&self.options, msg).await,
+        }
+    }
+
+    /// 合并所有 upstream 的密钥列表，按密钥 blob 去重
+    async fn list_identities(&mut self) -> SshAgentMessage {
+        let mut identities: Vec<Identity> = Vec::new();
+        self.owners.clear();
+        for (index, upstream) in self.upstreams.iter_mut().enumerate() {
+            let request = SshAgentMessage::from(&AgentMessage::RequestIdentities);
+            let reply = match upstream.request(&self.options, request).await {
+                Ok(reply) => reply,
+                // 不可用的 upstream 不影响其余 upstream 的密钥
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+            let listed = match reply.parse() {
+                Ok(AgentMessage::IdentitiesAnswer(listed)) => listed,
+                _ => {
+                    warn!("{} did not list its identities", upstream.transport);
+                    continue;
+                }
+            };
+            debug!("{} holds {} identities", upstream.transport, listed.len());
+            for identity in listed {
+                if self.owners.contains_key(&identity.key_blob) {
+                    continue;
+                }
+                self.owners.insert(identity.key_blob.clone(), index);
+                identities.push(identity);
+            }
+        }
+        SshAgentMessage::from(&AgentMessage::IdentitiesAnswer(identities))
+    }
+
+    /// 查找持有密钥的 upstream；客户端未先列出密钥时刷新一次
+    async fn owner_of(&mut self, key_blob: &[u8]) -> Option<usize> {
+        if !self.owners.contains_key(key_blob) {
+            self.list_identities().await;
+        }
+        self.owners.get(key_blob).copied()
+    }
+
+    /// 等待客户端自己持有的空闲连接上出现数据
+    ///
+    /// Agents never speak unprompted: a frame arriving between requests is
+    /// discarded instead of being taken as the reply to the next request,
+    /// and a connection closed while idle is dropped and re-established on
+    /// the next request. Never completes while no connection is held.
+    pub async fn watch_idle(&mut self) {
+        let readers: Vec<_> = self
+            .upstreams
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, upstream)| {
+                let connection = upstream.connection.as_mut()?;
+                Some(async move { (index, connection.reader.next().await) }.boxed())
+            })
+            .collect();
+        if readers.is_empty() {
+            return std::future::pending().await;
+        }
+        let ((index, frame), _, rest) = futures::future::select_all(readers).await;
+        drop(rest);
+        let upstream = &mut self.upstreams[index];
+        match frame {
+            Some(Ok(frame)) => discard(upstream.transport.as_ref(), frame),
+            Some(Err(e)) => {
+                debug!("Idle connection to {} failed: {}", upstream.transport, e);
+                upstream.disconnect();
+            }
+            None => {
+                debug!("{} closed the idle connection", upstream.transport);
+                upstream.disconnect();
+            }
+        }
+    }
+
+    /// 断开所有连接，下一个请求重新连接
+    pub fn reset(&mut self) {
+        for upstream in &mut self.upstreams {
+            upstream.disconnect();
+        }
+    }
+
+    /// 发送给所有 upstream；失败的 upstream 视为返回 SSH_AGENT_FAILURE
+    async fn broadcast(&mut self, msg: SshAgentMessage) -> Vec<SshAgentMessage> {
+        let mut replies = Vec::with_capacity(self.upstreams.len());
+        for upstream in &mut self.upstreams {
+            let reply = upstream
+                .request(&self.options, msg.clone())
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("{}", e);
+                    SshAgentMessage::from(&AgentMessage::Failure)
+                });
+            replies.push(reply);
+        }
+        replies
+    }
+}
+
+impl std::fmt::Display for Upstreams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", upstream.transport)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::{Backoff, RetryPolicy};
+    use crate::testing::{Connect, FakeAgent, identity, upstream_options};
+    use std::time::Duration;
+
+    fn agent(name: &str, connect: Connect) -> Arc<FakeAgent> {
+        let agent = FakeAgent::new(name);
+        agent.set_connect(connect);
+        agent
+    }
+
+    fn failover(agents: &[Arc<FakeAgent>]) -> UpstreamOptions {
+        UpstreamOptions {
+            mode: UpstreamMode::Failover,
+            ..upstream_options(agents)
+        }
+    }
+
+    fn list() -> SshAgentMessage {
+        SshAgentMessage::from(&AgentMessage::RequestIdentities)
+    }
+
+    #[tokio::test]
+    async fn failed_fallback_keeps_the_session_usable() {
+        let options = failover(&[
+            agent("closing", Connect::Close),
+            agent("down", Connect::Refuse),
+        ]);
+        let mut upstreams = Upstreams::connect(&options).await.unwrap();
+        // 两次都失败，但第二次不能因为 upstream 列表为空而 panic
+        assert!(upstreams.request(list()).await.is_err());
+        assert!(upstreams.request(list()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelled_fallback_keeps_the_session_usable() {
+        let options = failover(&[
+            agent("closing", Connect::Close),
+            agent("hanging", Connect::Hang),
+        ]);
+        let mut upstreams = Upstreams::connect(&options).await.unwrap();
+        for _ in 0..2 {
+            let request = upstreams.request(list());
+            let result = tokio::time::timeout(Duration::from_millis(50), request).await;
+            assert!(result.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_sessions_do_not_exhaust_the_pool() {
+        let agent = FakeAgent::new("agent");
+        let pool = Arc::new(ConnectionPool::new(agent.clone(), 1, None));
+        let mut options = upstream_options(&[agent]);
+        options.pools = Some(vec![pool.clone()].into());
+        options.pin = PinMode::Always;
+        // 池大小为 1，三个固定连接的客户端同时在线也都能得到响应
+        let mut sessions = Vec::new();
+        for _ in 0..3 {
+            let session = async {
+                let mut upstreams = Upstreams::connect(&options).await?;
+                let response = upstreams.request(list()).await?;
+                anyhow::Ok((upstreams, response))
+            };
+            let result = tokio::time::timeout(Duration::from_secs(1), session).await;
+            let (upstreams, response) = result.expect("waiting for a pool slot").unwrap();
+            assert_eq!(response.payload[0], proto::SSH_AGENT_IDENTITIES_ANSWER);
+            sessions.push(upstreams);
+        }
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    /// 第一个 upstream 不可用、第二个列出一个密钥
+    fn down_then_up() -> (Arc<FakeAgent>, Arc<FakeAgent>) {
+        let down = agent("down", Connect::Refuse);
+        let up = FakeAgent::with_identities("up", vec![identity(2, "second")]);
+        (down, up)
+    }
+
+    fn waiting(mut options: UpstreamOptions, wait: WaitMode) -> UpstreamOptions {
+        options.wait = Some(wait);
+        options.retry = RetryPolicy {
+            max_retries: 1,
+            backoff: Backoff::Constant,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            ..RetryPolicy::none()
+        };
+        options
+    }
+
+    async fn listed(upstreams: &mut Upstreams) -> Vec<Identity> {
+        let reply = upstreams.request(list()).await.unwrap();
+        match reply.parse().unwrap() {
+            AgentMessage::IdentitiesAnswer(identities) => identities,
+            other => panic!("unexpected {}", other.name()),
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_failover_uses_the_next_upstream() {
+        for wait in [WaitMode::Queue, WaitMode::FailFast] {
+            let (down, up) = down_then_up();
+            let options = waiting(failover(&[down, up]), wait);
+            let connect = Upstreams::connect(&options);
+            let mut upstreams = tokio::time::timeout(Duration::from_secs(1), connect)
+                .await
+                .expect("waiting on the unavailable upstream")
+                .unwrap();
+            assert_eq!(listed(&mut upstreams).await, [identity(2, "second")]);
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_merge_skips_the_unavailable_upstream() {
+        for wait in [WaitMode::Queue, WaitMode::FailFast] {
+            let (down, up) = down_then_up();
+            let options = waiting(upstream_options(&[down, up]), wait);
+            let connect = Upstreams::connect(&options);
+            let mut upstreams = tokio::time::timeout(Duration::from_secs(1), connect)
+                .await
+                .expect("waiting on the unavailable upstream")
+                .unwrap();
+            assert_eq!(listed(&mut upstreams).await, [identity(2, "second")]);
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_until_an_upstream_appears() {
+        let agent = agent("late", Connect::Refuse);
+        let options = waiting(
+            upstream_options(std::slice::from_ref(&agent)),
+            WaitMode::Queue,
+        );
+        let connect = tokio::spawn(async move { Upstreams::connect(&options).await.is_ok() });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!connect.is_finished());
+        agent.set_connect(Connect::Accept);
+        let connected = tokio::time::timeout(Duration::from_secs(1), connect).await;
+        assert!(
+            connected
+                .expect("still waiting after the upstream appeared")
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_during_a_session_does_not_wait() {
+        let agent = FakeAgent::new("agent");
+        let options = waiting(
+            upstream_options(std::slice::from_ref(&agent)),
+            WaitMode::Queue,
+        );
+        let mut upstreams = Upstreams::connect(&options).await.unwrap();
+        // 连接断开后 upstream 也不可用
+        agent.drop_requests(1);
+        agent.set_connect(Connect::Refuse);
+        let request = upstreams.request(list());
+        let result = tokio::time::timeout(Duration::from_secs(1), request).await;
+        assert!(
+            result
+                .expect("waiting for the upstream mid-session")
+                .is_err()
+        );
+    }
+
+    fn sign() -> SshAgentMessage {
+        SshAgentMessage::from(&AgentMessage::SignRequest {
+            key_blob: crate::testing::key_blob(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+    }
+
+    fn lock() -> SshAgentMessage {
+        SshAgentMessage::from(&AgentMessage::Lock {
+            passphrase: b"secret".to_vec(),
+        })
+    }
+
+    fn signer(reply: &SshAgentMessage) -> String {
+        match reply.parse().unwrap() {
+            AgentMessage::SignResponse { signature } => String::from_utf8(signature).unwrap(),
+            other => panic!("unexpected {}", other.name()),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_sign_request_moves_to_the_next_upstream() {
+        let (primary, backup) = (FakeAgent::new("primary"), FakeAgent::new("backup"));
+        let mut upstreams = Upstreams::connect(&failover(&[primary.clone(), backup.clone()]))
+            .await
+            .unwrap();
+        // 原连接与重连后的连接都在收到请求后断开
+        primary.drop_requests(2);
+        let reply = upstreams.request(sign()).await.unwrap();
+        assert_eq!(signer(&reply), "backup");
+        assert_eq!(primary.requests(), [proto::SSH_AGENTC_SIGN_REQUEST; 2]);
+    }
+
+    #[tokio::test]
+    async fn failed_lock_is_not_repeated_on_the_next_upstream() {
+        let (primary, backup) = (FakeAgent::new("primary"), FakeAgent::new("backup"));
+        let mut upstreams = Upstreams::connect(&failover(&[primary.clone(), backup.clone()]))
+            .await
+            .unwrap();
+        primary.drop_requests(1);
+        assert!(upstreams.request(lock()).await.is_err());
+        assert_eq!(primary.requests(), [proto::SSH_AGENTC_LOCK]);
+        assert!(backup.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream_passes_any_request_on() {
+        let (primary, backup) = (agent("primary", Connect::Close), FakeAgent::new("backup"));
+        let mut upstreams = Upstreams::connect(&failover(&[primary.clone(), backup.clone()]))
+            .await
+            .unwrap();
+        // 空闲连接已被关闭，重新连接也失败，LOCK 从未发给 primary
+        primary.set_connect(Connect::Refuse);
+        let reply = upstreams.request(lock()).await.unwrap();
+        assert_eq!(reply.payload, [proto::SSH_AGENT_SUCCESS]);
+        assert!(primary.requests().is_empty());
+        assert_eq!(backup.requests(), [proto::SSH_AGENTC_LOCK]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_returns_to_the_primary() {
+        let (primary, backup) = (agent("primary", Connect::Refuse), FakeAgent::new("backup"));
+        let mut upstreams = Upstreams::connect(&failover(&[primary.clone(), backup.clone()]))
+            .await
+            .unwrap();
+        assert_eq!(signer(&upstreams.request(sign()).await.unwrap()), "backup");
+        primary.set_connect(Connect::Accept);
+        // 间隔未到之前继续使用后备 upstream
+        assert_eq!(signer(&upstreams.request(sign()).await.unwrap()), "backup");
+        tokio::time::advance(FAILBACK_INTERVAL).await;
+        assert_eq!(signer(&upstreams.request(sign()).await.unwrap()), "primary");
+    }
+
+    fn merged() -> (Arc<FakeAgent>, Arc<FakeAgent>) {
+        let first =
+            FakeAgent::with_identities("first", vec![identity(1, "one"), identity(2, "first")]);
+        let second =
+            FakeAgent::with_identities("second", vec![identity(2, "second"), identity(3, "three")]);
+        (first, second)
+    }
+
+    fn sign_with(seed: u8) -> SshAgentMessage {
+        SshAgentMessage::from(&AgentMessage::SignRequest {
+            key_blob: crate::testing::key_blob(seed),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+    }
+
+    #[tokio::test]
+    async fn merged_list_keeps_upstream_order_without_duplicates() {
+        let (first, second) = merged();
+        let mut upstreams = Upstreams::connect(&upstream_options(&[first, second]))
+            .await
+            .unwrap();
+        // 两个 upstream 都有的密钥只出现一次，使用第一个 upstream 的注释
+        assert_eq!(
+            listed(&mut upstreams).await,
+            [
+                identity(1, "one"),
+                identity(2, "first"),
+                identity(3, "three")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn key_requests_go_to_the_upstream_holding_the_key() {
+        let (first, second) = merged();
+        let mut upstreams = Upstreams::connect(&upstream_options(&[first.clone(), second.clone()]))
+            .await
+            .unwrap();
+        // 客户端未先列出密钥时自动刷新一次
+        assert_eq!(
+            signer(&upstreams.request(sign_with(3)).await.unwrap()),
+            "second"
+        );
+        assert_eq!(
+            signer(&upstreams.request(sign_with(2)).await.unwrap()),
+            "first"
+        );
+        let remove = SshAgentMessage::from(&AgentMessage::RemoveIdentity {
+            key_blob: crate::testing::key_blob(1),
+        });
+        let reply = upstreams.request(remove).await.unwrap();
+        assert_eq!(reply.payload, [proto::SSH_AGENT_SUCCESS]);
+        // 没有 upstream 持有的密钥直接失败
+        let reply = upstreams.request(sign_with(9)).await.unwrap();
+        assert_eq!(reply.payload, [proto::SSH_AGENT_FAILURE]);
+
+        let list = proto::SSH_AGENTC_REQUEST_IDENTITIES;
+        let (sign, remove) = (
+            proto::SSH_AGENTC_SIGN_REQUEST,
+            proto::SSH_AGENTC_REMOVE_IDENTITY,
+        );
+        assert_eq!(first.requests(), [list, sign, remove, list]);
+        assert_eq!(second.requests(), [list, sign, list]);
+    }
+
+    #[tokio::test]
+    async fn lock_unlock_and_remove_all_reach_every_upstream() {
+        let (first, second) = merged();
+        let mut upstreams = Upstreams::connect(&upstream_options(&[first.clone(), second.clone()]))
+            .await
+            .unwrap();
+        let requests = [
+            lock(),
+            SshAgentMessage::from(&AgentMessage::Unlock {
+                passphrase: b"secret".to_vec(),
+            }),
+            SshAgentMessage::from(&AgentMessage::RemoveAllIdentities),
+        ];
+        for request in requests.clone() {
+            let reply = upstreams.request(request).await.unwrap();
+            assert_eq!(reply.payload, [proto::SSH_AGENT_SUCCESS]);
+        }
+        let types = requests.map(|request| request.payload[0]);
+        assert_eq!(first.requests(), types);
+        assert_eq!(second.requests(), types);
+
+        // 任一 upstream 失败时整体返回 SSH_AGENT_FAILURE
+        second.drop_requests(1);
+        let reply = upstreams.request(lock()).await.unwrap();
+        assert_eq!(reply.payload, [proto::SSH_AGENT_FAILURE]);
+        assert_eq!(first.requests().last(), Some(&proto::SSH_AGENTC_LOCK));
+    }
+
+    #[tokio::test]
+    async fn dropped_list_request_is_reissued_once() {
+        let agent = FakeAgent::with_identities("agent", vec![identity(1, "one")]);
+        let mut upstreams = Upstreams::connect(&upstream_options(std::slice::from_ref(&agent)))
+            .await
+            .unwrap();
+        agent.drop_requests(1);
+        assert_eq!(listed(&mut upstreams).await, [identity(1, "one")]);
+        let listing = proto::SSH_AGENTC_REQUEST_IDENTITIES;
+        assert_eq!(agent.requests(), [listing, listing]);
+
+        // 重连后再次断开时不再重发
+        agent.drop_requests(2);
+        assert!(upstreams.request(list()).await.is_err());
+        assert_eq!(agent.requests(), [listing; 4]);
+    }
+
+    #[tokio::test]
+    async fn dropped_add_identity_is_not_reissued() {
+        let agent = FakeAgent::new("agent");
+        let mut upstreams = Upstreams::connect(&upstream_options(std::slice::from_ref(&agent)))
+            .await
+            .unwrap();
+        agent.drop_requests(1);
+        // 内容无关紧要，fake agent 只看消息类型
+        let add = SshAgentMessage::from_payload(vec![proto::SSH_AGENTC_ADD_IDENTITY]);
+        assert!(upstreams.request(add).await.is_err());
+        assert_eq!(agent.requests(), [proto::SSH_AGENTC_ADD_IDENTITY]);
+        // 下一个请求使用新的连接
+        let reply = upstreams.request(lock()).await.unwrap();
+        assert_eq!(reply.payload, [proto::SSH_AGENT_SUCCESS]);
+    }
+}
@@ -5,23 +5,73 @@ if [ -z "$WSLENV" ]; then
     return 1
 fi
 
-if ! command -v socat &> /dev/null; then
-    echo "socat could not be found, please install it."
-    return 1
-fi
-
-export SSH_AUTH_SOCK=$HOME/.ssh/ssh-agent.sock
-rm -f $SSH_AUTH_SOCK
+agent_sock=$HOME/.ssh/ssh-agent.sock
 
+# 优先使用 Linux 版本的 serve（无需 socat），否则经 socat 为每个连接启动 Windows 程序
 bridge_path="wsl2-ssh-agent.exe"
 if [ -n "$1" ]; then
     bridge_path="$1"
+elif command -v wsl2-ssh-agent &> /dev/null; then
+    bridge_path="wsl2-ssh-agent"
 fi
 
+# 设置通过配置文件（--profile 或 WSL2_SSH_AGENT_PROFILE）和 WSL2_SSH_AGENT_* 环境变量传递，
+# 这里把这些变量经 WSLENV 转交给 Windows 程序；第二个参数仅为兼容旧用法保留
+for name in $(compgen -e | grep '^WSL2_SSH_AGENT_'); do
+    case ":$WSLENV:" in
+        *":$name:"* | *":$name/"*) ;;
+        *)
+            # 配置文件路径需要转换为 Windows 路径
+            if [ "$name" = "WSL2_SSH_AGENT_CONFIG" ]; then
+                export WSLENV="${WSLENV:+$WSLENV:}$name/p"
+            else
+                export WSLENV="${WSLENV:+$WSLENV:}$name"
+            fi
+            ;;
+    esac
+done
+
+case "$bridge_path" in
+    *.exe) ;;
+    *)
+        # 配置中没有 upstream 时经 Windows helper 连接 --pipe 指定的命名管道（默认 OpenSSH Agent）
+        serve_args=()
+        if settings=$("$bridge_path" config check 2> /dev/null); then
+            if ! grep -q '^upstream = ' <<< "$settings"; then
+                pipe=$(sed -n "s/^pipe = '\(.*\)'.*/\1/p" <<< "$settings")
+                serve_args=(-u "helper:pipe:${pipe:-\\\\.\\pipe\\openssh-ssh-agent}")
+            fi
+            rm -f "$agent_sock"
+            "$bridge_path" "${serve_args[@]}" serve --listen "unix:$agent_sock" $2 &
+            serve_pid=$!
+            # serve 启动失败时错误只留在后台任务里，确认进程仍在运行且 socket 已创建
+            for _ in $(seq 50); do
+                [ -S "$agent_sock" ] || ! kill -0 "$serve_pid" 2> /dev/null && break
+                sleep 0.1
+            done
+            if [ -S "$agent_sock" ] && kill -0 "$serve_pid" 2> /dev/null; then
+                export SSH_AUTH_SOCK=$agent_sock
+                return 0
+            fi
+            kill "$serve_pid" 2> /dev/null
+        fi
+        echo "$bridge_path serve failed to start, falling back to wsl2-ssh-agent.exe through socat."
+        bridge_path="wsl2-ssh-agent.exe"
+        ;;
+esac
+
+if ! command -v socat &> /dev/null; then
+    echo "socat could not be found, please install it or use the Linux build with serve."
+    return 1
+fi
+
+rm -f "$agent_sock"
+
 if [ -n "$2" ]; then
     full_command="$bridge_path $2"
 else
     full_command="$bridge_path"
 fi
 
-socat UNIX-LISTEN:$SSH_AUTH_SOCK,fork EXEC:"$full_command",nofork &
+socat UNIX-LISTEN:$agent_sock,fork EXEC:"$full_command",nofork &
+export SSH_AUTH_SOCK=$agent_sock
@@ -0,0 +1,126 @@
+//! End-to-end test of `serve` against a real OpenSSH ssh-agent
+#![cfg(unix)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// 测试结束时结束子进程并删除临时目录
+struct Fixture {
+    dir: PathBuf,
+    children: Vec<Child>,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("wsl2-ssh-agent-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self {
+            dir,
+            children: Vec::new(),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn spawn(&mut self, command: &mut Command) {
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to start {:?}: {}", command.get_program(), e));
+        self.children.push(child);
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn wait_for(socket: &Path) {
+    let started = Instant::now();
+    while !socket.exists() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "{} did not appear",
+            socket.display()
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn run(command: &mut Command) -> String {
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        command,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn lists_and_signs_through_serve() {
+    let mut fixture = Fixture::new();
+    let upstream = fixture.path("upstream.sock");
+    let agent = fixture.path("agent.sock");
+    let key = fixture.path("id_ed25519");
+
+    fixture.spawn(Command::new("ssh-agent").arg("-D").arg("-a").arg(&upstream));
+    wait_for(&upstream);
+    run(Command::new("ssh-keygen")
+        .args([
+            "-q",
+            "-t",
+            "ed25519",
+            "-N",
+            "",
+            "-C",
+            "integration-test",
+            "-f",
+        ])
+        .arg(&key));
+    run(Command::new("ssh-add")
+        .arg("-q")
+        .arg(&key)
+        .env("SSH_AUTH_SOCK", &upstream));
+    // 签名只能经过 agent 完成
+    std::fs::remove_file(&key).unwrap();
+
+    fixture.spawn(
+        Command::new(env!("CARGO_BIN_EXE_wsl2-ssh-agent"))
+            .arg("--upstream")
+            .arg(format!("unix:{}", upstream.display()))
+            .arg("serve")
+            .arg("--listen")
+            .arg(format!("unix:{}", agent.display()))
+            .env("XDG_CONFIG_HOME", &fixture.dir)
+            .env_remove("SSH_ASKPASS"),
+    );
+    wait_for(&agent);
+
+    let listed = run(Command::new("ssh-add")
+        .arg("-l")
+        .env("SSH_AUTH_SOCK", &agent));
+    assert!(listed.contains("integration-test (ED25519)"), "{}", listed);
+
+    let message = fixture.path("message");
+    std::fs::write(&message, b"signed through the bridge\n").unwrap();
+    run(Command::new("ssh-keygen")
+        .args(["-Y", "sign", "-n", "file", "-f"])
+        .arg(key.with_extension("pub"))
+        .arg(&message)
+        .env("SSH_AUTH_SOCK", &agent));
+    let signature = std::fs::read_to_string(message.with_extension("sig")).unwrap();
+    assert!(signature.starts_with("-----BEGIN SSH SIGNATURE-----"));
+}